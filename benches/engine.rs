@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use crossbeam::channel::{Receiver, Sender};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use rust_decimal::Decimal;
+
+use cex::matching_engine::messages::{DatabaseMessage, EngineMessage, EngineResponse};
+use cex::matching_engine::router::Router;
+use cex::matching_engine::types::{BidOrAsk, Order, TradingPair};
+use cex::websocket::events::MarketDataEvent;
+
+const MARKET_COUNTS: [usize; 3] = [1, 4, 16];
+
+// Crossing bid/ask pairs sent per run, spread evenly over the markets
+const ORDER_PAIRS: usize = 4_000;
+
+fn markets(count: usize) -> Vec<TradingPair> {
+    (0..count).map(|market| TradingPair::new(format!("COIN{}", market), "USD".to_string())).collect()
+}
+
+fn order_for(user_id: &str, side: BidOrAsk) -> Order {
+    let mut order = Order::new(side, Decimal::ONE);
+    order.user_id = user_id.to_string();
+    order
+}
+
+// A router with every market on one of `shards` threads and a buyer and seller per market
+fn router(pairs: &[TradingPair], shards: usize) -> (
+    Router,
+    Sender<EngineMessage>,
+    Receiver<EngineResponse>,
+    Receiver<DatabaseMessage>,
+    Receiver<MarketDataEvent>
+) {
+    let (mut router, msg_tx, resp_rx, db_rx, ws_rx) = Router::new(shards);
+
+    for (market, pair) in pairs.iter().enumerate() {
+        router.add_market(pair.clone());
+        router.add_user(format!("buyer{}", market), HashMap::from([("USD".to_string(), Decimal::from(1_000_000_000))])).unwrap();
+        router.add_user(format!("seller{}", market), HashMap::from([(pair.base.clone(), Decimal::from(1_000_000_000))])).unwrap();
+    }
+
+    (router, msg_tx, resp_rx, db_rx, ws_rx)
+}
+
+// Send every order through the router and wait until the engines have answered all of them
+fn bench_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("engine_throughput");
+    group.throughput(Throughput::Elements((ORDER_PAIRS * 2) as u64));
+    group.sample_size(20);
+
+    for market_count in MARKET_COUNTS {
+        let pairs = markets(market_count);
+
+        for (label, shards) in [("single_shard", 1), ("shard_per_market", market_count)] {
+            group.bench_with_input(BenchmarkId::new(label, market_count), &shards, |b, &shards| {
+                b.iter_batched(
+                    || router(&pairs, shards),
+                    |(router, msg_tx, resp_rx, db_rx, ws_rx)| {
+                        let _handles = router.start();
+
+                        for i in 0..ORDER_PAIRS {
+                            let market = i % pairs.len();
+                            let pair = &pairs[market];
+                            let _ = msg_tx.send(EngineMessage::PlaceOrder{
+                                pair: pair.clone(),
+                                order: order_for(&format!("seller{}", market), BidOrAsk::Ask),
+                                price: Some(Decimal::from(100)),
+                            });
+                            let _ = msg_tx.send(EngineMessage::PlaceOrder{
+                                pair: pair.clone(),
+                                order: order_for(&format!("buyer{}", market), BidOrAsk::Bid),
+                                price: Some(Decimal::from(100)),
+                            });
+                        }
+
+                        let mut answered = 0;
+                        while answered < ORDER_PAIRS * 2 {
+                            match resp_rx.recv() {
+                                Ok(EngineResponse::OrderPlaced{ .. }) | Ok(EngineResponse::Error{ .. }) => answered += 1,
+                                Ok(_) => {}
+                                Err(_) => break,
+                            }
+                        }
+
+                        (msg_tx, db_rx, ws_rx)
+                    },
+                    BatchSize::PerIteration,
+                )
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_throughput);
+criterion_main!(benches);
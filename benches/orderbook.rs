@@ -0,0 +1,101 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use rust_decimal::Decimal;
+use uuid::Uuid;
+
+use cex::matching_engine::orderbook::OrderBook;
+use cex::matching_engine::types::{BidOrAsk, Order, TradingPair};
+
+const LEVEL_DEPTHS: [usize; 3] = [100, 1_000, 10_000];
+
+// The previous layout: one Vec per price level, found by scanning and removed with Vec::remove
+fn vec_level(depth: usize) -> (Vec<Order>, Vec<Uuid>) {
+    let orders: Vec<Order> = (0..depth).map(|_| Order::new(BidOrAsk::Ask, Decimal::ONE)).collect();
+    let ids = orders.iter().map(|o| o.id).collect();
+    (orders, ids)
+}
+
+fn indexed_book(depth: usize) -> (OrderBook, Vec<Uuid>) {
+    let mut orderbook = OrderBook::new(TradingPair::new("BTC".to_string(), "USD".to_string()));
+    let mut ids = Vec::with_capacity(depth);
+
+    for _ in 0..depth {
+        let order = Order::new(BidOrAsk::Ask, Decimal::ONE);
+        ids.push(order.id);
+        orderbook.add_order(Decimal::from(100), order);
+    }
+
+    (orderbook, ids)
+}
+
+// Cancel every order of a single deep level, always picking from the middle of the queue
+fn bench_cancel_from_middle(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cancel_from_middle");
+
+    for depth in LEVEL_DEPTHS {
+        group.bench_with_input(BenchmarkId::new("vec_remove", depth), &depth, |b, &depth| {
+            b.iter_batched(
+                || vec_level(depth),
+                |(mut orders, mut ids)| {
+                    while !ids.is_empty() {
+                        let order_id = ids.swap_remove(ids.len() / 2);
+                        let position = orders.iter().position(|o| o.id == order_id).unwrap();
+                        orders.remove(position);
+                    }
+                },
+                BatchSize::LargeInput,
+            )
+        });
+
+        group.bench_with_input(BenchmarkId::new("indexed_queue", depth), &depth, |b, &depth| {
+            b.iter_batched(
+                || indexed_book(depth),
+                |(mut orderbook, mut ids)| {
+                    while !ids.is_empty() {
+                        let order_id = ids.swap_remove(ids.len() / 2);
+                        orderbook.cancel_order(order_id).unwrap();
+                    }
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+// Sweep a whole level with one aggressive order
+fn bench_sweep_level(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sweep_level");
+
+    for depth in LEVEL_DEPTHS {
+        group.bench_with_input(BenchmarkId::new("vec_remove", depth), &depth, |b, &depth| {
+            b.iter_batched(
+                || vec_level(depth).0,
+                |mut orders| {
+                    let mut remaining = Decimal::from(depth as u64);
+                    while remaining > Decimal::ZERO && !orders.is_empty() {
+                        remaining -= orders[0].size;
+                        orders.remove(0);
+                    }
+                },
+                BatchSize::LargeInput,
+            )
+        });
+
+        group.bench_with_input(BenchmarkId::new("indexed_queue", depth), &depth, |b, &depth| {
+            b.iter_batched(
+                || indexed_book(depth).0,
+                |mut orderbook| {
+                    let taker = Order::new(BidOrAsk::Bid, Decimal::from(depth as u64));
+                    orderbook.add_order(Decimal::from(100), taker)
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_cancel_from_middle, bench_sweep_level);
+criterion_main!(benches);
@@ -0,0 +1,12 @@
+#![no_main]
+
+// Random order, cancel and amend streams against OrderBook, checked step by step against the
+// reference matcher. Run with `cargo fuzz run orderbook` from the repository root.
+use libfuzzer_sys::fuzz_target;
+use cex::matching_engine::simulation::{Action, run};
+
+fuzz_target!(|data: &[u8]| {
+    if let Err(message) = run(&Action::from_bytes(data)) {
+        panic!("{}", message);
+    }
+});
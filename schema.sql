@@ -50,6 +50,8 @@ CREATE TABLE IF NOT EXISTS trades (
     price DECIMAL(20,8) NOT NULL,
     quantity DECIMAL(20,8) NOT NULL,
     volume DECIMAL(30,8) NOT NULL,
+    buyer_fee DECIMAL(20,8) NOT NULL DEFAULT 0,
+    seller_fee DECIMAL(20,8) NOT NULL DEFAULT 0,
     executed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
 );
 
@@ -7,7 +7,12 @@ use uuid::Uuid;
 use chrono::Utc;
 
 use crate::api::types::*;
-use crate::redis::message::RedisOrderRequest;
+use crate::redis::message::{RedisOrderRequest, RedisAmendRequest, RedisAdminRequest, RedisMarketStatus, RedisMarketSpec};
+use crate::matching_engine::spec::MarketSpec;
+use crate::tiers::{TierSchedule, TierStatus};
+use crate::wallet::{Deposit, Withdrawal};
+use crate::redis::message::{RedisWithdrawalRequest, RedisTransferRequest, RedisSubAccountRequest};
+use crate::balance::transfer::InternalTransfer;
 
 
 pub struct ApiService{
@@ -41,12 +46,31 @@ impl ApiService{
             )
             .service(web::scope("/api/v1")
                 .route("/order", web::post().to(place_order))
+                .route("/order/{id}", web::put().to(amend_order))
+                .route("/markets", web::get().to(get_markets))
                 .route("/depth/{market}", web::get().to(get_depth))
+                .route("/auction/{market}", web::get().to(get_auction))
                 .route("/trades/{market}", web::get().to(get_recent_trades))
                 .route("/balance/{user_id}", web::get().to(get_balance))
+                .route("/account/{user_id}/fee_tier", web::get().to(get_fee_tier))
+                .route("/account/{user_id}/sub_accounts", web::get().to(get_sub_accounts))
+                .route("/account/{user_id}/sub_accounts", web::post().to(create_sub_account))
+                .route("/transfers", web::post().to(request_transfer))
+                .route("/transfers/{user_id}", web::get().to(get_transfers))
+                .route("/deposit_address/{user_id}/{asset}", web::get().to(get_deposit_address))
+                .route("/deposits/{user_id}", web::get().to(get_deposits))
+                .route("/withdrawals", web::post().to(request_withdrawal))
+                .route("/withdrawals/{id}", web::get().to(get_withdrawal))
                 .route("/tickers", web::get().to(get_tickers))
                 .route("/tickers/{market}", web::get().to(get_ticker))
                 .route("/health", web::get().to(health_check))
+                .route("/admin/markets", web::get().to(get_market_statuses))
+                .route("/admin/markets", web::post().to(list_market))
+                .route("/admin/markets/{market}/reference_price", web::post().to(set_reference_price))
+                .route("/admin/markets/{market}/{action}", web::post().to(change_market_status))
+                .route("/admin/withdrawals/{id}/{action}", web::post().to(change_withdrawal))
+                .route("/admin/check_invariants", web::post().to(check_invariants))
+                .route("/admin/invariants", web::get().to(get_invariant_report))
             )
         })
         .bind(bind_address)?
@@ -73,6 +97,12 @@ async fn place_order(
         order_type: order_req.order_type.clone(),
         price: order_req.price,
         quantity: order_req.quantity,
+        quote_quantity: order_req.quote_quantity,
+        trigger_price: order_req.trigger_price,
+        display_quantity: order_req.display_quantity,
+        time_in_force: order_req.time_in_force.clone(),
+        self_trade_prevention: order_req.self_trade_prevention.clone(),
+        sub_account: order_req.sub_account.clone(),
         timestamp: Utc::now(),
     };
 
@@ -108,30 +138,170 @@ async fn place_order(
     }
 }
 
+async fn amend_order(
+    redis_client: web::Data<Arc<Client>>,
+    path: web::Path<String>,
+    amend_req: web::Json<AmendOrderRequest>,
+) -> Result<HttpResponse>{
+    let order_id = path.into_inner();
+    println!("Received amend request for order {}: {:?}", order_id, amend_req);
+
+    if Uuid::parse_str(&order_id).is_err() {
+        let error = ApiError::new(format!("Invalid order id: {}", order_id), 400);
+        return Ok(HttpResponse::BadRequest().json(error));
+    }
+
+    let redis_amend = RedisAmendRequest {
+        order_id: order_id.clone(),
+        market: amend_req.market.clone(),
+        user_id: amend_req.user_id.clone(),
+        price: amend_req.price,
+        quantity: amend_req.quantity,
+        timestamp: Utc::now(),
+    };
+
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let json = serde_json::to_string(&redis_amend).unwrap();
+
+            match con.lpush::<_, _, ()>("amend_queue", json).await {
+                Ok(_) => {
+                    let response = PlaceOrderResponse{
+                        success: true,
+                        order_id: Some(order_id),
+                        trades: vec![],
+                        error: None,
+                    };
+
+                    Ok(HttpResponse::Ok().json(response))
+                }
+
+                Err(e) => {
+                    let error = ApiError::new(format!("Failed to queue amend: {}", e), 500);
+                    Ok(HttpResponse::InternalServerError().json(error))
+                }
+            }
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
+// Exchange info: the trading rules and status of every market the engine has announced
+async fn get_markets(
+    redis_client: web::Data<Arc<Client>>,
+) -> Result<HttpResponse>{
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let specs: std::collections::HashMap<String, String> = con.hgetall("market_specs").await.unwrap_or_default();
+            let statuses: std::collections::HashMap<String, String> = con.hgetall("markets").await.unwrap_or_default();
+
+            let mut markets: Vec<MarketInfo> = specs.values()
+                .filter_map(|json| serde_json::from_str::<RedisMarketSpec>(json).ok())
+                .map(|market_spec| {
+                    let status = statuses.get(&market_spec.market)
+                        .and_then(|json| serde_json::from_str::<RedisMarketStatus>(json).ok())
+                        .map(|status| status.status);
+                    let spec = market_spec.spec;
+
+                    MarketInfo{
+                        market: market_spec.market,
+                        base: market_spec.base,
+                        quote: market_spec.quote,
+                        status,
+                        tick_size: spec.tick_size,
+                        step_size: spec.step_size,
+                        min_quantity: spec.min_quantity,
+                        max_quantity: spec.max_quantity,
+                        min_notional: spec.min_notional,
+                        base_precision: spec.base_precision,
+                        quote_precision: spec.quote_precision,
+                        band_percent: spec.band_percent,
+                        breaker_percent: spec.breaker_percent,
+                        breaker_window_secs: spec.breaker_window_secs,
+                        auction_secs: spec.auction_secs,
+                        fees: spec.fees.clone(),
+                    }
+                })
+                .collect();
+            markets.sort_by(|a, b| a.market.cmp(&b.market));
+
+            Ok(HttpResponse::Ok().json(ExchangeInfoResponse{
+                markets,
+                timestamp: Utc::now(),
+            }))
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
 async fn get_depth(
-    _redis_client: web::Data<Arc<Client>>,
+    redis_client: web::Data<Arc<Client>>,
     path: web::Path<String>,
 ) -> Result<HttpResponse>{
     let market = path.into_inner();
     println!("Getting depth for market: {}", market);
 
-    let response = DepthResponse {
-        market: market.clone(),
-        bids: vec![
-            PriceLevel { price: rust_decimal::Decimal::from(49500), quantity: rust_decimal::Decimal::from(2) },
-            PriceLevel { price: rust_decimal::Decimal::from(49400), quantity: rust_decimal::Decimal::from(1) },
-        ],
-        asks: vec![
-            PriceLevel { price: rust_decimal::Decimal::from(50100), quantity: rust_decimal::Decimal::from(1) },
-            PriceLevel { price: rust_decimal::Decimal::from(50200), quantity: rust_decimal::Decimal::from(3) },
-        ],
-        timestamp: Utc::now(),
-    };
-    Ok(HttpResponse::Ok().json(response))
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            // The engine keeps the latest book of every market under "depth:{market}"
+            let snapshot: Option<String> = con.get(format!("depth:{}", market)).await.unwrap_or(None);
+
+            let response = snapshot
+                .and_then(|json| serde_json::from_str::<DepthResponse>(&json).ok())
+                .unwrap_or_else(|| DepthResponse {
+                    market: market.clone(),
+                    bids: vec![],
+                    asks: vec![],
+                    timestamp: Utc::now(),
+                });
+
+            Ok(HttpResponse::Ok().json(response))
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
 }
 
 
 
+// Indicative price and volume of a market's running call auction
+async fn get_auction(
+    redis_client: web::Data<Arc<Client>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse>{
+    let market = path.into_inner();
+
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let auction: Option<String> = con.get(format!("auction:{}", market)).await.unwrap_or(None);
+
+            match auction.and_then(|json| serde_json::from_str::<AuctionResponse>(&json).ok()) {
+                Some(response) => Ok(HttpResponse::Ok().json(response)),
+                None => {
+                    let error = ApiError::new(format!("No call auction running for market {}", market), 404);
+                    Ok(HttpResponse::NotFound().json(error))
+                }
+            }
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
 async fn get_recent_trades(
     _redis_client: web::Data<Arc<Client>>,
     path: web::Path<String>,
@@ -144,9 +314,17 @@ async fn get_recent_trades(
         trades: vec![
             TradeInfo{
                 trade_id: Uuid::new_v4().to_string(),
+                market: market.clone(),
                 price: rust_decimal::Decimal::from(50000),
                 quantity: rust_decimal::Decimal::from(1),
                 timestamp: Utc::now(),
+                side: "buy".to_string(),
+                buyer_user_id: "user123".to_string(),
+                seller_user_id: "user456".to_string(),
+                maker_order_id: Uuid::new_v4().to_string(),
+                taker_order_id: Uuid::new_v4().to_string(),
+                buyer_fee: rust_decimal::Decimal::ZERO,
+                seller_fee: rust_decimal::Decimal::ZERO,
             }
         ],
     };
@@ -184,6 +362,352 @@ async fn get_balance(
 }
 
 
+// Fee tier of a user and how much more volume the next one needs
+async fn get_fee_tier(
+    redis_client: web::Data<Arc<Client>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse>{
+    let user_id = path.into_inner();
+
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let status: Option<String> = con.hget("fee_tiers", &user_id).await.unwrap_or(None);
+            let status = status.and_then(|json| serde_json::from_str::<TierStatus>(&json).ok());
+
+            // Users without volume in the window start from the bottom of the schedule
+            let status = match status {
+                Some(status) => Some(status),
+                None => {
+                    let schedule: Option<String> = con.get("fee_tier_schedule").await.unwrap_or(None);
+                    schedule
+                        .and_then(|json| serde_json::from_str::<TierSchedule>(&json).ok())
+                        .map(|schedule| schedule.status(&user_id, rust_decimal::Decimal::ZERO))
+                }
+            };
+
+            match status {
+                Some(status) => Ok(HttpResponse::Ok().json(status)),
+                None => {
+                    let error = ApiError::new("Fee tiers are not available".to_string(), 503);
+                    Ok(HttpResponse::ServiceUnavailable().json(error))
+                }
+            }
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
+
+async fn get_sub_accounts(
+    redis_client: web::Data<Arc<Client>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse>{
+    let user_id = path.into_inner();
+
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let mut sub_accounts: Vec<String> = con.smembers(format!("sub_accounts:{}", user_id)).await.unwrap_or_default();
+            sub_accounts.sort();
+
+            Ok(HttpResponse::Ok().json(SubAccountsResponse{ user_id, sub_accounts }))
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
+async fn create_sub_account(
+    redis_client: web::Data<Arc<Client>>,
+    path: web::Path<String>,
+    sub_account_req: web::Json<SubAccountRequest>,
+) -> Result<HttpResponse>{
+    let user_id = path.into_inner();
+    println!("Received sub-account request for user {}: {:?}", user_id, sub_account_req);
+
+    let redis_sub_account = RedisSubAccountRequest {
+        user_id: user_id.clone(),
+        name: sub_account_req.into_inner().name,
+        timestamp: Utc::now(),
+    };
+
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let json = serde_json::to_string(&redis_sub_account).unwrap();
+
+            match con.lpush::<_, _, ()>("sub_account_queue", json).await {
+                Ok(_) => Ok(HttpResponse::Ok().json(SubAccountsResponse{
+                    user_id,
+                    sub_accounts: vec![redis_sub_account.name],
+                })),
+
+                Err(e) => {
+                    let error = ApiError::new(format!("Failed to queue sub-account: {}", e), 500);
+                    Ok(HttpResponse::InternalServerError().json(error))
+                }
+            }
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
+async fn request_transfer(
+    redis_client: web::Data<Arc<Client>>,
+    transfer_req: web::Json<TransferRequest>,
+) -> Result<HttpResponse>{
+    let transfer_req = transfer_req.into_inner();
+    println!("Received transfer request: {:?}", transfer_req);
+
+    if transfer_req.amount <= rust_decimal::Decimal::ZERO {
+        let error = ApiError::new(format!("Invalid amount: {}", transfer_req.amount), 400);
+        return Ok(HttpResponse::BadRequest().json(error));
+    }
+
+    let redis_transfer = RedisTransferRequest {
+        transfer_id: Uuid::new_v4().to_string(),
+        user_id: transfer_req.user_id,
+        from_sub_account: transfer_req.from_sub_account,
+        to_user_id: transfer_req.to_user_id,
+        to_sub_account: transfer_req.to_sub_account,
+        asset: transfer_req.asset,
+        amount: transfer_req.amount,
+        timestamp: Utc::now(),
+    };
+
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let json = serde_json::to_string(&redis_transfer).unwrap();
+
+            match con.lpush::<_, _, ()>("transfer_queue", json).await {
+                Ok(_) => Ok(HttpResponse::Ok().json(TransferQueuedResponse{
+                    success: true,
+                    transfer_id: redis_transfer.transfer_id,
+                })),
+
+                Err(e) => {
+                    let error = ApiError::new(format!("Failed to queue transfer: {}", e), 500);
+                    Ok(HttpResponse::InternalServerError().json(error))
+                }
+            }
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
+// Transfers from or to a user, rejected ones included, newest first
+async fn get_transfers(
+    redis_client: web::Data<Arc<Client>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse>{
+    let user_id = path.into_inner();
+
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let transfers: std::collections::HashMap<String, String> = con.hgetall(format!("transfers:{}", user_id)).await.unwrap_or_default();
+
+            let mut transfers: Vec<InternalTransfer> = transfers.values()
+                .filter_map(|json| serde_json::from_str::<InternalTransfer>(json).ok())
+                .collect();
+            transfers.sort_by_key(|transfer| std::cmp::Reverse(transfer.timestamp));
+
+            Ok(HttpResponse::Ok().json(transfers))
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
+// The wallet service hands out addresses; the first request for an asset queues one.
+// When the last attempt failed, the request is queued again and the failure returned.
+async fn get_deposit_address(
+    redis_client: web::Data<Arc<Client>>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse>{
+    let (user_id, asset) = path.into_inner();
+    let key = format!("{}:{}", user_id, asset);
+
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let address: Option<String> = con.hget("deposit_addresses", &key).await.unwrap_or(None);
+            if address.is_some() {
+                return Ok(HttpResponse::Ok().json(DepositAddressResponse{ user_id, asset, address }));
+            }
+
+            let failure: Option<String> = con.hget("wallet_errors", format!("address:{}", key)).await.unwrap_or(None);
+            match con.rpush::<_, _, ()>("deposit_address_requests", &key).await {
+                Ok(_) => match failure {
+                    Some(failure) => Ok(HttpResponse::BadRequest().json(ApiError::new(failure, 400))),
+                    None => Ok(HttpResponse::Accepted().json(DepositAddressResponse{ user_id, asset, address: None })),
+                },
+                Err(e) => {
+                    let error = ApiError::new(format!("Failed to queue deposit address request: {}", e), 500);
+                    Ok(HttpResponse::InternalServerError().json(error))
+                }
+            }
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
+// Deposits of a user: pending ones first, then credited ones newest first
+async fn get_deposits(
+    redis_client: web::Data<Arc<Client>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse>{
+    let user_id = path.into_inner();
+
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let deposits: std::collections::HashMap<String, String> = con.hgetall(format!("deposits:{}", user_id)).await.unwrap_or_default();
+
+            let mut deposits: Vec<Deposit> = deposits.values()
+                .filter_map(|json| serde_json::from_str::<Deposit>(json).ok())
+                .collect();
+            deposits.sort_by(|a, b| b.credited_at.is_none().cmp(&a.credited_at.is_none()).then(b.credited_at.cmp(&a.credited_at)));
+
+            Ok(HttpResponse::Ok().json(deposits))
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
+async fn request_withdrawal(
+    redis_client: web::Data<Arc<Client>>,
+    withdrawal_req: web::Json<WithdrawalRequest>,
+) -> Result<HttpResponse>{
+    let withdrawal_req = withdrawal_req.into_inner();
+    println!("Received withdrawal request: {:?}", withdrawal_req);
+
+    if withdrawal_req.amount <= rust_decimal::Decimal::ZERO {
+        let error = ApiError::new(format!("Invalid amount: {}", withdrawal_req.amount), 400);
+        return Ok(HttpResponse::BadRequest().json(error));
+    }
+
+    let redis_withdrawal = RedisWithdrawalRequest {
+        withdrawal_id: Uuid::new_v4().to_string(),
+        action: "request".to_string(),
+        user_id: Some(withdrawal_req.user_id),
+        asset: Some(withdrawal_req.asset),
+        amount: Some(withdrawal_req.amount),
+        address: Some(withdrawal_req.address),
+        reason: None,
+        timestamp: Utc::now(),
+    };
+
+    queue_withdrawal_action(redis_client, redis_withdrawal).await
+}
+
+async fn get_withdrawal(
+    redis_client: web::Data<Arc<Client>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse>{
+    let withdrawal_id = path.into_inner();
+
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let withdrawal: Option<String> = con.hget("withdrawals", &withdrawal_id).await.unwrap_or(None);
+
+            match withdrawal.and_then(|json| serde_json::from_str::<Withdrawal>(&json).ok()) {
+                Some(withdrawal) => Ok(HttpResponse::Ok().json(withdrawal)),
+                None => {
+                    let error = ApiError::new(format!("Withdrawal {} not found", withdrawal_id), 404);
+                    Ok(HttpResponse::NotFound().json(error))
+                }
+            }
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
+// `action` is "approve" or "reject"
+async fn change_withdrawal(
+    redis_client: web::Data<Arc<Client>>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse>{
+    let (withdrawal_id, action) = path.into_inner();
+
+    if !matches!(action.as_str(), "approve" | "reject") {
+        let error = ApiError::new(format!("Unknown withdrawal action: {}", action), 400);
+        return Ok(HttpResponse::BadRequest().json(error));
+    }
+    if Uuid::parse_str(&withdrawal_id).is_err() {
+        let error = ApiError::new(format!("Invalid withdrawal id: {}", withdrawal_id), 400);
+        return Ok(HttpResponse::BadRequest().json(error));
+    }
+
+    let redis_withdrawal = RedisWithdrawalRequest {
+        withdrawal_id,
+        action,
+        user_id: None,
+        asset: None,
+        amount: None,
+        address: None,
+        reason: None,
+        timestamp: Utc::now(),
+    };
+
+    queue_withdrawal_action(redis_client, redis_withdrawal).await
+}
+
+async fn queue_withdrawal_action(
+    redis_client: web::Data<Arc<Client>>,
+    redis_withdrawal: RedisWithdrawalRequest,
+) -> Result<HttpResponse>{
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let json = serde_json::to_string(&redis_withdrawal).unwrap();
+
+            match con.lpush::<_, _, ()>("withdrawal_queue", json).await {
+                Ok(_) => Ok(HttpResponse::Ok().json(WithdrawalActionResponse{
+                    success: true,
+                    withdrawal_id: redis_withdrawal.withdrawal_id,
+                    action: redis_withdrawal.action,
+                })),
+
+                Err(e) => {
+                    let error = ApiError::new(format!("Failed to queue withdrawal: {}", e), 500);
+                    Ok(HttpResponse::InternalServerError().json(error))
+                }
+            }
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
+
 async fn get_tickers(
     _redis_client: web::Data<Arc<Client>>,
 ) -> Result<HttpResponse>{
@@ -226,6 +750,139 @@ async fn get_ticker(
 }
 
 
+async fn get_market_statuses(
+    redis_client: web::Data<Arc<Client>>,
+) -> Result<HttpResponse>{
+    println!("Getting market statuses");
+
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let statuses: std::collections::HashMap<String, String> = con.hgetall("markets").await.unwrap_or_default();
+
+            let mut markets: Vec<MarketStatusResponse> = statuses.values()
+                .filter_map(|json| serde_json::from_str::<RedisMarketStatus>(json).ok())
+                .map(|status| MarketStatusResponse{
+                    market: status.market,
+                    status: status.status,
+                    timestamp: status.timestamp,
+                })
+                .collect();
+            markets.sort_by(|a, b| a.market.cmp(&b.market));
+
+            Ok(HttpResponse::Ok().json(markets))
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
+async fn list_market(
+    redis_client: web::Data<Arc<Client>>,
+    list_req: web::Json<ListMarketRequest>,
+) -> Result<HttpResponse>{
+    let list_req = list_req.into_inner();
+    queue_market_action(redis_client, list_req.market, "list".to_string(), list_req.spec, None).await
+}
+
+// `action` is one of "halt", "resume", "cancel_only", "delist", "auction" or "uncross"
+async fn change_market_status(
+    redis_client: web::Data<Arc<Client>>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse>{
+    let (market, action) = path.into_inner();
+
+    if !matches!(action.as_str(), "halt" | "resume" | "cancel_only" | "delist" | "auction" | "uncross") {
+        let error = ApiError::new(format!("Unknown market action: {}", action), 400);
+        return Ok(HttpResponse::BadRequest().json(error));
+    }
+
+    queue_market_action(redis_client, market, action, None, None).await
+}
+
+async fn set_reference_price(
+    redis_client: web::Data<Arc<Client>>,
+    path: web::Path<String>,
+    price_req: web::Json<ReferencePriceRequest>,
+) -> Result<HttpResponse>{
+    queue_market_action(redis_client, path.into_inner(), "set_reference_price".to_string(), None, Some(price_req.price)).await
+}
+
+// The engine halts the markets of any violation; the report shows up in GET /admin/invariants
+async fn check_invariants(
+    redis_client: web::Data<Arc<Client>>,
+) -> Result<HttpResponse>{
+    queue_market_action(redis_client, String::new(), "check_invariants".to_string(), None, None).await
+}
+
+async fn get_invariant_report(
+    redis_client: web::Data<Arc<Client>>,
+) -> Result<HttpResponse>{
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let report: Option<String> = con.get("invariant_report").await.unwrap_or(None);
+
+            match report.and_then(|json| serde_json::from_str::<InvariantReportResponse>(&json).ok()) {
+                Some(response) => Ok(HttpResponse::Ok().json(response)),
+                None => {
+                    let error = ApiError::new("No invariant check has run yet".to_string(), 404);
+                    Ok(HttpResponse::NotFound().json(error))
+                }
+            }
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
+async fn queue_market_action(
+    redis_client: web::Data<Arc<Client>>,
+    market: String,
+    action: String,
+    spec: Option<MarketSpec>,
+    price: Option<rust_decimal::Decimal>,
+) -> Result<HttpResponse>{
+    println!("Received {} request for market {}", action, market);
+
+    let redis_admin = RedisAdminRequest {
+        market: market.clone(),
+        action: action.clone(),
+        spec,
+        price,
+        timestamp: Utc::now(),
+    };
+
+    match redis_client.get_async_connection().await{
+        Ok(mut con) => {
+            let json = serde_json::to_string(&redis_admin).unwrap();
+
+            match con.lpush::<_, _, ()>("admin_queue", json).await {
+                Ok(_) => Ok(HttpResponse::Ok().json(MarketActionResponse{
+                    success: true,
+                    market,
+                    action,
+                })),
+
+                Err(e) => {
+                    let error = ApiError::new(format!("Failed to queue market action: {}", e), 500);
+                    Ok(HttpResponse::InternalServerError().json(error))
+                }
+            }
+        }
+
+        Err(e) => {
+            let error = ApiError::new(format!("Redis connection error: {}", e), 500);
+            Ok(HttpResponse::InternalServerError().json(error))
+        }
+    }
+}
+
+
 async fn health_check() -> Result<HttpResponse>{
     Ok(HttpResponse::Ok().json(serde_json::json!({
         "status": "healthy",
@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
 use rust_decimal::Decimal;
 use chrono::{DateTime, Utc};
+use crate::matching_engine::spec::MarketSpec;
+use crate::matching_engine::fees::FeeSchedule;
+use crate::matching_engine::invariants::Violation;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PlaceOrderRequest {
@@ -9,7 +12,148 @@ pub struct PlaceOrderRequest {
     pub market: String,
     pub side: String,
     pub price: Option<Decimal>,
+    #[serde(default)]
     pub quantity: Decimal,
+    #[serde(default)]
+    pub quote_quantity: Option<Decimal>,
+    // Set for stop and stop-limit orders
+    #[serde(default)]
+    pub trigger_price: Option<Decimal>,
+    // Iceberg orders only show this much of their quantity at a time
+    #[serde(default)]
+    pub display_quantity: Option<Decimal>,
+    #[serde(default)]
+    pub time_in_force: Option<String>,
+    #[serde(default)]
+    pub self_trade_prevention: Option<String>,
+    #[serde(default)]
+    pub sub_account: Option<String>,
+}
+
+// Fields left out keep their current value; `quantity` is the new open quantity
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AmendOrderRequest {
+    pub user_id: String,
+    pub market: String,
+    pub price: Option<Decimal>,
+    pub quantity: Option<Decimal>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListMarketRequest {
+    pub market: String,
+    // Fields left out of the spec take the defaults
+    #[serde(default)]
+    pub spec: Option<MarketSpec>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReferencePriceRequest {
+    pub price: Decimal,
+}
+
+// Sub-accounts are left out for the main account
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferRequest {
+    pub user_id: String,
+    #[serde(default)]
+    pub from_sub_account: Option<String>,
+    pub to_user_id: String,
+    #[serde(default)]
+    pub to_sub_account: Option<String>,
+    pub asset: String,
+    pub amount: Decimal,
+}
+
+// Follow the transfer in GET /transfers/{user_id}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferQueuedResponse {
+    pub success: bool,
+    pub transfer_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubAccountRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubAccountsResponse {
+    pub user_id: String,
+    pub sub_accounts: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WithdrawalRequest {
+    pub user_id: String,
+    pub asset: String,
+    pub amount: Decimal,
+    pub address: String,
+}
+
+// Withdrawals are handled asynchronously; follow them with GET /withdrawals/{id}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WithdrawalActionResponse {
+    pub success: bool,
+    pub withdrawal_id: String,
+    pub action: String,
+}
+
+// `address` is missing while a new one is being created; ask again shortly
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DepositAddressResponse {
+    pub user_id: String,
+    pub asset: String,
+    pub address: Option<String>,
+}
+
+// The engine applies admin actions asynchronously; this only says it was queued
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarketActionResponse {
+    pub success: bool,
+    pub market: String,
+    pub action: String,
+}
+
+// An empty list of violations means everything added up
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InvariantReportResponse {
+    pub violations: Vec<Violation>,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarketStatusResponse {
+    pub market: String,
+    pub status: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+// Trading rules and status of one market, as listed by GET /markets
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarketInfo {
+    pub market: String,
+    pub base: String,
+    pub quote: String,
+    pub status: Option<String>,
+    pub tick_size: Decimal,
+    pub step_size: Decimal,
+    pub min_quantity: Decimal,
+    pub max_quantity: Option<Decimal>,
+    pub min_notional: Decimal,
+    pub base_precision: u32,
+    pub quote_precision: u32,
+    pub band_percent: Option<Decimal>,
+    pub breaker_percent: Option<Decimal>,
+    pub breaker_window_secs: u64,
+    pub auction_secs: Option<u64>,
+    pub fees: FeeSchedule,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExchangeInfoResponse {
+    pub markets: Vec<MarketInfo>,
+    pub timestamp: DateTime<Utc>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,9 +174,18 @@ pub struct BalanceResponse {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TradeInfo{
     pub trade_id: String,
+    pub market: String,
     pub price: Decimal,
     pub quantity: Decimal,
     pub timestamp: DateTime<Utc>,
+    pub side: String,
+    pub buyer_user_id: String,
+    pub seller_user_id: String,
+    pub maker_order_id: String,
+    pub taker_order_id: String,
+    // Buyer's fee in base, seller's fee in quote; negative for rebates
+    pub buyer_fee: Decimal,
+    pub seller_fee: Decimal,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -43,6 +196,17 @@ pub struct DepthResponse {
     pub timestamp: DateTime<Utc>,
 }
 
+// `price` is missing while nothing in the book crosses
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuctionResponse {
+    pub market: String,
+    pub price: Option<Decimal>,
+    pub volume: Decimal,
+    pub surplus: Decimal,
+    pub ends_at: Option<DateTime<Utc>>,
+    pub timestamp: DateTime<Utc>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PriceLevel {
     pub price: Decimal,
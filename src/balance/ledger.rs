@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use rust_decimal::Decimal;
+use uuid::Uuid;
+use serde::{Deserialize, Serialize};
+
+
+// Where funds sit. Every asset has its own set of accounts.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Account{
+    // Free funds of a user
+    Available(String),
+    // Funds of a user reserved for open orders
+    Locked(String),
+    // Trading fees the exchange collected, net of maker rebates
+    Fees,
+    // Everything outside the exchange: deposits come from here and withdrawals go back
+    External,
+}
+
+impl Account{
+    pub fn user_id(&self) -> Option<&str>{
+        match self{
+            Account::Available(user_id) | Account::Locked(user_id) => Some(user_id),
+            Account::Fees | Account::External => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind{
+    Deposit,
+    Withdrawal,
+    Lock,
+    Unlock,
+    Trade,
+    Fee,
+    Transfer,
+}
+
+// What caused an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Reference{
+    // Balances a user was set up with
+    Opening,
+    Order(Uuid),
+    Trade(Uuid),
+    Transfer(Uuid),
+}
+
+// Moves `amount` of `asset` from one account to another, so every entry balances by itself
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerEntry{
+    // Position in the ledger, from 1
+    pub seq: u64,
+    pub kind: EntryKind,
+    pub reference: Reference,
+    pub asset: String,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Decimal,
+}
+
+impl LedgerEntry{
+    // Numbered when it is posted
+    pub fn new(kind: EntryKind, reference: Reference, asset: &str, from: Account, to: Account, amount: Decimal) -> LedgerEntry{
+        LedgerEntry{
+            seq: 0,
+            kind,
+            reference,
+            asset: asset.to_string(),
+            from,
+            to,
+            amount,
+        }
+    }
+
+    pub fn touches_user(&self, user_id: &str) -> bool{
+        self.from.user_id() == Some(user_id) || self.to.user_id() == Some(user_id)
+    }
+}
+
+
+// Append-only list of entries. Account balances are a projection of it, kept up to date as
+// entries are posted and rebuilt after loading.
+//
+// Only the entries since the last checkpoint are kept in memory. `compact` hands the older
+// ones over to be archived and keeps the balances they added up to, so snapshots and
+// replays stay the size of the recent entries rather than the whole history.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Ledger{
+    // Every balance after the first `archived` entries
+    checkpoint: Vec<(Account, String, Decimal)>,
+    archived: u64,
+    // Entries after the checkpoint, oldest first
+    entries: Vec<LedgerEntry>,
+    // asset -> maker rebates ever paid out of the fee account
+    rebates: HashMap<String, Decimal>,
+    #[serde(skip)]
+    balances: HashMap<(Account, String), Decimal>,
+    // (user, asset) -> seq of every entry in `entries` that touched them
+    #[serde(skip)]
+    history: HashMap<(String, String), Vec<u64>>,
+}
+
+impl Ledger{
+    pub fn balance(&self, account: &Account, asset: &str) -> Decimal{
+        self.balances.get(&(account.clone(), asset.to_string())).copied().unwrap_or_default()
+    }
+
+    // Whether any entry ever touched the account
+    pub fn has_account(&self, account: &Account, asset: &str) -> bool{
+        self.balances.contains_key(&(account.clone(), asset.to_string()))
+    }
+
+    // Every (account, asset) pair with its balance
+    pub fn balances(&self) -> impl Iterator<Item = (&Account, &str, Decimal)>{
+        self.balances.iter().map(|((account, asset), balance)| (account, asset.as_str(), *balance))
+    }
+
+    // Entries since the last checkpoint
+    pub fn entries(&self) -> &[LedgerEntry]{
+        &self.entries
+    }
+
+    pub fn rebates(&self, asset: &str) -> Decimal{
+        self.rebates.get(asset).copied().unwrap_or_default()
+    }
+
+    // How many entries have been moved out to the archive
+    pub fn archived(&self) -> u64{
+        self.archived
+    }
+
+    // Post entries together or not at all. User accounts may never go negative; the fee and
+    // external accounts can.
+    pub fn post(&mut self, entries: Vec<LedgerEntry>) -> Result<(), String>{
+        let mut changes: HashMap<(Account, String), Decimal> = HashMap::new();
+        for entry in &entries {
+            if entry.amount < Decimal::ZERO {
+                return Err(format!("Negative ledger amount {} {}", entry.amount, entry.asset));
+            }
+            *changes.entry((entry.from.clone(), entry.asset.clone())).or_default() -= entry.amount;
+            *changes.entry((entry.to.clone(), entry.asset.clone())).or_default() += entry.amount;
+        }
+
+        for ((account, asset), change) in &changes {
+            let Some(user_id) = account.user_id() else {
+                continue;
+            };
+            if self.balance(account, asset) + change < Decimal::ZERO {
+                return Err(match account {
+                    Account::Locked(_) => format!("Insufficient locked {} for user {}", asset, user_id),
+                    _ => format!("Insufficient {} balance for user {}", asset, user_id),
+                });
+            }
+        }
+
+        for (key, change) in changes {
+            *self.balances.entry(key).or_default() += change;
+        }
+        for mut entry in entries {
+            if entry.from == Account::Fees && entry.kind == EntryKind::Fee {
+                *self.rebates.entry(entry.asset.clone()).or_default() += entry.amount;
+            }
+            entry.seq = self.archived + self.entries.len() as u64 + 1;
+            self.index(&entry);
+            self.entries.push(entry);
+        }
+        Ok(())
+    }
+
+    fn index(&mut self, entry: &LedgerEntry){
+        let mut users = vec![entry.from.user_id(), entry.to.user_id()];
+        users.dedup();
+        for user_id in users.into_iter().flatten() {
+            self.history.entry((user_id.to_string(), entry.asset.clone())).or_default().push(entry.seq);
+        }
+    }
+
+    // Entries since the last checkpoint that moved `asset` in or out of one of the user's
+    // accounts, oldest first. Older ones are in the archive.
+    pub fn history(&self, user_id: &str, asset: &str) -> Vec<LedgerEntry>{
+        let Some(seqs) = self.history.get(&(user_id.to_string(), asset.to_string())) else {
+            return Vec::new();
+        };
+        seqs.iter()
+            .map(|seq| self.entries[(seq - self.archived - 1) as usize].clone())
+            .collect()
+    }
+
+    // Move the checkpoint up to entry `through`, or the latest one if there are fewer.
+    // Returns the entries it now covers, which are no longer kept here and have to be
+    // archived by the caller.
+    pub fn compact(&mut self, through: u64) -> Vec<LedgerEntry>{
+        let count = (through.saturating_sub(self.archived) as usize).min(self.entries.len());
+        let compacted: Vec<LedgerEntry> = self.entries.drain(..count).collect();
+
+        self.checkpoint = self.replay(&compacted).into_iter()
+            .map(|((account, asset), balance)| (account, asset, balance))
+            .collect();
+        self.archived += count as u64;
+        self.reindex();
+        compacted
+    }
+
+    // Recompute every balance from the checkpoint and the entries after it, after loading
+    // the ledger
+    pub fn rebuild(&mut self){
+        self.balances = self.replay(&self.entries);
+        self.reindex();
+    }
+
+    fn reindex(&mut self){
+        self.history.clear();
+        for entry in std::mem::take(&mut self.entries) {
+            self.index(&entry);
+            self.entries.push(entry);
+        }
+    }
+
+    // Whether the balances kept up to date as entries were posted still match the entries
+    pub fn is_consistent(&self) -> bool{
+        self.replay(&self.entries) == self.balances
+    }
+
+    // Balances after applying `entries` to the checkpoint
+    fn replay(&self, entries: &[LedgerEntry]) -> HashMap<(Account, String), Decimal>{
+        let mut balances: HashMap<(Account, String), Decimal> = self.checkpoint.iter()
+            .map(|(account, asset, balance)| ((account.clone(), asset.clone()), *balance))
+            .collect();
+        for entry in entries {
+            *balances.entry((entry.from.clone(), entry.asset.clone())).or_default() -= entry.amount;
+            *balances.entry((entry.to.clone(), entry.asset.clone())).or_default() += entry.amount;
+        }
+        balances
+    }
+}
@@ -1,8 +1,28 @@
-use std::collections::HashMap;
+pub mod ledger;
+pub mod transfer;
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
 use rust_decimal::Decimal;
 use uuid::Uuid;
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use crate::matching_engine::types::{Trade, TradingPair};
+use crate::wallet::{Deposit, DepositStatus, Withdrawal, WithdrawalStatus};
+use self::ledger::{Account, EntryKind, Ledger, LedgerEntry, Reference};
+use self::transfer::InternalTransfer;
+
+// Sub-accounts have balances of their own, kept under "{user_id}/{name}"
+pub const SUB_ACCOUNT_SEPARATOR: char = '/';
 
-#[derive(Debug, Clone)]
+pub fn account_id(user_id: &str, sub_account: Option<&str>) -> String {
+    match sub_account {
+        Some(name) => format!("{}{}{}", user_id, SUB_ACCOUNT_SEPARATOR, name),
+        None => user_id.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserBalance {
     pub available: Decimal,  // Free to use
     pub locked: Decimal,     // Reserved for open orders
@@ -21,131 +41,531 @@ impl UserBalance {
     }
 }
 
-#[derive(Debug)]
+// What an open order holds locked, and the market it was placed on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reservation {
+    pub user_id: String,
+    pub pair: TradingPair,
+    pub asset: String,
+    pub amount: Decimal,
+}
+
+// Balances of every user plus the funds reserved for open orders. Balances are projections
+// of the ledger; every change to them is posted there first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BalanceState {
+    users: HashSet<String>,
+    // order_id -> reservation, for unlocking on cancel
+    locked_funds: HashMap<Uuid, Reservation>,
+    ledger: Ledger,
+    // Every withdrawal requested; open ones hold their amount in the user's locked funds
+    withdrawals: HashMap<Uuid, Withdrawal>,
+    // Credited deposits by transaction hash, so none is credited twice
+    deposits: HashMap<String, Deposit>,
+    // Master user -> names of its sub-accounts
+    sub_accounts: HashMap<String, BTreeSet<String>>,
+}
+
+// Shared handle to the balances of all users. Every engine shard holds a clone.
+//
+// Locking protocol: each call takes the lock for its whole duration, so it is atomic with
+// respect to every other shard, and the lock is never held across calls. An order first
+// reserves what it can spend with `lock_funds` before it touches a book; matching only ever
+// settles against those reservations (`settle_trade`, `release_funds`, `unlock_funds`), so
+// shards never compete for available funds after an order has been accepted.
+#[derive(Debug, Clone, Default)]
 pub struct BalanceManager {
-    // user_id -> asset -> balance
-    balances: HashMap<String, HashMap<String, UserBalance>>,
-    // order_id -> (user_id, asset, locked_amount) for unlocking on cancel
-    locked_funds: HashMap<Uuid, (String, String, Decimal)>,
+    state: Arc<Mutex<BalanceState>>,
 }
 
 impl BalanceManager {
     pub fn new() -> Self {
-        BalanceManager {
-            balances: HashMap::new(),
-            locked_funds: HashMap::new(),
-        }
+        BalanceManager::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, BalanceState> {
+        // A shard that panicked mid-call can't leave a half-applied change behind: every
+        // method validates before it mutates, so the data is still usable
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn add_user(&self, user_id: String, initial_balances: HashMap<String, Decimal>) -> Result<(), String> {
+        self.state().add_user(user_id, initial_balances)
+    }
+
+    pub fn can_place_order(&self, user_id: &str, asset: &str, required_amount: Decimal) -> bool {
+        self.state().can_place_order(user_id, asset, required_amount)
+    }
+
+    pub fn lock_funds(&self, order_id: Uuid, pair: &TradingPair, user_id: &str, asset: &str, amount: Decimal) -> Result<(), String> {
+        self.state().lock_funds(order_id, pair, user_id, asset, amount)
+    }
+
+    pub fn unlock_funds(&self, order_id: Uuid) -> Result<(), String> {
+        self.state().unlock_funds(order_id)
+    }
+
+    pub fn release_funds(&self, order_id: Uuid, amount: Decimal) -> Result<(), String> {
+        self.state().release_funds(order_id, amount)
+    }
+
+    pub fn adjust_lock(&self, order_id: Uuid, new_amount: Decimal) -> Result<(), String> {
+        self.state().adjust_lock(order_id, new_amount)
+    }
+
+    // What an order still has reserved, None once it has nothing locked
+    pub fn reserved(&self, order_id: Uuid) -> Option<Decimal> {
+        self.state().locked_funds.get(&order_id).map(|reservation| reservation.amount)
+    }
+
+    pub fn settle_trade(&self, trade: &Trade) -> Result<(), String> {
+        self.state().settle_trade(trade)
+    }
+
+    pub fn execute_trade(&self, trade: &Trade) -> Result<(), String> {
+        self.state().execute_trade(trade)
+    }
+
+    pub fn deposit(&self, transfer_id: Uuid, user_id: &str, asset: &str, amount: Decimal) -> Result<(), String> {
+        self.state().deposit(transfer_id, user_id, asset, amount)
+    }
+
+    pub fn withdraw(&self, transfer_id: Uuid, user_id: &str, asset: &str, amount: Decimal) -> Result<(), String> {
+        self.state().withdraw(transfer_id, user_id, asset, amount)
+    }
+
+    pub fn add_sub_account(&self, user_id: &str, name: &str) -> Result<(), String> {
+        self.state().add_sub_account(user_id, name)
+    }
+
+    pub fn sub_accounts(&self, user_id: &str) -> Vec<String> {
+        self.state().sub_accounts.get(user_id).map(|names| names.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    pub fn transfer(&self, transfer: InternalTransfer) -> Result<InternalTransfer, String> {
+        self.state().transfer(transfer)
+    }
+
+    pub fn credit_deposit(&self, deposit: Deposit, now: DateTime<Utc>) -> Result<Deposit, String> {
+        self.state().credit_deposit(deposit, now)
+    }
+
+    pub fn deposit_credited(&self, tx_hash: &str) -> bool {
+        self.state().deposits.contains_key(tx_hash)
+    }
+
+    pub fn request_withdrawal(&self, withdrawal: Withdrawal) -> Result<Withdrawal, String> {
+        self.state().request_withdrawal(withdrawal)
+    }
+
+    pub fn approve_withdrawal(&self, withdrawal_id: Uuid, now: DateTime<Utc>) -> Result<Withdrawal, String> {
+        self.state().approve_withdrawal(withdrawal_id, now)
+    }
+
+    pub fn broadcast_withdrawal(&self, withdrawal_id: Uuid, tx_hash: String, now: DateTime<Utc>) -> Result<Withdrawal, String> {
+        self.state().broadcast_withdrawal(withdrawal_id, tx_hash, now)
+    }
+
+    pub fn confirm_withdrawal(&self, withdrawal_id: Uuid, confirmations: u32, now: DateTime<Utc>) -> Result<Withdrawal, String> {
+        self.state().confirm_withdrawal(withdrawal_id, confirmations, now)
+    }
+
+    pub fn fail_withdrawal(&self, withdrawal_id: Uuid, reason: String, now: DateTime<Utc>) -> Result<Withdrawal, String> {
+        self.state().fail_withdrawal(withdrawal_id, reason, now)
+    }
+
+    // Withdrawals that haven't confirmed or failed yet, oldest first
+    pub fn open_withdrawals(&self) -> Vec<Withdrawal> {
+        let mut open: Vec<Withdrawal> = self.state().open_withdrawals().cloned().collect();
+        open.sort_by_key(|withdrawal| (withdrawal.requested_at, withdrawal.id));
+        open
+    }
+
+    pub fn get_balance(&self, user_id: &str, asset: &str) -> Option<UserBalance> {
+        self.state().get_balance(user_id, asset)
+    }
+
+    pub fn get_user_balances(&self, user_id: &str) -> Option<HashMap<String, UserBalance>> {
+        self.state().get_user_balances(user_id)
+    }
+
+    pub fn fee_balances(&self) -> HashMap<String, Decimal> {
+        self.state().fee_balances()
+    }
+
+    pub fn ledger_history(&self, user_id: &str, asset: &str) -> Vec<LedgerEntry> {
+        self.state().ledger_history(user_id, asset)
+    }
+
+    // Look at the state of all balances at one point in time, under the lock
+    pub fn inspect<T>(&self, f: impl FnOnce(&BalanceState) -> T) -> T {
+        f(&self.state())
+    }
+
+    // Copy of everything, taken under the lock
+    pub fn snapshot(&self) -> BalanceState {
+        self.state().clone()
+    }
+
+    // Checkpoint the ledger up to entry `through` and return the entries to archive
+    pub fn compact_ledger(&self, through: u64) -> Vec<LedgerEntry> {
+        self.state().compact_ledger(through)
+    }
+
+    pub fn restore(&self, mut state: BalanceState) {
+        state.ledger.rebuild();
+        *self.state() = state;
+    }
+}
+
+impl BalanceState {    
+    // Register a user and deposit their starting balances
+    // The user only exists once the opening balances are booked
+    pub fn add_user(&mut self, user_id: String, initial_balances: HashMap<String, Decimal>) -> Result<(), String> {
+        let entries = initial_balances.iter()
+            .map(|(asset, amount)| LedgerEntry::new(EntryKind::Deposit, Reference::Opening, asset, Account::External, Account::Available(user_id.clone()), *amount))
+            .collect();
+
+        self.ledger.post(entries).map_err(|e| format!("Failed to set up opening balances of {}: {}", user_id, e))?;
+        self.users.insert(user_id);
+        Ok(())
     }
     
-    // Initialize user with starting balances
-    pub fn add_user(&mut self, user_id: String, initial_balances: HashMap<String, Decimal>) {
-        let mut user_balances = HashMap::new();
-        for (asset, amount) in initial_balances {
-            user_balances.insert(asset, UserBalance::new(amount));
+    // A sub-account starts empty and is funded by transfers from its master
+    pub fn add_sub_account(&mut self, user_id: &str, name: &str) -> Result<(), String> {
+        if !self.users.contains(user_id) {
+            return Err(format!("User {} not found", user_id));
+        }
+        if name.is_empty() || name.contains(SUB_ACCOUNT_SEPARATOR) {
+            return Err(format!("Invalid sub-account name {:?}", name));
+        }
+
+        let account = account_id(user_id, Some(name));
+        if !self.users.insert(account) {
+            return Err(format!("Sub-account {} of user {} already exists", name, user_id));
         }
-        self.balances.insert(user_id, user_balances);
+        self.sub_accounts.entry(user_id.to_string()).or_default().insert(name.to_string());
+        Ok(())
     }
-    
+
+    // Move available funds between two accounts in one ledger entry. A user can fund their
+    // own sub-accounts and other users' main accounts.
+    pub fn transfer(&mut self, transfer: InternalTransfer) -> Result<InternalTransfer, String> {
+        let (from, to) = (transfer.from_account(), transfer.to_account());
+        if transfer.to_sub_account.is_some() && transfer.to_user_id != transfer.user_id {
+            return Err("Transfers to another user go to their main account".to_string());
+        }
+        if let Some(unknown) = [&from, &to].into_iter().find(|account| !self.users.contains(*account)) {
+            return Err(format!("Account {} not found", unknown));
+        }
+        if from == to {
+            return Err("Can't transfer to the same account".to_string());
+        }
+        if transfer.amount <= Decimal::ZERO {
+            return Err(format!("Invalid amount {}", transfer.amount));
+        }
+
+        self.ledger.post(vec![LedgerEntry::new(
+            EntryKind::Transfer,
+            Reference::Transfer(transfer.id),
+            &transfer.asset,
+            Account::Available(from),
+            Account::Available(to),
+            transfer.amount,
+        )])?;
+        Ok(transfer)
+    }
+
     // Check if user has enough balance for an order
     pub fn can_place_order(&self, user_id: &str, asset: &str, required_amount: Decimal) -> bool {
-        if let Some(user_balances) = self.balances.get(user_id) {
-            if let Some(balance) = user_balances.get(asset) {
-                return balance.available >= required_amount;
-            }
-        }
-        false
+        let account = Account::Available(user_id.to_string());
+        self.users.contains(user_id) && self.ledger.has_account(&account, asset) && self.ledger.balance(&account, asset) >= required_amount
     }
     
-    // Lock funds for an order (reserve them)
-    pub fn lock_funds(&mut self, order_id: Uuid, user_id: &str, asset: &str, amount: Decimal) -> Result<(), String> {
+    // Lock funds for an order on `pair` (reserve them)
+    pub fn lock_funds(&mut self, order_id: Uuid, pair: &TradingPair, user_id: &str, asset: &str, amount: Decimal) -> Result<(), String> {
         if !self.can_place_order(user_id, asset, amount) {
             return Err(format!("Insufficient {} balance for user {}", asset, user_id));
         }
-        
-        if let Some(user_balances) = self.balances.get_mut(user_id) {
-            if let Some(balance) = user_balances.get_mut(asset) {
-                balance.available -= amount;
-                balance.locked += amount;
-                
-                // Track locked funds for potential unlocking
-                self.locked_funds.insert(order_id, (user_id.to_string(), asset.to_string(), amount));
-                
-                return Ok(());
-            }
-        }
-        
-        Err(format!("User {} or asset {} not found", user_id, asset))
+
+        self.move_funds(EntryKind::Lock, Reference::Order(order_id), user_id, asset, amount)?;
+
+        // Track locked funds for potential unlocking
+        self.locked_funds.insert(order_id, Reservation{
+            user_id: user_id.to_string(),
+            pair: pair.clone(),
+            asset: asset.to_string(),
+            amount,
+        });
+        Ok(())
     }
     
     // Unlock funds (when order is cancelled)
     pub fn unlock_funds(&mut self, order_id: Uuid) -> Result<(), String> {
-        if let Some((user_id, asset, amount)) = self.locked_funds.remove(&order_id) {
-            if let Some(user_balances) = self.balances.get_mut(&user_id) {
-                if let Some(balance) = user_balances.get_mut(&asset) {
-                    balance.locked -= amount;
-                    balance.available += amount;
-                    return Ok(());
-                }
-            }
-        }
-        Err(format!("Order {} not found in locked funds", order_id))
+        let Some(Reservation{ user_id, asset, amount, .. }) = self.locked_funds.get(&order_id).cloned() else {
+            return Err(format!("Order {} not found in locked funds", order_id));
+        };
+
+        self.move_funds(EntryKind::Unlock, Reference::Order(order_id), &user_id, &asset, amount)?;
+        self.locked_funds.remove(&order_id);
+        Ok(())
     }
     
-    // Execute trade - transfer balances between users
-    pub fn execute_trade(&mut self, buyer_id: &str, seller_id: &str, 
-                        base_asset: &str, quote_asset: &str, 
-                        quantity: Decimal, price: Decimal) -> Result<(), String> {
-        let quote_amount = quantity * price;
-        
-        // Buyer: lose quote asset, gain base asset
-        self.transfer_asset(buyer_id, quote_asset, seller_id, quote_amount)?;
-        
-        // Seller: lose base asset, gain quote asset  
-        self.transfer_asset(seller_id, base_asset, buyer_id, quantity)?;
-        
+    // Release part of an order's locked funds, e.g. when a bid fills below its limit price
+    pub fn release_funds(&mut self, order_id: Uuid, amount: Decimal) -> Result<(), String> {
+        let Some(Reservation{ user_id, asset, amount: locked_amount, .. }) = self.locked_funds.get(&order_id).cloned() else {
+            return Err(format!("Order {} not found in locked funds", order_id));
+        };
+
+        if locked_amount < amount {
+            return Err(format!("Order {} has only {} {} locked", order_id, locked_amount, asset));
+        }
+
+        self.move_funds(EntryKind::Unlock, Reference::Order(order_id), &user_id, &asset, amount)?;
+        self.consume_locked(order_id, amount);
         Ok(())
     }
-    
-    // Helper: Transfer asset from one user to another
-    fn transfer_asset(&mut self, from_user: &str, asset: &str, to_user: &str, amount: Decimal) -> Result<(), String> {
-        // Remove from sender's locked funds
-        if let Some(from_balances) = self.balances.get_mut(from_user) {
-            if let Some(from_balance) = from_balances.get_mut(asset) {
-                if from_balance.locked >= amount {
-                    from_balance.locked -= amount;
-                } else {
-                    return Err(format!("Insufficient locked {} for user {}", asset, from_user));
-                }
-            } else {
-                return Err(format!("Asset {} not found for user {}", asset, from_user));
-            }
-        } else {
-            return Err(format!("User {} not found", from_user));
+
+    // Move an order's reservation to `new_amount`, locking more or releasing the difference
+    pub fn adjust_lock(&mut self, order_id: Uuid, new_amount: Decimal) -> Result<(), String> {
+        let Some(Reservation{ user_id, asset, amount: locked_amount, .. }) = self.locked_funds.get(&order_id).cloned() else {
+            return Err(format!("Order {} not found in locked funds", order_id));
+        };
+
+        if new_amount == locked_amount {
+            return Ok(());
         }
-        
-        // Add to receiver's available funds
-        if let Some(to_balances) = self.balances.get_mut(to_user) {
-            if let Some(to_balance) = to_balances.get_mut(asset) {
-                to_balance.available += amount;
-            } else {
-                // Create new asset balance if it doesn't exist
-                to_balances.insert(asset.to_string(), UserBalance::new(amount));
-            }
-        } else {
-            return Err(format!("User {} not found", to_user));
+        if new_amount < locked_amount {
+            return self.release_funds(order_id, locked_amount - new_amount);
         }
-        
+
+        let extra = new_amount - locked_amount;
+        if !self.can_place_order(&user_id, &asset, extra) {
+            return Err(format!("Insufficient {} balance for user {}", asset, user_id));
+        }
+
+        self.move_funds(EntryKind::Lock, Reference::Order(order_id), &user_id, &asset, extra)?;
+        if let Some(reservation) = self.locked_funds.get_mut(&order_id) {
+            reservation.amount = new_amount;
+        }
+        Ok(())
+    }
+
+    // Move funds of an order or withdrawal between a user's available and locked accounts
+    fn move_funds(&mut self, kind: EntryKind, reference: Reference, user_id: &str, asset: &str, amount: Decimal) -> Result<(), String> {
+        let (available, locked) = (Account::Available(user_id.to_string()), Account::Locked(user_id.to_string()));
+        let (from, to) = match kind {
+            EntryKind::Lock => (available, locked),
+            _ => (locked, available),
+        };
+        self.ledger.post(vec![LedgerEntry::new(kind, reference, asset, from, to, amount)])
+    }
+
+    // Settle a trade against the funds both orders locked when they were placed
+    pub fn settle_trade(&mut self, trade: &Trade) -> Result<(), String> {
+        self.execute_trade(trade)?;
+
+        self.consume_locked(trade.buyer_order_id, trade.quantity * trade.price);
+        self.consume_locked(trade.seller_order_id, trade.quantity);
         Ok(())
     }
+
+    // Reduce an order's reservation after its locked funds were spent in a trade
+    fn consume_locked(&mut self, order_id: Uuid, amount: Decimal) {
+        if let Some(reservation) = self.locked_funds.get_mut(&order_id) {
+            reservation.amount -= amount;
+        }
+    }
+    
+    // Execute trade - move both legs out of the sides' locked funds, then take each side's
+    // fee out of what it received. Posted as one batch, so it happens completely or not at all.
+    pub fn execute_trade(&mut self, trade: &Trade) -> Result<(), String> {
+        let (buyer_id, seller_id) = (&trade.buyer_account(), &trade.seller_account());
+        if let Some(unknown) = [buyer_id, seller_id].into_iter().find(|user_id| !self.users.contains(*user_id)) {
+            return Err(format!("User {} not found", unknown));
+        }
+
+        let (base, quote) = (&trade.pair.base, &trade.pair.quote);
+        let reference = Reference::Trade(trade.id);
+        let mut entries = vec![
+            // Buyer: lose quote asset, gain base asset
+            LedgerEntry::new(EntryKind::Trade, reference, quote, Account::Locked(buyer_id.clone()), Account::Available(seller_id.clone()), trade.quantity * trade.price),
+            // Seller: lose base asset, gain quote asset
+            LedgerEntry::new(EntryKind::Trade, reference, base, Account::Locked(seller_id.clone()), Account::Available(buyer_id.clone()), trade.quantity),
+        ];
+        entries.extend(fee_entry(reference, buyer_id, base, trade.buyer_fee));
+        entries.extend(fee_entry(reference, seller_id, quote, trade.seller_fee));
+
+        self.ledger.post(entries)
+    }
+
+    pub fn deposit(&mut self, transfer_id: Uuid, user_id: &str, asset: &str, amount: Decimal) -> Result<(), String> {
+        self.external_transfer(EntryKind::Deposit, transfer_id, user_id, asset, amount)
+    }
+
+    pub fn withdraw(&mut self, transfer_id: Uuid, user_id: &str, asset: &str, amount: Decimal) -> Result<(), String> {
+        self.external_transfer(EntryKind::Withdrawal, transfer_id, user_id, asset, amount)
+    }
+
+    fn external_transfer(&mut self, kind: EntryKind, transfer_id: Uuid, user_id: &str, asset: &str, amount: Decimal) -> Result<(), String> {
+        if !self.users.contains(user_id) {
+            return Err(format!("User {} not found", user_id));
+        }
+        if amount <= Decimal::ZERO {
+            return Err(format!("Invalid amount {}", amount));
+        }
+
+        let available = Account::Available(user_id.to_string());
+        let (from, to) = match kind {
+            EntryKind::Deposit => (Account::External, available),
+            _ => (available, Account::External),
+        };
+        self.ledger.post(vec![LedgerEntry::new(kind, Reference::Transfer(transfer_id), asset, from, to, amount)])
+    }
     
+    pub fn credit_deposit(&mut self, mut deposit: Deposit, now: DateTime<Utc>) -> Result<Deposit, String> {
+        if self.deposits.contains_key(&deposit.tx_hash) {
+            return Err(format!("Deposit {} was already credited", deposit.tx_hash));
+        }
+
+        self.deposit(deposit.id, &deposit.user_id, &deposit.asset, deposit.amount)?;
+        deposit.status = DepositStatus::Credited;
+        deposit.credited_at = Some(now);
+        self.deposits.insert(deposit.tx_hash.clone(), deposit.clone());
+        Ok(deposit)
+    }
+
+    // Hold the amount of a new withdrawal until it confirms or fails
+    pub fn request_withdrawal(&mut self, withdrawal: Withdrawal) -> Result<Withdrawal, String> {
+        if self.withdrawals.contains_key(&withdrawal.id) {
+            return Err(format!("Withdrawal {} already exists", withdrawal.id));
+        }
+        if withdrawal.amount <= Decimal::ZERO {
+            return Err(format!("Invalid amount {}", withdrawal.amount));
+        }
+        if !self.can_place_order(&withdrawal.user_id, &withdrawal.asset, withdrawal.amount) {
+            return Err(format!("Insufficient {} balance for user {}", withdrawal.asset, withdrawal.user_id));
+        }
+
+        self.move_funds(EntryKind::Lock, Reference::Transfer(withdrawal.id), &withdrawal.user_id, &withdrawal.asset, withdrawal.amount)?;
+        self.withdrawals.insert(withdrawal.id, withdrawal.clone());
+        Ok(withdrawal)
+    }
+
+    pub fn approve_withdrawal(&mut self, withdrawal_id: Uuid, now: DateTime<Utc>) -> Result<Withdrawal, String> {
+        let withdrawal = self.withdrawal_mut(withdrawal_id)?;
+        withdrawal.advance(WithdrawalStatus::Approved, now)?;
+        Ok(withdrawal.clone())
+    }
+
+    pub fn broadcast_withdrawal(&mut self, withdrawal_id: Uuid, tx_hash: String, now: DateTime<Utc>) -> Result<Withdrawal, String> {
+        let withdrawal = self.withdrawal_mut(withdrawal_id)?;
+        withdrawal.advance(WithdrawalStatus::Broadcast, now)?;
+        withdrawal.tx_hash = Some(tx_hash);
+        Ok(withdrawal.clone())
+    }
+
+    // The held funds leave the exchange
+    pub fn confirm_withdrawal(&mut self, withdrawal_id: Uuid, confirmations: u32, now: DateTime<Utc>) -> Result<Withdrawal, String> {
+        let mut withdrawal = self.withdrawal_mut(withdrawal_id)?.clone();
+        withdrawal.advance(WithdrawalStatus::Confirmed, now)?;
+        withdrawal.confirmations = confirmations;
+
+        self.ledger.post(vec![LedgerEntry::new(
+            EntryKind::Withdrawal,
+            Reference::Transfer(withdrawal_id),
+            &withdrawal.asset,
+            Account::Locked(withdrawal.user_id.clone()),
+            Account::External,
+            withdrawal.amount,
+        )])?;
+        self.withdrawals.insert(withdrawal_id, withdrawal.clone());
+        Ok(withdrawal)
+    }
+
+    // The held funds go back to the user
+    pub fn fail_withdrawal(&mut self, withdrawal_id: Uuid, reason: String, now: DateTime<Utc>) -> Result<Withdrawal, String> {
+        let mut withdrawal = self.withdrawal_mut(withdrawal_id)?.clone();
+        withdrawal.advance(WithdrawalStatus::Failed, now)?;
+        withdrawal.failure = Some(reason);
+
+        self.move_funds(EntryKind::Unlock, Reference::Transfer(withdrawal_id), &withdrawal.user_id, &withdrawal.asset, withdrawal.amount)?;
+        self.withdrawals.insert(withdrawal_id, withdrawal.clone());
+        Ok(withdrawal)
+    }
+
+    fn withdrawal_mut(&mut self, withdrawal_id: Uuid) -> Result<&mut Withdrawal, String> {
+        self.withdrawals.get_mut(&withdrawal_id).ok_or(format!("Withdrawal {} not found", withdrawal_id))
+    }
+
     // Get user's balance for an asset
-    pub fn get_balance(&self, user_id: &str, asset: &str) -> Option<&UserBalance> {
-        self.balances.get(user_id)?.get(asset)
+    pub fn get_balance(&self, user_id: &str, asset: &str) -> Option<UserBalance> {
+        let available = Account::Available(user_id.to_string());
+        let locked = Account::Locked(user_id.to_string());
+        if !self.users.contains(user_id) || !(self.ledger.has_account(&available, asset) || self.ledger.has_account(&locked, asset)) {
+            return None;
+        }
+
+        Some(UserBalance {
+            available: self.ledger.balance(&available, asset),
+            locked: self.ledger.balance(&locked, asset),
+        })
     }
     
     // Get all balances for a user
-    pub fn get_user_balances(&self, user_id: &str) -> Option<&HashMap<String, UserBalance>> {
-        self.balances.get(user_id)
+    pub fn get_user_balances(&self, user_id: &str) -> Option<HashMap<String, UserBalance>> {
+        if !self.users.contains(user_id) {
+            return None;
+        }
+
+        let balances = self.ledger.balances()
+            .filter(|(account, _, _)| account.user_id() == Some(user_id))
+            .filter_map(|(_, asset, _)| self.get_balance(user_id, asset).map(|balance| (asset.to_string(), balance)))
+            .collect();
+        Some(balances)
+    }
+
+    // asset -> fees collected, net of rebates
+    pub fn fee_balances(&self) -> HashMap<String, Decimal> {
+        self.ledger.balances()
+            .filter(|(account, _, _)| **account == Account::Fees)
+            .map(|(_, asset, balance)| (asset.to_string(), balance))
+            .collect()
+    }
+
+    // Every entry since the last ledger checkpoint that moved `asset` in or out of the
+    // user's accounts, oldest first
+    pub fn ledger_history(&self, user_id: &str, asset: &str) -> Vec<LedgerEntry> {
+        self.ledger.history(user_id, asset)
+    }
+
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    pub fn compact_ledger(&mut self, through: u64) -> Vec<LedgerEntry> {
+        self.ledger.compact(through)
+    }
+
+    // What each open order has reserved, by order id
+    pub fn reservations(&self) -> &HashMap<Uuid, Reservation> {
+        &self.locked_funds
+    }
+
+    // Withdrawals still holding their amount in the user's locked funds
+    pub fn open_withdrawals(&self) -> impl Iterator<Item = &Withdrawal> {
+        self.withdrawals.values().filter(|withdrawal| !withdrawal.status.is_final())
+    }
+}
+
+// A fee moves from what the user received to the fee account; a negative fee is a rebate
+// paid out of it
+fn fee_entry(reference: Reference, user_id: &str, asset: &str, fee: Decimal) -> Option<LedgerEntry> {
+    let user = Account::Available(user_id.to_string());
+    match fee {
+        fee if fee > Decimal::ZERO => Some(LedgerEntry::new(EntryKind::Fee, reference, asset, user, Account::Fees, fee)),
+        fee if fee < Decimal::ZERO => Some(LedgerEntry::new(EntryKind::Fee, reference, asset, Account::Fees, user, -fee)),
+        _ => None,
     }
 }
 
@@ -155,23 +575,81 @@ mod tests {
     
     #[test]
     fn test_balance_management() {
-        let mut bm = BalanceManager::new();
+        let bm = BalanceManager::new();
         
         // Add user with initial balances
         let mut initial = HashMap::new();
         initial.insert("BTC".to_string(), Decimal::from(10));
         initial.insert("USD".to_string(), Decimal::from(50000));
-        bm.add_user("user1".to_string(), initial);
+        bm.add_user("user1".to_string(), initial).unwrap();
         
         // Test locking funds
+        let pair = TradingPair::new("BTC".to_string(), "USD".to_string());
         let order_id = Uuid::new_v4();
-        assert!(bm.lock_funds(order_id, "user1", "USD", Decimal::from(25000)).is_ok());
+        assert!(bm.lock_funds(order_id, &pair, "user1", "USD", Decimal::from(25000)).is_ok());
         
         let balance = bm.get_balance("user1", "USD").unwrap();
         assert_eq!(balance.available, Decimal::from(25000));
         assert_eq!(balance.locked, Decimal::from(25000));
         
         // Test insufficient funds
-        assert!(bm.lock_funds(Uuid::new_v4(), "user1", "USD", Decimal::from(30000)).is_err());
+        assert!(bm.lock_funds(Uuid::new_v4(), &pair, "user1", "USD", Decimal::from(30000)).is_err());
+    }
+
+    #[test]
+    fn test_opening_balances_that_dont_add_up_leave_no_user_behind() {
+        let bm = BalanceManager::new();
+        assert!(bm.add_user("user2".to_string(), HashMap::from([("USD".to_string(), Decimal::from(-1))])).is_err());
+        assert!(bm.get_balance("user2", "USD").is_none());
+    }
+
+    #[test]
+    fn test_trades_post_balanced_entries_to_the_ledger() {
+        use crate::matching_engine::types::{BidOrAsk, Order};
+        use chrono::Utc;
+
+        let bm = BalanceManager::new();
+        bm.add_user("buyer".to_string(), HashMap::from([("USD".to_string(), Decimal::from(1000))])).unwrap();
+        bm.add_user("seller".to_string(), HashMap::from([("BTC".to_string(), Decimal::from(5))])).unwrap();
+
+        let mut buy = Order::new(BidOrAsk::Bid, Decimal::from(2));
+        buy.user_id = "buyer".to_string();
+        let mut sell = Order::new(BidOrAsk::Ask, Decimal::from(2));
+        sell.user_id = "seller".to_string();
+        let pair = TradingPair::new("BTC".to_string(), "USD".to_string());
+        bm.lock_funds(buy.id, &pair, "buyer", "USD", Decimal::from(200)).unwrap();
+        bm.lock_funds(sell.id, &pair, "seller", "BTC", Decimal::from(2)).unwrap();
+
+        let mut trade = Trade::new(pair, &buy, &sell, Decimal::from(100), Decimal::from(2), Uuid::new_v4(), Utc::now());
+        trade.buyer_fee = Decimal::new(2, 2);
+        trade.seller_fee = Decimal::from(-1);
+        bm.settle_trade(&trade).unwrap();
+
+        assert_eq!(bm.get_balance("buyer", "BTC").unwrap().available, Decimal::new(198, 2));
+        assert_eq!(bm.get_balance("seller", "USD").unwrap().available, Decimal::from(201));
+        assert_eq!(bm.fee_balances()["USD"], Decimal::from(-1));
+
+        // Each history only has the entries that touched that user and asset, oldest first
+        let history = bm.ledger_history("seller", "USD");
+        assert_eq!(history.iter().map(|entry| entry.kind).collect::<Vec<_>>(), [EntryKind::Trade, EntryKind::Fee]);
+        assert!(history.iter().all(|entry| entry.reference == Reference::Trade(trade.id)));
+        let history = bm.ledger_history("buyer", "USD");
+        assert_eq!(history.iter().map(|entry| entry.kind).collect::<Vec<_>>(), [EntryKind::Deposit, EntryKind::Lock, EntryKind::Trade]);
+
+        // Every asset nets to zero across all accounts, the external one included
+        let state = bm.snapshot();
+        for asset in ["BTC", "USD"] {
+            let total: Decimal = state.ledger.balances().filter(|(_, a, _)| *a == asset).map(|(_, _, balance)| balance).sum();
+            assert_eq!(total, Decimal::ZERO);
+        }
+
+        // Nothing is left locked, so settling again fails without posting anything
+        let entries = state.ledger.entries().len();
+        assert!(bm.settle_trade(&trade).is_err());
+        assert_eq!(bm.snapshot().ledger.entries().len(), entries);
+
+        // Withdrawals can't overdraw
+        assert!(bm.withdraw(Uuid::new_v4(), "buyer", "USD", Decimal::from(801)).is_err());
+        assert!(bm.withdraw(Uuid::new_v4(), "buyer", "USD", Decimal::from(800)).is_ok());
     }
-}
\ No newline at end of file
+}
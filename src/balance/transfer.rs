@@ -0,0 +1,47 @@
+use rust_decimal::Decimal;
+use uuid::Uuid;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferStatus{
+    Completed,
+    Rejected,
+}
+
+// Funds moved between two accounts on the exchange, e.g. from a master user to one of its
+// sub-accounts or to another user. Only available funds move.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InternalTransfer{
+    pub id: Uuid,
+    // The user asking for the transfer, who owns the source account
+    pub user_id: String,
+    // None for the user's main account
+    pub from_sub_account: Option<String>,
+    pub to_user_id: String,
+    pub to_sub_account: Option<String>,
+    pub asset: String,
+    pub amount: Decimal,
+    pub status: TransferStatus,
+    // Why it was rejected
+    pub failure: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl InternalTransfer{
+    pub fn from_account(&self) -> String{
+        super::account_id(&self.user_id, self.from_sub_account.as_deref())
+    }
+
+    pub fn to_account(&self) -> String{
+        super::account_id(&self.to_user_id, self.to_sub_account.as_deref())
+    }
+
+    pub fn reject(mut self, reason: String) -> InternalTransfer{
+        self.status = TransferStatus::Rejected;
+        self.failure = Some(reason);
+        self
+    }
+}
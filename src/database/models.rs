@@ -14,6 +14,8 @@ pub struct DbTrade {
     pub price: Decimal,
     pub quantity: Decimal,
     pub volume: Decimal,
+    pub buyer_fee: Decimal,
+    pub seller_fee: Decimal,
     pub executed_at: DateTime<Utc>,
 }
 
@@ -1,61 +1,55 @@
-use sqlx::{PgPool, Row};
-use uuid::Uuid;
-use rust_decimal::Decimal;
+use sqlx::PgPool;
 use anyhow::Result;
 use crate::database::models::*;
+use crate::matching_engine::types::Trade;
 
 pub struct TradeQueries;
 
 
 impl TradeQueries{
-    pub async fn save_trade(
-        pool: &PgPool,
-        trade_id: Uuid,
-        buyer_order_id: Uuid,
-        seller_order_id: Uuid,
-        buyer_user_id: Uuid,
-        seller_user_id: Uuid,
-        price: Decimal,
-        quantity: Decimal,
-        volume: Decimal,
-        executed_at: chrono::DateTime<chrono::Utc>,
-    ) -> Result<()>{
+    pub async fn save_trade(pool: &PgPool, trade: &Trade) -> Result<()>{
         let trading_pair_id = sqlx::query_scalar!(
-            "SELECT id FROM trading_pairs WHERE symbol = 'BTC_USD'",
+            "SELECT id FROM trading_pairs WHERE symbol = $1",
+            trade.pair.symbol()
         )
         .fetch_one(pool)
         .await?;
 
-
-        let buyer_order = sqlx::query_as!(
-            "SELECT id FROM orders WHERE username = 'user123' LIMIT 1",
+        let buyer_user_id = sqlx::query_scalar!(
+            "SELECT id FROM users WHERE username = $1",
+            trade.buyer_user_id
         )
         .fetch_one(pool)
         .await?;
-        let seller_order = sqlx::query_scalar!(
-            "SELECT id FROM users WHERE username = 'user456' LIMIT 1"
+
+        let seller_user_id = sqlx::query_scalar!(
+            "SELECT id FROM users WHERE username = $1",
+            trade.seller_user_id
         )
         .fetch_one(pool)
         .await?;
         
-        let volume = price * quantity;
+        let volume = trade.price * trade.quantity;
 
         sqlx::query!(
             r#"
             INSERT INTO trades (id, trading_pair_id, buyer_order_id, seller_order_id,
-                              buyer_user_id, seller_user_id, price, quantity, volume, executed_at)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                              buyer_user_id, seller_user_id, price, quantity, volume,
+                              buyer_fee, seller_fee, executed_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
             "#,
-            trade_id,
+            trade.id,
             trading_pair_id,
-            buyer_order_id,
-            seller_order_id,
+            trade.buyer_order_id,
+            trade.seller_order_id,
             buyer_user_id,
             seller_user_id,
-            price,
-            quantity,
+            trade.price,
+            trade.quantity,
             volume,
-            executed_at
+            trade.buyer_fee,
+            trade.seller_fee,
+            trade.timestamp
         )
         .execute(pool)
         .await?;
@@ -69,7 +63,7 @@ impl TradeQueries{
             r#"
             SELECT t.id, t.trading_pair_id, t.buyer_order_id, t.seller_order_id,
                    t.buyer_user_id, t.seller_user_id, t.price, t.quantity,
-                   t.volume, t.executed_at
+                   t.volume, t.buyer_fee, t.seller_fee, t.executed_at
             FROM trades t
             ORDER BY t.executed_at DESC
             LIMIT $1
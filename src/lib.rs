@@ -0,0 +1,8 @@
+pub mod matching_engine;
+pub mod balance;
+pub mod websocket;
+pub mod redis;
+pub mod api;
+pub mod database;
+pub mod tiers;
+pub mod wallet;
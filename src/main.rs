@@ -1,23 +1,22 @@
-mod matching_engine;
-mod balance;
-mod websocket; 
-mod redis;
-mod api;
-mod database;
-
 use std::thread;
 use std::time::Duration;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use rust_decimal::Decimal;
-use matching_engine::{
-    engine::MatchingEngine,
-    types::{Order, BidOrAsk, TradingPair},
+use cex::matching_engine::{
+    router::Router,
+    spec::MarketSpec,
+    fees::{FeeRates, FeeSchedule},
+    clock::{IdSource, RandomIds, SequentialIds, SystemClock},
+    types::{Order, BidOrAsk, Trade, TradingPair},
     messages::{EngineMessage, EngineResponse, DatabaseMessage}
 };
-use websocket::WebSocketServer; 
-use redis::RedisService;
-use api::ApiService;
-use database::Database;
+use cex::websocket::{self, WebSocketServer};
+use cex::redis::RedisService;
+use cex::api::ApiService;
+use cex::database::Database;
+use cex::tiers::{TierSchedule, TierService};
+use cex::wallet::{AssetConfig, WalletService, chain::MockChain};
+use cex::balance::BalanceManager;
 
 #[tokio::main] 
 async fn main() {
@@ -51,34 +50,110 @@ async fn main() {
         }
     }
     
-    let (mut engine, order_sender, response_receiver, db_receiver, ws_receiver) = MatchingEngine::new();
+    // Trade ids derived from the journal sequence make replays reproduce the same output
+    let deterministic = matches!(std::env::var("ENGINE_DETERMINISTIC").as_deref(), Ok("1") | Ok("true"));
+
+    // Markets are spread over this many engine threads
+    let shard_count = std::env::var("ENGINE_SHARDS")
+        .ok()
+        .and_then(|shards| shards.parse().ok())
+        .unwrap_or(1);
+
+    let (mut engine, order_sender, response_receiver, db_receiver, ws_receiver) = Router::with_sources(shard_count, |shard| {
+        let ids: Box<dyn IdSource> = if deterministic {
+            Box::new(SequentialIds::for_shard(shard as u32))
+        } else {
+            Box::new(RandomIds)
+        };
+        (Box::new(SystemClock), ids)
+    });
     
-    setup_markets_and_users(&mut engine);
+    if let Err(e) = setup_markets_and_users(&mut engine) {
+        println!("Failed to set up markets and users: {}", e);
+        return;
+    }
+
+    let snapshot_dir = std::env::var("SNAPSHOT_DIR")
+        .unwrap_or_else(|_| "snapshots".to_string());
+
+    let snapshot_interval = std::env::var("SNAPSHOT_INTERVAL")
+        .ok()
+        .and_then(|interval| interval.parse().ok())
+        .unwrap_or(10000);
+
+    if let Err(e) = engine.enable_snapshots(&snapshot_dir, Some(snapshot_interval)) {
+        println!("Snapshots disabled: {}", e);
+    }
+
+    let journal_path = std::env::var("JOURNAL_PATH")
+        .unwrap_or_else(|_| "engine.journal".to_string());
+
+    match engine.open_journal(&journal_path) {
+        Ok(recovery) => {
+            for rejected in &recovery.rejected_snapshots {
+                println!("Rejected snapshot {}", rejected);
+            }
+            if let Some(seq) = recovery.snapshot_seq {
+                println!("Loaded snapshot at sequence {}", seq);
+            }
+            if recovery.torn_bytes > 0 {
+                println!("Dropped {} bytes of a half-written entry from {}", recovery.torn_bytes, journal_path);
+            }
+            println!("Replayed {} journal entries from {}", recovery.replayed, journal_path);
+        }
+        Err(e) => {
+            println!("Failed to open journal: {}", e);
+            return;
+        }
+    }
     
     start_websocket_service(ws_receiver, &websocket_host, &websocket_port).await;
     start_redis_service(order_sender.clone(), response_receiver, &redis_url).await;
     start_api_service(&redis_url, &api_host, &api_port).await;
+    let balance_manager = engine.balance_manager().clone();
     start_matching_engine(engine);
-    start_database_worker(db_receiver);
+    start_wallet_service(order_sender.clone(), balance_manager, &redis_url);
+    let trade_sender = start_tier_service(order_sender.clone(), &redis_url);
+    start_database_worker(db_receiver, trade_sender);
     
     send_initial_order(order_sender).await;
     
     tokio::time::sleep(Duration::from_secs(3600)).await;
 }
 
-fn setup_markets_and_users(engine: &mut MatchingEngine) {
+fn setup_markets_and_users(engine: &mut Router) -> Result<(), String> {
     let btc_usd = TradingPair::new("BTC".to_string(), "USD".to_string());
-    engine.add_market(btc_usd);
+    engine.add_market_with_spec(btc_usd, MarketSpec{
+        tick_size: Decimal::new(1, 2),
+        step_size: Decimal::new(1, 5),
+        min_quantity: Decimal::new(1, 5),
+        max_quantity: Some(Decimal::from(1000)),
+        min_notional: Decimal::from(10),
+        base_precision: 8,
+        quote_precision: 2,
+        band_percent: Some(Decimal::from(10)),
+        breaker_percent: Some(Decimal::from(15)),
+        breaker_window_secs: 300,
+        auction_secs: Some(60),
+        fees: FeeSchedule{
+            tiers: BTreeMap::from([
+                (0, FeeRates{ maker: Decimal::new(1, 3), taker: Decimal::new(2, 3) }),
+                (1, FeeRates{ maker: Decimal::new(8, 4), taker: Decimal::new(18, 4) }),
+                (2, FeeRates{ maker: Decimal::new(5, 4), taker: Decimal::new(15, 4) }),
+                (3, FeeRates{ maker: Decimal::new(-1, 4), taker: Decimal::new(1, 3) }),
+            ]),
+        },
+    })?;
     
     let mut user1_balances = HashMap::new();
     user1_balances.insert("BTC".to_string(), Decimal::from(10));
     user1_balances.insert("USD".to_string(), Decimal::from(100000));
-    engine.add_user("user123".to_string(), user1_balances);
+    engine.add_user("user123".to_string(), user1_balances)?;
     
     let mut user2_balances = HashMap::new();
     user2_balances.insert("BTC".to_string(), Decimal::from(5));
     user2_balances.insert("USD".to_string(), Decimal::from(50000));
-    engine.add_user("user456".to_string(), user2_balances);
+    engine.add_user("user456".to_string(), user2_balances)
 }
 
 async fn start_websocket_service(
@@ -123,17 +198,61 @@ async fn start_api_service(redis_url: &str, host: &str, port: &str) {
     });
 }
 
-fn start_matching_engine(engine: MatchingEngine) {
-    let _engine_handle = thread::spawn(move || {
-        let mut engine = engine;
-        engine.run();
-    });
+fn start_matching_engine(engine: Router) {
+    let _engine_handles = engine.start();
+}
+
+// VIP tiers by 30-day volume in USD, recalculated every TIER_RECALC_SECS (default 300)
+fn start_tier_service(order_sender: crossbeam::channel::Sender<EngineMessage>, redis_url: &str) -> crossbeam::channel::Sender<Vec<Trade>> {
+    let schedule = TierSchedule{
+        reference_asset: "USD".to_string(),
+        window_days: 30,
+        thresholds: BTreeMap::from([
+            (1, Decimal::from(100_000)),
+            (2, Decimal::from(1_000_000)),
+            (3, Decimal::from(10_000_000)),
+        ]),
+    };
+
+    let interval = std::env::var("TIER_RECALC_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(300);
+
+    let (trade_sender, trade_receiver) = crossbeam::channel::unbounded();
+    let service = TierService::new(schedule, trade_receiver, order_sender, Duration::from_secs(interval))
+        .with_redis(redis_url)
+        .expect("Failed to create fee tier service");
+    let _tier_handle = service.start();
+    trade_sender
+}
+
+// Deposits and withdrawals against an in-memory chain until real adapters are wired in;
+// the chain is polled every WALLET_POLL_SECS (default 10)
+fn start_wallet_service(order_sender: crossbeam::channel::Sender<EngineMessage>, balance_manager: BalanceManager, redis_url: &str) {
+    let assets = HashMap::from([
+        ("BTC".to_string(), AssetConfig{ confirmations: 3, auto_approve_limit: Some(Decimal::ONE) }),
+        ("USD".to_string(), AssetConfig{ confirmations: 1, auto_approve_limit: Some(Decimal::from(10_000)) }),
+    ]);
+
+    let interval = std::env::var("WALLET_POLL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(10);
+
+    let service = WalletService::new(Box::new(MockChain::new()), assets, balance_manager, order_sender, Duration::from_secs(interval))
+        .with_redis(redis_url)
+        .expect("Failed to create wallet service");
+    let _wallet_handle = service.start();
 }
 
-fn start_database_worker(db_receiver: crossbeam::channel::Receiver<DatabaseMessage>) {
+fn start_database_worker(db_receiver: crossbeam::channel::Receiver<DatabaseMessage>, trade_sender: crossbeam::channel::Sender<Vec<Trade>>) {
     let _db_handle = thread::spawn(move || {
-        while let Ok(_) = db_receiver.recv() {
-            // Process database operations silently
+        while let Ok(message) = db_receiver.recv() {
+            // Executed trades feed the fee tiers; everything else is processed silently
+            if let DatabaseMessage::SaveTrades(trades) = message {
+                let _ = trade_sender.send(trades);
+            }
         }
     });
 }
@@ -144,7 +263,7 @@ async fn send_initial_order(order_sender: crossbeam::channel::Sender<EngineMessa
     
     let _ = order_sender.send(EngineMessage::PlaceOrder {
         pair: btc_usd,
-        price: Decimal::from(50000),
+        price: Some(Decimal::from(50000)),
         order: sell_order,
     });
 }
\ No newline at end of file
@@ -0,0 +1,128 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use crate::matching_engine::types::Limit;
+
+
+// What a call auction would do if it uncrossed at `price`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AuctionPrice{
+    pub price: Decimal,
+    // Quantity that trades
+    pub volume: Decimal,
+    // Buy quantity minus sell quantity willing to trade at the price; what's left unfilled
+    pub surplus: Decimal,
+}
+
+// The single price a crossed book uncrosses at. Every bid and ask price is a candidate;
+// the winner is the one that
+//   1. executes the most volume,
+//   2. then leaves the smallest surplus,
+//   3. then, when the surplus is on one side at every remaining price, the highest price
+//      for buy pressure and the lowest for sell pressure,
+//   4. then is closest to the reference price, the lowest one without a reference.
+// Hidden iceberg quantity takes part in full. None when nothing crosses.
+pub fn equilibrium_price(
+    bids: &BTreeMap<Decimal, Limit>,
+    asks: &BTreeMap<Decimal, Limit>,
+    reference_price: Option<Decimal>,
+) -> Option<AuctionPrice>{
+    let candidates: BTreeSet<Decimal> = bids.keys().chain(asks.keys()).copied().collect();
+
+    // Best candidates so far, lowest price first
+    let mut best: Vec<AuctionPrice> = Vec::new();
+
+    for price in candidates {
+        let demand: Decimal = bids.range(price..).map(|(_, limit)| limit.total_size()).sum();
+        let supply: Decimal = asks.range(..=price).map(|(_, limit)| limit.total_size()).sum();
+        let volume = demand.min(supply);
+        if volume <= Decimal::ZERO {
+            continue;
+        }
+
+        let candidate = AuctionPrice{ price, volume, surplus: demand - supply };
+        let ranking = match best.first() {
+            Some(leader) => volume.cmp(&leader.volume).then(leader.surplus.abs().cmp(&candidate.surplus.abs())),
+            None => Ordering::Greater,
+        };
+
+        match ranking {
+            Ordering::Greater => best = vec![candidate],
+            Ordering::Equal => best.push(candidate),
+            Ordering::Less => {}
+        }
+    }
+
+    if best.iter().all(|candidate| candidate.surplus > Decimal::ZERO) {
+        return best.last().copied();
+    }
+    if best.iter().all(|candidate| candidate.surplus < Decimal::ZERO) {
+        return best.first().copied();
+    }
+
+    match reference_price {
+        Some(reference_price) => best.into_iter().min_by_key(|candidate| (candidate.price - reference_price).abs()),
+        None => best.first().copied(),
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matching_engine::types::{BidOrAsk, Order};
+
+    fn side(levels: &[(i64, i64)], bid_or_ask: BidOrAsk) -> BTreeMap<Decimal, Limit> {
+        levels.iter().map(|(price, size)| {
+            let mut limit = Limit::new(Decimal::from(*price));
+            limit.add_order(Order::new(bid_or_ask, Decimal::from(*size)));
+            (Decimal::from(*price), limit)
+        }).collect()
+    }
+
+    fn auction(bids: &[(i64, i64)], asks: &[(i64, i64)], reference_price: Option<i64>) -> Option<(i64, i64, i64)> {
+        let bids = side(bids, BidOrAsk::Bid);
+        let asks = side(asks, BidOrAsk::Ask);
+        equilibrium_price(&bids, &asks, reference_price.map(Decimal::from)).map(|auction| (
+            i64::try_from(auction.price).unwrap(),
+            i64::try_from(auction.volume).unwrap(),
+            i64::try_from(auction.surplus).unwrap(),
+        ))
+    }
+
+    #[test]
+    fn test_equilibrium_price_maximises_volume_then_applies_tie_breaks() {
+        // price  demand  supply  volume
+        //   99     45      12      12
+        //  100     45      22      22
+        //  101     25      40      25
+        //  102     10      40      10
+        //  103      0      45       0
+        let bids = [(102, 10), (101, 15), (100, 20)];
+        let asks = [(99, 12), (100, 10), (101, 18), (103, 5)];
+        assert_eq!(auction(&bids, &asks, None), Some((101, 25, -15)));
+
+        // 10 trades at 100 and at 101 with nothing left over: the reference decides
+        let bids = [(101, 10)];
+        let asks = [(100, 10)];
+        assert_eq!(auction(&bids, &asks, Some(105)), Some((101, 10, 0)));
+        assert_eq!(auction(&bids, &asks, Some(99)), Some((100, 10, 0)));
+        assert_eq!(auction(&bids, &asks, None), Some((100, 10, 0)));
+
+        // Same volume and surplus at 100 and 102: buyers are left over, so the higher price
+        assert_eq!(auction(&[(102, 20)], &[(100, 10)], Some(100)), Some((102, 10, 10)));
+        // and sellers left over push it down
+        assert_eq!(auction(&[(102, 10)], &[(100, 20)], Some(102)), Some((100, 10, -10)));
+
+        // Volume 15 at 101 beats 10 at 100, even with a larger surplus
+        //   100: demand 20, supply 10 -> 10, surplus 10
+        //   101: demand 20, supply 15 -> 15, surplus 5
+        assert_eq!(auction(&[(101, 20)], &[(100, 10), (101, 5)], None), Some((101, 15, 5)));
+
+        // A book that doesn't cross has no auction price
+        assert_eq!(auction(&[(99, 10)], &[(100, 10)], Some(100)), None);
+        assert_eq!(auction(&[], &[(100, 10)], None), None);
+    }
+}
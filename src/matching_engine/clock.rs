@@ -0,0 +1,92 @@
+use chrono::{DateTime, Duration, Utc};
+use uuid::Uuid;
+
+
+// Where the engine gets the time from. It is read once per accepted message and
+// journaled with it, so replays see the same time as the original run.
+pub trait Clock: Send{
+    fn now(&mut self) -> DateTime<Utc>;
+}
+
+// Where the engine gets ids for the things it creates, like trades
+pub trait IdSource: Send{
+    // Called before each message with its input sequence number
+    fn begin_message(&mut self, seq: u64);
+    fn next_id(&mut self) -> Uuid;
+}
+
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock{
+    fn now(&mut self) -> DateTime<Utc>{
+        Utc::now()
+    }
+}
+
+// Starts at a fixed time and moves forward by `step` on every reading
+#[derive(Debug, Clone, Copy)]
+pub struct SimulatedClock{
+    next: DateTime<Utc>,
+    step: Duration,
+}
+
+impl SimulatedClock{
+    pub fn new(start: DateTime<Utc>, step: Duration) -> SimulatedClock{
+        SimulatedClock{ next: start, step }
+    }
+}
+
+impl Clock for SimulatedClock{
+    fn now(&mut self) -> DateTime<Utc>{
+        let now = self.next;
+        self.next += self.step;
+        now
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomIds;
+
+impl IdSource for RandomIds{
+    fn begin_message(&mut self, _seq: u64){}
+
+    fn next_id(&mut self) -> Uuid{
+        Uuid::new_v4()
+    }
+}
+
+// The n-th id created while processing input `seq` is built from (seq, n),
+// so the same input always gets the same ids. Shards put their index in the
+// top half of n so their ids never collide.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SequentialIds{
+    shard: u32,
+    seq: u64,
+    counter: u64,
+}
+
+impl SequentialIds{
+    pub fn new() -> SequentialIds{
+        SequentialIds::default()
+    }
+
+    pub fn for_shard(shard: u32) -> SequentialIds{
+        SequentialIds{ shard, ..SequentialIds::default() }
+    }
+}
+
+impl IdSource for SequentialIds{
+    fn begin_message(&mut self, seq: u64){
+        self.seq = seq;
+        self.counter = 0;
+    }
+
+    fn next_id(&mut self) -> Uuid{
+        let id = Uuid::from_u64_pair(self.seq, ((self.shard as u64) << 32) | self.counter);
+        self.counter += 1;
+        id
+    }
+}
@@ -1,23 +1,53 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
 use crossbeam::channel::{Receiver, Sender, unbounded};
 use rust_decimal::Decimal;
+use uuid::Uuid;
+use chrono::{DateTime, Duration, Utc};
 use crate::balance::BalanceManager;
-use crate::websocket::events::MarketDataEvent;
+use crate::balance::transfer::{InternalTransfer, TransferStatus};
+use crate::websocket::events::{MarketDataEvent, OrderStatus};
+use crate::wallet::{Deposit, Withdrawal, WithdrawalStatus};
+
+// Price levels per side in depth updates
+const DEPTH_LEVELS: usize = 20;
 
 use crate::matching_engine::{
-    orderbook::OrderBook,
-    types::{TradingPair, Order},
+    invariants::{self, Violation},
+    orderbook::{OrderBook, MatchResult, MatchContext, Settlement},
+    clock::{Clock, IdSource, SystemClock, RandomIds},
+    stops::{StopBook, TriggerDirection},
+    journal::{Journal, JournalEntry, Recovery, Sequencer},
+    snapshot::{self, EngineSnapshot},
+    spec::{MarketSpec, RejectReason},
+    types::{TradingPair, Order, Trade, OrderType, TimeInForce, BidOrAsk, SelfTradePrevention, MarketStatus},
     messages::{EngineMessage, EngineResponse, DatabaseMessage}
 };
 
 
 pub struct MatchingEngine{
     pub orderbooks: HashMap<TradingPair, OrderBook>,
+    pub stop_books: HashMap<TradingPair, StopBook>,
     pub balance_manager: BalanceManager,
     pub message_receiver: Receiver<EngineMessage>,
     pub message_sender: Sender<EngineResponse>,
     pub database_sender: Sender<DatabaseMessage>,
     pub event_broadcaster: Sender<MarketDataEvent>,
+    // Fee tier of every user that isn't on tier 0
+    fee_tiers: HashMap<String, u32>,
+    journal: Option<Journal>,
+    sequencer: Sequencer,
+    // Sequence number of the last journal entry applied to the state
+    last_seq: u64,
+    snapshot_dir: Option<PathBuf>,
+    // Take a snapshot every this many messages
+    snapshot_interval: Option<u64>,
+    // Set while the journal is replayed; the outside world already saw these results
+    replaying: bool,
+    clock: Box<dyn Clock>,
+    ids: Box<dyn IdSource>,
+    // When the message being processed was accepted, used for everything it produces
+    now: DateTime<Utc>,
 }
 
 impl MatchingEngine {
@@ -27,87 +57,1793 @@ impl MatchingEngine {
         Receiver<EngineResponse>, 
         Receiver<DatabaseMessage>,
         Receiver<MarketDataEvent>
+    ){
+        MatchingEngine::with_sources(Box::new(SystemClock), Box::new(RandomIds))
+    }
+
+    // A simulated clock with sequential ids makes the output a pure function of the input
+    pub fn with_sources(clock: Box<dyn Clock>, ids: Box<dyn IdSource>) -> (
+        Self,
+        Sender<EngineMessage>,
+        Receiver<EngineResponse>,
+        Receiver<DatabaseMessage>,
+        Receiver<MarketDataEvent>
     ){
         let (msg_tx, msg_rx) = unbounded();
         let (resp_tx, resp_rx) = unbounded();
         let (db_tx, db_rx) = unbounded();
         let (ws_tx, ws_rx) = unbounded();
 
+        let engine = MatchingEngine::from_parts(clock, ids, BalanceManager::new(), msg_rx, resp_tx, db_tx, ws_tx);
+
+        (engine, msg_tx, resp_rx, db_rx, ws_rx)
+    }
 
-        let engine = Self{
+    // An engine wired to channels and balances shared with other engines, as the shards of a router are
+    pub fn from_parts(
+        clock: Box<dyn Clock>,
+        ids: Box<dyn IdSource>,
+        balance_manager: BalanceManager,
+        message_receiver: Receiver<EngineMessage>,
+        message_sender: Sender<EngineResponse>,
+        database_sender: Sender<DatabaseMessage>,
+        event_broadcaster: Sender<MarketDataEvent>,
+    ) -> Self {
+        Self{
             orderbooks: HashMap::new(),
-            balance_manager: BalanceManager::new(),
-            message_receiver: msg_rx,
-            message_sender: resp_tx,
-            database_sender: db_tx,
-            event_broadcaster: ws_tx,
-        };
+            stop_books: HashMap::new(),
+            balance_manager,
+            message_receiver,
+            message_sender,
+            database_sender,
+            event_broadcaster,
+            fee_tiers: HashMap::new(),
+            journal: None,
+            sequencer: Sequencer::new(),
+            last_seq: 0,
+            snapshot_dir: None,
+            snapshot_interval: None,
+            replaying: false,
+            clock,
+            ids,
+            now: DateTime::<Utc>::MIN_UTC,
+        }
+    }
 
-        (engine, msg_tx, resp_rx, db_rx, ws_rx)
+    // Number messages in one sequence with the other engines holding `sequencer`
+    pub fn share_sequencer(&mut self, sequencer: Sequencer) {
+        self.sequencer = sequencer;
     }
 
     pub fn add_market(&mut self, pair: TradingPair) {
-        self.orderbooks.insert(pair, OrderBook::new());
+        self.add_market_with_spec(pair, MarketSpec::default()).expect("the default spec is valid");
+    }
+
+    pub fn add_market_with_spec(&mut self, pair: TradingPair, spec: MarketSpec) -> Result<(), String> {
+        spec.fees.validate()?;
+        self.insert_market(pair, spec);
+        Ok(())
+    }
+
+    fn insert_market(&mut self, pair: TradingPair, spec: MarketSpec) {
+        let mut orderbook = OrderBook::new(pair.clone());
+        orderbook.spec = spec;
+        self.stop_books.insert(pair.clone(), StopBook::new());
+        self.orderbooks.insert(pair, orderbook);
+    }
+
+    // Write snapshots to `dir`, every `interval` messages and on TakeSnapshot.
+    // Call before `open_journal` so restarts start from the latest snapshot.
+    pub fn enable_snapshots(&mut self, dir: &str, interval: Option<u64>) {
+        self.snapshot_dir = Some(PathBuf::from(dir));
+        self.snapshot_interval = interval.filter(|interval| *interval > 0);
+    }
+
+    // Rebuild state from the latest snapshot plus the journal entries after it, then journal
+    // every message `run` accepts from here on. Markets and users have to be set up the same
+    // way as before the restart.
+    pub fn open_journal(&mut self, path: &str) -> Result<Recovery, String> {
+        let (mut recovery, entries) = self.load_journal(path)?;
+        recovery.replayed = entries.len();
+        for entry in entries {
+            self.replay(entry);
+        }
+        Ok(recovery)
+    }
+
+    // Restore the latest snapshot and journal to `path` from here on. Returns the entries
+    // after the snapshot, which still have to be replayed in order.
+    pub fn load_journal(&mut self, path: &str) -> Result<(Recovery, Vec<JournalEntry>), String> {
+        let (journal, entries) = Journal::open(path)?;
+        let mut recovery = Recovery{ torn_bytes: journal.torn_bytes(), ..Recovery::default() };
+
+        if let Some(dir) = self.snapshot_dir.as_deref() {
+            let (snapshot, rejected) = EngineSnapshot::load_latest(dir);
+            recovery.rejected_snapshots = rejected;
+            if let Some(snapshot) = snapshot {
+                recovery.snapshot_seq = Some(snapshot.seq);
+                self.restore_snapshot(snapshot);
+            }
+        }
+
+        self.sequencer.advance_to(journal.last_seq().max(self.last_seq));
+        self.journal = Some(journal);
+
+        let snapshot_seq = self.last_seq;
+        Ok((recovery, entries.into_iter().filter(|entry| entry.seq > snapshot_seq).collect()))
+    }
+
+    // Apply a journal entry again, without telling the outside world
+    pub fn replay(&mut self, entry: JournalEntry) {
+        self.replaying = true;
+        self.apply_message(entry.seq, entry.timestamp, entry.message);
+        self.replaying = false;
+    }
+
+    fn restore_snapshot(&mut self, snapshot: EngineSnapshot) {
+        for book in snapshot.books {
+            let pair = book.pair.clone();
+            self.orderbooks.insert(pair, book.restore());
+        }
+
+        for stops in snapshot.stop_books {
+            let mut stop_book = StopBook::new();
+            for stop in stops.stops {
+                stop_book.add(stop.order, stop.trigger_price, stop.limit_price, stop.direction);
+            }
+            self.stop_books.insert(stops.pair, stop_book);
+        }
+
+        self.balance_manager.restore(snapshot.balances);
+        self.fee_tiers = snapshot.fee_tiers;
+        self.last_seq = snapshot.seq;
+    }
+
+    pub fn take_snapshot(&self) -> Result<PathBuf, String> {
+        let dir = self.snapshot_dir.as_deref().ok_or("Snapshots are not enabled")?;
+        let mut snapshot = EngineSnapshot::capture(self.last_seq, &self.orderbooks, &self.stop_books, &self.balance_manager, &self.fee_tiers);
+
+        // The snapshot only carries the ledger entries since its checkpoint; the older ones
+        // are archived first and dropped from memory once the snapshot is on disk
+        let archive = snapshot.balances.compact_ledger(u64::MAX);
+        snapshot::archive_ledger(dir, &archive)?;
+        let path = snapshot.write(dir)?;
+        self.balance_manager.compact_ledger(snapshot.balances.ledger().archived());
+        Ok(path)
+    }
+
+    fn handle_take_snapshot(&self) {
+        match self.take_snapshot() {
+            Ok(path) => self.respond(EngineResponse::SnapshotTaken{ seq: self.last_seq, path: path.display().to_string() }),
+            Err(message) => self.send_error(message),
+        }
     }
 
     pub fn run(mut self){
+        // Tell the outside world where every market stands after a (re)start
+        for orderbook in self.orderbooks.values() {
+            self.respond(EngineResponse::MarketSpecChanged{ pair: orderbook.pair.clone(), spec: orderbook.spec.clone() });
+            self.respond(EngineResponse::MarketStatusChanged{ pair: orderbook.pair.clone(), status: orderbook.status });
+            self.publish_indicative(&orderbook.pair);
+        }
+
         while let Ok(msg) = self.message_receiver.recv(){
-            match msg{
-                EngineMessage::PlaceOrder{pair, order, price} => {
-                    self.handle_place_order(pair, order, price);
-                },
+            self.accept_message(msg);
+        }
+    }
 
-                EngineMessage::CancelOrder{order_id} => {
-                    self.handle_cancel_order(order_id);
-                }
+    // A message only counts as accepted once it is on disk. A message the journal refuses
+    // leaves its sequence number unused.
+    fn accept_message(&mut self, msg: EngineMessage){
+        if let EngineMessage::TakeSnapshot = msg {
+            self.handle_take_snapshot();
+            return;
+        }
+
+        let seq = self.sequencer.next();
+        let now = self.clock.now();
+        if let Some(Err(message)) = self.journal.as_mut().map(|journal| journal.append(seq, &msg, now)) {
+            self.send_error(message);
+            return;
+        }
+
+        self.apply_message(seq, now, msg);
+
+        if self.snapshot_interval.is_some_and(|interval| self.last_seq.is_multiple_of(interval)) {
+            self.handle_take_snapshot();
+        }
+    }
+
+    // Auctions that ran out by the time a message arrives uncross before it is handled,
+    // so a replay of the journal uncrosses at the same point
+    fn apply_message(&mut self, seq: u64, now: DateTime<Utc>, msg: EngineMessage){
+        let checked = matches!(msg, EngineMessage::CheckInvariants);
+        self.begin_message(seq, now);
+        self.uncross_due_auctions();
+        self.process_message(msg);
+
+        if cfg!(debug_assertions) && !checked {
+            self.enforce_invariants(false);
+        }
+    }
+
+    fn begin_message(&mut self, seq: u64, now: DateTime<Utc>){
+        self.last_seq = seq;
+        self.now = now;
+        self.ids.begin_message(seq);
+    }
+
+    fn process_message(&mut self, msg: EngineMessage){
+        match msg{
+            EngineMessage::PlaceOrder{pair, order, price} => {
+                self.handle_place_order(pair, order, price);
+            },
+
+            EngineMessage::PlaceStopOrder{pair, order, trigger_price, limit_price} => {
+                self.handle_place_stop_order(pair, order, trigger_price, limit_price);
+            },
+
+            EngineMessage::CancelOrder{pair, order_id} => {
+                self.handle_cancel_order(pair, order_id);
+            },
+
+            EngineMessage::AmendOrder{pair, order_id, user_id, price, size} => {
+                self.handle_amend_order(pair, order_id, user_id, price, size);
+            },
+
+            EngineMessage::TakeSnapshot => {
+                self.handle_take_snapshot();
+            },
+
+            EngineMessage::ListMarket{pair, spec} => {
+                self.handle_list_market(pair, spec);
+            },
+
+            EngineMessage::SetMarketStatus{pair, status} => {
+                self.handle_set_market_status(pair, status);
+            },
+
+            EngineMessage::SetReferencePrice{pair, price} => {
+                self.handle_set_reference_price(pair, price);
+            },
+
+            EngineMessage::Uncross{pair} => {
+                self.handle_uncross(pair);
+            },
+
+            EngineMessage::SetFeeTier{user_id, tier} => {
+                self.set_fee_tier(user_id, tier);
+            },
+
+            EngineMessage::CreateSubAccount{user_id, name} => {
+                self.handle_create_sub_account(user_id, name);
+            },
+
+            EngineMessage::Transfer{transfer_id, user_id, from_sub_account, to_user_id, to_sub_account, asset, amount} => {
+                self.handle_transfer(InternalTransfer{
+                    id: transfer_id,
+                    user_id,
+                    from_sub_account,
+                    to_user_id,
+                    to_sub_account,
+                    asset,
+                    amount,
+                    status: TransferStatus::Completed,
+                    failure: None,
+                    timestamp: self.now,
+                });
+            },
+
+            EngineMessage::CreditDeposit{deposit} => {
+                self.handle_credit_deposit(deposit);
+            },
+
+            EngineMessage::RequestWithdrawal{withdrawal_id, user_id, asset, amount, address} => {
+                self.handle_request_withdrawal(Withdrawal::new(withdrawal_id, user_id, asset, amount, address, self.now));
+            },
+
+            EngineMessage::ApproveWithdrawal{withdrawal_id} => {
+                let result = self.balance_manager.approve_withdrawal(withdrawal_id, self.now);
+                self.send_withdrawal(result);
+            },
+
+            EngineMessage::WithdrawalBroadcast{withdrawal_id, tx_hash} => {
+                let result = self.balance_manager.broadcast_withdrawal(withdrawal_id, tx_hash, self.now);
+                self.send_withdrawal(result);
+            },
+
+            EngineMessage::ConfirmWithdrawal{withdrawal_id, confirmations} => {
+                let result = self.balance_manager.confirm_withdrawal(withdrawal_id, confirmations, self.now);
+                self.send_withdrawal(result);
+            },
+
+            EngineMessage::FailWithdrawal{withdrawal_id, reason} => {
+                let result = self.balance_manager.fail_withdrawal(withdrawal_id, reason, self.now);
+                self.send_withdrawal(result);
+            },
+
+            EngineMessage::CheckInvariants => {
+                self.enforce_invariants(true);
             }
         }
     }
 
-    fn handle_place_order(&mut self, pair: TradingPair, order: Order, price: Decimal){
+    fn respond(&self, response: EngineResponse) {
+        if !self.replaying {
+            let _ = self.message_sender.send(response);
+        }
+    }
+
+    fn broadcast(&self, event: MarketDataEvent) {
+        if !self.replaying {
+            let _ = self.event_broadcaster.send(event);
+        }
+    }
+
+    fn persist(&self, message: DatabaseMessage) {
+        if !self.replaying {
+            let _ = self.database_sender.send(message);
+        }
+    }
+
+    fn handle_place_order(&mut self, pair: TradingPair, order: Order, price: Option<Decimal>){
         let order_id = order.id;
 
-        if let Some(orderbook) = self.orderbooks.get_mut(&pair){
-            let trades = orderbook.add_order(price, order.clone());
+        if let Err(reason) = self.check_spec(&pair, &order, price, None).and(self.check_band(&pair, price)) {
+            self.reject(order_id, reason);
+            return;
+        }
+
+        match self.place_order(&pair, order, price) {
+            Ok(result) => self.send_placed(order_id, &result),
+            Err(message) => self.send_error(message),
+        }
+
+        self.process_triggers(&pair);
+    }
+
+    // Lock funds, match and settle one order. Stops that trigger are placed through here too.
+    fn place_order(&mut self, pair: &TradingPair, mut order: Order, price: Option<Decimal>) -> Result<MatchResult, String> {
+        let order_id = order.id;
+
+        Self::validate_order(&order, price)?;
+
+        let Some(orderbook) = self.orderbooks.get_mut(pair) else {
+            return Err(format!("Market not found for pair: {:?}", pair));
+        };
+
+        if !orderbook.status.accepts_orders() {
+            return Err(Self::market_closed(pair, orderbook.status));
+        }
+
+        // Nothing matches before the uncross, so only orders that rest make sense
+        let in_auction = orderbook.status == MarketStatus::Auction;
+        if in_auction && (order.order_type == OrderType::Market || !order.time_in_force.rests()) {
+            return Err(format!("Market {} is in a call auction and only takes resting limit orders", pair.symbol()));
+        }
+
+        if !in_auction && order.time_in_force == TimeInForce::PostOnly && price.is_some_and(|price| orderbook.would_cross(order.bid_or_ask, price)) {
+            return Err(format!("Post-only order {} would take liquidity", order_id));
+        }
 
-            for trade in &trades {
-                let trade_event = MarketDataEvent::from_trade(trade, &pair);
-                let _ = self.event_broadcaster.send(trade_event);
+        // Reserve the funds the order can spend before it touches the book. A triggered stop
+        // keeps what it reserved when it was placed and only tops it up or gives back the rest.
+        let (asset, amount) = Self::required_funds(orderbook, pair, &order, price);
+        match self.balance_manager.reserved(order_id) {
+            None => self.balance_manager.lock_funds(order_id, pair, &order.account(), &asset, amount)?,
+            Some(reserved) => if let Err(message) = self.balance_manager.adjust_lock(order_id, amount) {
+                // A stop-market buy whose sweep costs more than the user can add buys as many
+                // steps as its reservation pays for
+                if order.bid_or_ask != BidOrAsk::Bid || price.is_some() || order.quote_size.is_some() {
+                    return Err(message);
+                }
+                let (size, cost) = orderbook.market_sweep(&Order{ quote_size: Some(reserved), ..order.clone() });
+                if size.is_zero() {
+                    return Err(message);
+                }
+                order.size = size;
+                self.balance_manager.adjust_lock(order_id, cost)?;
             }
+        }
+
+        // Market orders stop sweeping at the edge of the price band
+        let worst_price = orderbook.guard.worst_price(&orderbook.spec, order.bid_or_ask);
+
+        let mut settlement = TradeSettlement::new(&self.balance_manager, &self.fee_tiers, &orderbook.spec);
+        let mut ctx = MatchContext{ timestamp: self.now, ids: self.ids.as_mut(), settlement: &mut settlement };
+        let mut result = match (order.order_type, price) {
+            (OrderType::Limit, Some(price)) => orderbook.add_order_with(&mut ctx, price, order),
+            _ => orderbook.add_capped_market_order_with(&mut ctx, order, worst_price),
+        };
+
+        self.complete_match(pair, &mut result, price);
+        Ok(result)
+    }
 
-            let response = EngineResponse::OrderPlaced{
-                order_id,
-                trades: trades.clone(),
+    fn send_placed(&self, order_id: Uuid, result: &MatchResult) {
+        let response = EngineResponse::OrderPlaced{
+            order_id,
+            trades: result.trades.clone(),
+            status: Self::final_status(result),
+            filled_size: result.order.filled_size,
+            remaining_size: result.order.size,
+            self_trade_prevented: result.prevented.clone(),
+        };
+
+        self.respond(response);
+    }
+
+    // Stops wait in the market's stop book with their funds locked until the
+    // last trade price reaches the trigger
+    fn handle_place_stop_order(&mut self, pair: TradingPair, mut order: Order, trigger_price: Decimal, limit_price: Option<Decimal>){
+        let order_id = order.id;
+        order.order_type = if limit_price.is_some() { OrderType::Limit } else { OrderType::Market };
+
+        if let Err(message) = Self::validate_order(&order, limit_price) {
+            self.send_error(message);
+            return;
+        }
+
+        if trigger_price <= Decimal::ZERO {
+            self.send_error(format!("Invalid trigger price {}", trigger_price));
+            return;
+        }
+
+        if order.quote_size.is_some() {
+            self.send_error("Quote quantity is not supported for stop orders".to_string());
+            return;
+        }
+
+        // Stop-market orders are held to the notional at their trigger
+        if let Err(reason) = self.check_spec(&pair, &order, limit_price, Some(trigger_price)) {
+            self.reject(order_id, reason);
+            return;
+        }
+
+        let Some(orderbook) = self.orderbooks.get(&pair) else {
+            self.send_error(format!("Market not found for pair: {:?}", pair));
+            return;
+        };
+
+        if !orderbook.status.accepts_orders() {
+            self.send_error(Self::market_closed(&pair, orderbook.status));
+            return;
+        }
+
+        // Relative to the current price a stop is either waiting for the price to rise
+        // or to fall. Without any trades yet, buys stop above and sells stop below.
+        let direction = match (orderbook.last_trade_price, order.bid_or_ask) {
+            (Some(last_price), _) if trigger_price >= last_price => TriggerDirection::Above,
+            (Some(_), _) => TriggerDirection::Below,
+            (None, BidOrAsk::Bid) => TriggerDirection::Above,
+            (None, BidOrAsk::Ask) => TriggerDirection::Below,
+        };
+
+        // Stop-market buys don't know their fill price yet, hold the trigger price for now
+        let (asset, amount) = match order.bid_or_ask {
+            BidOrAsk::Bid => (pair.quote.clone(), limit_price.unwrap_or(trigger_price) * order.size),
+            BidOrAsk::Ask => (pair.base.clone(), order.size),
+        };
+
+        if let Err(message) = self.balance_manager.lock_funds(order_id, &pair, &order.account(), &asset, amount) {
+            self.send_error(message);
+            return;
+        }
+
+        self.broadcast(MarketDataEvent::order_update(&order, OrderStatus::Placed, None, self.now));
+        self.persist(DatabaseMessage::SaveOrder(order.clone()));
+
+        let response = EngineResponse::OrderPlaced{
+            order_id,
+            trades: Vec::new(),
+            status: OrderStatus::Placed,
+            filled_size: Decimal::ZERO,
+            remaining_size: order.size,
+            self_trade_prevented: Vec::new(),
+        };
+        self.respond(response);
+
+        self.stop_books.entry(pair.clone()).or_default().add(order, trigger_price, limit_price, direction);
+
+        // A trigger at the current price fires straight away
+        self.process_triggers(&pair);
+    }
+
+    // Place every stop the last trade price has reached, one at a time, so fills
+    // from one triggered stop can trigger the next
+    fn process_triggers(&mut self, pair: &TradingPair) {
+        loop {
+            // Stops keep waiting while the market is closed or in a call auction
+            let Some(last_price) = self.orderbooks.get(pair)
+                .filter(|orderbook| orderbook.status == MarketStatus::Active)
+                .and_then(|orderbook| orderbook.last_trade_price) else {
+                return;
             };
 
-            let _ = self.message_sender.send(response);
+            let Some(stop) = self.stop_books.get_mut(pair).and_then(|stop_book| stop_book.pop_triggered(last_price)) else {
+                return;
+            };
+
+            let order = stop.order;
+            let order_id = order.id;
+
+            // The stop's reservation carries over to the live order
+            self.broadcast(MarketDataEvent::order_update(&order, OrderStatus::Triggered, None, self.now));
+
+            let placed = self.check_band(pair, stop.limit_price)
+                .map_err(|reason| reason.message())
+                .and_then(|_| self.place_order(pair, order.clone(), stop.limit_price));
+
+            match placed {
+                Ok(result) => self.send_placed(order_id, &result),
+                Err(message) => {
+                    let _ = self.balance_manager.unlock_funds(order_id);
+                    self.broadcast(MarketDataEvent::order_update(&order, OrderStatus::Cancelled, Some(message.clone()), self.now));
+                    self.persist(DatabaseMessage::UpdateOrderStatus{
+                        order_id,
+                        status: OrderStatus::Cancelled,
+                        filled_size: order.filled_size,
+                        remaining_size: order.size,
+                    });
+                    self.send_error(message);
+                }
+            }
+        }
+    }
+
+    // Lower the size in place and keep queue priority, or re-queue at the back
+    // of the book when the price changes or the size goes up
+    fn handle_amend_order(&mut self, pair: TradingPair, order_id: Uuid, user_id: String, new_price: Option<Decimal>, new_size: Option<Decimal>){
+        let Some((orderbook, location)) = self.orderbooks.get_mut(&pair)
+            .and_then(|orderbook| orderbook.locate(order_id).map(|location| (orderbook, location))) else {
+            self.send_error(format!("Order {} not found or already filled", order_id));
+            return;
+        };
+
+        let current = orderbook.get_order(order_id).expect("located order is resting").clone();
+
+        if !orderbook.status.accepts_orders() {
+            let message = Self::market_closed(&pair, orderbook.status);
+            self.send_error(message);
+            return;
+        }
+
+        if current.user_id != user_id {
+            self.send_error(format!("Order {} does not belong to user {}", order_id, user_id));
+            return;
+        }
+
+        let price = new_price.unwrap_or(location.price);
+        let size = new_size.unwrap_or(current.size);
+
+        if price <= Decimal::ZERO || size <= Decimal::ZERO {
+            self.send_error(format!("Invalid amend for order {}: price {} size {}", order_id, price, size));
+            return;
+        }
+
+        let spec = &orderbook.spec;
+        let checked = spec.check_price(price).and(spec.check_quantity(size)).and(spec.check_notional(price * size))
+            .and(if price != location.price { orderbook.guard.check_price(spec, price) } else { Ok(()) });
+        if let Err(reason) = checked {
+            self.reject(order_id, reason);
+            return;
+        }
+
+        if current.time_in_force == TimeInForce::PostOnly && price != location.price && orderbook.status != MarketStatus::Auction
+            && orderbook.would_cross(current.bid_or_ask, price) {
+            self.send_error(format!("Post-only order {} would take liquidity", order_id));
+            return;
+        }
+
+        let required = match current.bid_or_ask {
+            BidOrAsk::Bid => price * size,
+            BidOrAsk::Ask => size,
+        };
+
+        if let Err(message) = self.balance_manager.adjust_lock(order_id, required) {
+            self.send_error(message);
+            return;
+        }
+
+        let mut result = if price == location.price && size <= current.size {
+            orderbook.reduce_order(order_id, size);
+
+            let mut order = current;
+            order.shrink_to(size);
+            MatchResult{ rested: true, ..MatchResult::untouched(order) }
+        } else {
+            let mut order = orderbook.cancel_order(order_id).expect("located order is resting");
+            order.size = size;
+            let mut settlement = TradeSettlement::new(&self.balance_manager, &self.fee_tiers, &orderbook.spec);
+            orderbook.add_order_with(&mut MatchContext{ timestamp: self.now, ids: self.ids.as_mut(), settlement: &mut settlement }, price, order)
+        };
+
+        self.complete_match(&pair, &mut result, Some(price));
+
+        let response = EngineResponse::OrderAmended{
+            order_id,
+            trades: result.trades.clone(),
+            status: Self::final_status(&result),
+            filled_size: result.order.filled_size,
+            remaining_size: result.order.size,
+            self_trade_prevented: result.prevented.clone(),
+        };
+
+        self.respond(response);
+        self.process_triggers(&pair);
+    }
+
+    // Release funds of orders that are done and publish the trades of a match, which the book
+    // settled as it made them. A trade that couldn't be settled halts the market.
+    fn complete_match(&mut self, pair: &TradingPair, result: &mut MatchResult, limit_price: Option<Decimal>) {
+        let filled_makers: Vec<Uuid> = match self.orderbooks.get(pair) {
+            Some(orderbook) => result.trades.iter()
+                .map(|trade| trade.maker_order_id)
+                .filter(|maker_id| !orderbook.contains(*maker_id))
+                .collect(),
+            None => Vec::new(),
+        };
 
-            if !trades.is_empty(){
-                let _ = self.database_sender.send(DatabaseMessage::SaveTrades(trades.clone()));
-                let _ = self.database_sender.send(DatabaseMessage::UpdateBalances{
-                    user_id: order.user_id.clone(),
-                    trades,
+        self.release_improvement(result, limit_price);
+        self.release_prevented(result, limit_price);
+
+        // Whatever is still locked for orders that are done goes back to the owners
+        for maker_id in filled_makers {
+            let _ = self.balance_manager.unlock_funds(maker_id);
+        }
+        if !result.rested {
+            let _ = self.balance_manager.unlock_funds(result.order.id);
+        }
+
+        let trades = result.trades.clone();
+
+        for trade in &trades {
+            let trade_event = MarketDataEvent::from_trade(trade);
+            self.broadcast(trade_event);
+        }
+
+        if !trades.is_empty(){
+            self.persist(DatabaseMessage::SaveTrades(trades.clone()));
+            self.persist(DatabaseMessage::UpdateBalances{
+                user_id: result.order.user_id.clone(),
+                trades,
+            });
+        }
+
+        self.persist(DatabaseMessage::SaveOrder(result.order.clone()));
+        self.publish_depth(pair);
+        self.update_price_guard(pair, &result.trades);
+
+        if let Some(message) = &result.failed {
+            let mut violations = self.check_invariants();
+            violations.push(Violation::new(&pair.quote, Some(pair), format!("Order {} couldn't settle its next trade: {}", result.order.id, message)));
+            self.halt_violated(violations, true);
+        }
+    }
+
+    // Follow the reference price and trip the circuit breaker when the price ran too far
+    fn update_price_guard(&mut self, pair: &TradingPair, trades: &[Trade]) {
+        let Some(orderbook) = self.orderbooks.get_mut(pair) else {
+            return;
+        };
+
+        if orderbook.guard.record_trades(&orderbook.spec, trades, self.now) && orderbook.status == MarketStatus::Active {
+            // Markets with an auction find their price again in one, the others wait for an admin.
+            // The status change carries the reason out to market data.
+            let reason = Some("circuit_breaker".to_string());
+            match orderbook.spec.auction_secs {
+                Some(_) => self.start_auction(pair, reason),
+                None => self.change_status(pair, MarketStatus::Halted, reason),
+            }
+        }
+    }
+
+    fn publish_depth(&self, pair: &TradingPair) {
+        let Some(orderbook) = self.orderbooks.get(pair) else {
+            return;
+        };
+
+        let (bids, asks) = orderbook.depth(DEPTH_LEVELS);
+        self.broadcast(MarketDataEvent::Depth{
+            pair: format!("{}/{}", pair.base, pair.quote),
+            bids: bids.clone(),
+            asks: asks.clone(),
+            timestamp: self.now,
+        });
+        self.respond(EngineResponse::DepthUpdated{ pair: pair.clone(), bids, asks });
+
+        // Every change to the book of an auction moves the indicative price
+        self.publish_indicative(pair);
+    }
+
+    fn publish_indicative(&self, pair: &TradingPair) {
+        let Some(orderbook) = self.orderbooks.get(pair).filter(|orderbook| orderbook.status == MarketStatus::Auction) else {
+            return;
+        };
+
+        let auction = orderbook.indicative_price();
+        self.broadcast(MarketDataEvent::Auction{
+            pair: format!("{}/{}", pair.base, pair.quote),
+            price: auction.map(|auction| auction.price),
+            volume: auction.map(|auction| auction.volume).unwrap_or_default(),
+            surplus: auction.map(|auction| auction.surplus).unwrap_or_default(),
+            ends_at: orderbook.auction_ends_at,
+            timestamp: self.now,
+        });
+        self.respond(EngineResponse::AuctionIndicative{ pair: pair.clone(), auction, ends_at: orderbook.auction_ends_at });
+    }
+
+    // Bids lock quote at their limit price, asks lock the base they sell.
+    // Market orders lock what sweeping the book right now would cost.
+    fn required_funds(orderbook: &OrderBook, pair: &TradingPair, order: &Order, price: Option<Decimal>) -> (String, Decimal) {
+        match (order.bid_or_ask, price, order.quote_size) {
+            (BidOrAsk::Bid, Some(price), _) => (pair.quote.clone(), price * order.size),
+            (BidOrAsk::Bid, None, Some(quote_size)) => (pair.quote.clone(), quote_size),
+            (BidOrAsk::Bid, None, None) => (pair.quote.clone(), orderbook.market_sweep(order).1),
+            (BidOrAsk::Ask, _, Some(_)) => (pair.base.clone(), orderbook.market_sweep(order).0),
+            (BidOrAsk::Ask, _, None) => (pair.base.clone(), order.size),
+        }
+    }
+
+    // A bid that takes below its limit only needed the lower price
+    fn release_improvement(&mut self, result: &MatchResult, limit_price: Option<Decimal>) {
+        let (BidOrAsk::Bid, Some(limit_price)) = (result.order.bid_or_ask, limit_price) else {
+            return;
+        };
+
+        for trade in &result.trades {
+            let improvement = (limit_price - trade.price) * trade.quantity;
+            if improvement > Decimal::ZERO {
+                let _ = self.balance_manager.release_funds(result.order.id, improvement);
+            }
+        }
+    }
+
+    // Give back what self-trade prevention took off both orders and tell their owners
+    fn release_prevented(&mut self, result: &MatchResult, limit_price: Option<Decimal>) {
+        for prevented in &result.prevented {
+            let maker = &prevented.maker;
+            let reason = Some(format!("self_trade_prevention:{}", prevented.mode.as_str()));
+
+            if prevented.maker_cancelled {
+                let _ = self.balance_manager.unlock_funds(maker.id);
+                self.broadcast(MarketDataEvent::order_update(maker, OrderStatus::Cancelled, reason, self.now));
+                self.persist(DatabaseMessage::UpdateOrderStatus{
+                    order_id: maker.id,
+                    status: OrderStatus::Cancelled,
+                    filled_size: maker.filled_size,
+                    remaining_size: maker.size,
                 });
+            } else if prevented.maker_removed > Decimal::ZERO {
+                let released = match maker.bid_or_ask {
+                    BidOrAsk::Bid => prevented.maker_removed * prevented.price,
+                    BidOrAsk::Ask => prevented.maker_removed,
+                };
+                let _ = self.balance_manager.release_funds(maker.id, released);
+
+                let status = if maker.filled_size > Decimal::ZERO { OrderStatus::PartialFilled } else { OrderStatus::Placed };
+                self.broadcast(MarketDataEvent::order_update(maker, status.clone(), reason, self.now));
+                self.persist(DatabaseMessage::UpdateOrderStatus{
+                    order_id: maker.id,
+                    status,
+                    filled_size: maker.filled_size,
+                    remaining_size: maker.size,
+                });
+            }
+
+            // A decremented taker that rests keeps its lock, minus what it can no longer trade
+            if result.rested && !prevented.taker_cancelled && prevented.taker_removed > Decimal::ZERO {
+                let released = match (result.order.bid_or_ask, limit_price) {
+                    (BidOrAsk::Bid, Some(limit_price)) => prevented.taker_removed * limit_price,
+                    _ => prevented.taker_removed,
+                };
+                let _ = self.balance_manager.release_funds(result.order.id, released);
             }
+        }
+
+        if let Some(prevented) = result.prevented.iter().find(|prevented| prevented.taker_cancelled) {
+            let reason = Some(format!("self_trade_prevention:{}", prevented.mode.as_str()));
+            self.broadcast(MarketDataEvent::order_update(&result.order, OrderStatus::Cancelled, reason, self.now));
+        }
+    }
 
-            let _ = self.database_sender.send(DatabaseMessage::SaveOrder(order));
+    // Market-wide self-trade prevention, used by orders that don't set their own
+    pub fn set_self_trade_prevention(&mut self, pair: &TradingPair, mode: SelfTradePrevention) -> Result<(), String> {
+        let orderbook = self.orderbooks.get_mut(pair)
+            .ok_or_else(|| format!("Market not found for pair: {:?}", pair))?;
+        orderbook.self_trade_prevention = mode;
+        Ok(())
+    }
+
+    fn send_error(&self, message: String) {
+        self.respond(EngineResponse::Error{ message });
+    }
+
+    // Where an order ended up once it has been matched
+    fn final_status(result: &MatchResult) -> OrderStatus {
+        if result.rested {
+            if result.order.filled_size > Decimal::ZERO {
+                OrderStatus::PartialFilled
+            } else {
+                OrderStatus::Placed
+            }
+        } else if result.order.has_remaining() {
+            // IOC/FOK leftovers and market orders that ran out of liquidity
+            OrderStatus::Cancelled
         } else {
-            let response = EngineResponse::Error{
-                message: format!("Market not found for pair: {:?}", pair),
-            };
+            OrderStatus::Filled
+        }
+    }
 
-            let _ = self.message_sender.send(response);
+    fn validate_order(order: &Order, price: Option<Decimal>) -> Result<(), String> {
+        match (order.order_type, price) {
+            (OrderType::Limit, None) => return Err("Price is required for limit orders".to_string()),
+            (OrderType::Limit, Some(price)) if price <= Decimal::ZERO => {
+                return Err(format!("Invalid price {}", price));
+            }
+            (OrderType::Limit, Some(_)) if order.quote_size.is_some() => {
+                return Err("Quote quantity is only supported for market orders".to_string());
+            }
+            (OrderType::Market, _) if order.time_in_force == TimeInForce::PostOnly => {
+                return Err("Market orders can't be post-only".to_string());
+            }
+            (OrderType::Market, _) if order.display_size.is_some() => {
+                return Err("Iceberg orders must be limit orders".to_string());
+            }
+            _ => {}
+        }
+
+        if let Some(display_size) = order.display_size.filter(|display_size| *display_size <= Decimal::ZERO) {
+            return Err(format!("Invalid display quantity {}", display_size));
+        }
+
+        match order.quote_size {
+            Some(quote_size) if quote_size <= Decimal::ZERO => Err(format!("Invalid quote quantity {}", quote_size)),
+            None if order.size <= Decimal::ZERO => Err(format!("Invalid quantity {}", order.size)),
+            _ => Ok(()),
         }
     }
 
-    fn handle_cancel_order(&mut self, order_id: uuid::Uuid){
+    fn handle_cancel_order(&mut self, pair: TradingPair, order_id: Uuid){
+        if let Some(status) = self.orderbooks.get(&pair).map(|orderbook| orderbook.status).filter(|status| !status.accepts_cancels()) {
+            self.send_error(Self::market_closed(&pair, status));
+            return;
+        }
+
+        let cancelled = self.orderbooks.get_mut(&pair)
+            .and_then(|orderbook| orderbook.cancel_order(order_id))
+            .or_else(|| self.stop_books.get_mut(&pair)
+                .and_then(|stop_book| stop_book.cancel(order_id)).map(|stop| stop.order));
+
+        let Some(order) = cancelled else {
+            self.send_error(format!("Order {} not found or already filled", order_id));
+            return;
+        };
+
+        let _ = self.balance_manager.unlock_funds(order_id);
+
+        self.broadcast(MarketDataEvent::order_update(&order, OrderStatus::Cancelled, None, self.now));
+
+        self.persist(DatabaseMessage::UpdateOrderStatus{
+            order_id,
+            status: OrderStatus::Cancelled,
+            filled_size: order.filled_size,
+            remaining_size: order.size,
+        });
+
         let response = EngineResponse::OrderCancelled{order_id};
-        let _ = self.message_sender.send(response);
+        self.respond(response);
+        self.publish_depth(&pair);
+    }
+
+    fn handle_list_market(&mut self, pair: TradingPair, spec: MarketSpec){
+        if let Err(message) = spec.fees.validate() {
+            self.send_error(message);
+            return;
+        }
+
+        match self.orderbooks.get_mut(&pair) {
+            None => self.insert_market(pair.clone(), spec.clone()),
+            Some(orderbook) if orderbook.status == MarketStatus::Delisted => {
+                orderbook.status = MarketStatus::Active;
+                orderbook.spec = spec.clone();
+            }
+            Some(_) => {
+                self.send_error(format!("Market {} is already listed", pair.symbol()));
+                return;
+            }
+        }
+
+        self.respond(EngineResponse::MarketSpecChanged{ pair: pair.clone(), spec });
+        self.open_market(&pair, None);
+        self.publish_depth(&pair);
+    }
+
+    fn handle_set_market_status(&mut self, pair: TradingPair, status: MarketStatus){
+        let Some(orderbook) = self.orderbooks.get_mut(&pair) else {
+            self.send_error(format!("Market not found for pair: {:?}", pair));
+            return;
+        };
+
+        // Delisted markets come back through ListMarket
+        if orderbook.status == MarketStatus::Delisted || !orderbook.status.can_become(status) {
+            let message = format!("Market {} can't go from {} to {}", pair.symbol(), orderbook.status.as_str(), status.as_str());
+            self.send_error(message);
+            return;
+        }
+
+        let current = orderbook.status;
+        match status {
+            MarketStatus::Delisted => {
+                self.cancel_all_orders(&pair);
+                self.change_status(&pair, status, None);
+            }
+            MarketStatus::Auction => self.start_auction(&pair, None),
+            // Resuming straight out of an auction is the same as uncrossing it
+            MarketStatus::Active if current == MarketStatus::Auction => {
+                self.handle_uncross(pair);
+                return;
+            }
+            MarketStatus::Active if current == MarketStatus::Halted => self.open_market(&pair, None),
+            _ => self.change_status(&pair, status, None),
+        }
+
+        self.publish_depth(&pair);
+    }
+
+    // Go live after listing or a halt: through a call auction when the spec has one
+    fn open_market(&mut self, pair: &TradingPair, reason: Option<String>){
+        match self.orderbooks.get(pair).and_then(|orderbook| orderbook.spec.auction_secs) {
+            Some(_) => self.start_auction(pair, reason),
+            None => self.change_status(pair, MarketStatus::Active, reason),
+        }
     }
 
-    pub fn add_user(&mut self, user_id: String, initial_balances: HashMap<String, Decimal>) {
-        self.balance_manager.add_user(user_id, initial_balances);
+    fn start_auction(&mut self, pair: &TradingPair, reason: Option<String>){
+        let now = self.now;
+        if let Some(orderbook) = self.orderbooks.get_mut(pair) {
+            orderbook.auction_ends_at = orderbook.spec.auction_secs.map(|secs| now + Duration::seconds(secs as i64));
+        }
+
+        self.change_status(pair, MarketStatus::Auction, reason);
+        self.publish_indicative(pair);
+    }
+
+    // Trade everything that crosses at the equilibrium price, then match continuously again
+    fn handle_uncross(&mut self, pair: TradingPair){
+        let Some(orderbook) = self.orderbooks.get_mut(&pair) else {
+            self.send_error(format!("Market not found for pair: {:?}", pair));
+            return;
+        };
+
+        if orderbook.status != MarketStatus::Auction {
+            self.send_error(format!("Market {} is not in a call auction", pair.symbol()));
+            return;
+        }
+
+        let mut settlement = TradeSettlement::new(&self.balance_manager, &self.fee_tiers, &orderbook.spec);
+        let mut results = orderbook.uncross_with(&mut MatchContext{ timestamp: self.now, ids: self.ids.as_mut(), settlement: &mut settlement });
+        orderbook.auction_ends_at = None;
+
+        let price = results.iter().flat_map(|(_, result)| &result.trades).map(|trade| trade.price).next();
+        let volume = results.iter().flat_map(|(_, result)| &result.trades).map(|trade| trade.quantity).sum();
+        self.respond(EngineResponse::AuctionUncrossed{ pair: pair.clone(), price, volume });
+
+        // Bids locked funds at the price they rest at and get the difference back
+        for (limit_price, result) in &mut results {
+            self.complete_match(&pair, result, Some(*limit_price));
+        }
+
+        self.change_status(&pair, MarketStatus::Active, Some("auction_uncrossed".to_string()));
+        self.publish_depth(&pair);
+        self.process_triggers(&pair);
+    }
+
+    // Uncross every auction whose time is up, in a fixed order so replays produce the same ids
+    fn uncross_due_auctions(&mut self){
+        let mut due: Vec<TradingPair> = self.orderbooks.values()
+            .filter(|orderbook| orderbook.status == MarketStatus::Auction)
+            .filter(|orderbook| orderbook.auction_ends_at.is_some_and(|ends_at| ends_at <= self.now))
+            .map(|orderbook| orderbook.pair.clone())
+            .collect();
+        due.sort_by_key(|pair| pair.symbol());
+
+        for pair in due {
+            self.handle_uncross(pair);
+        }
     }
+
+    fn change_status(&mut self, pair: &TradingPair, status: MarketStatus, reason: Option<String>){
+        if let Some(orderbook) = self.orderbooks.get_mut(pair) {
+            orderbook.status = status;
+        }
+
+        self.broadcast(MarketDataEvent::MarketStatus{
+            pair: format!("{}/{}", pair.base, pair.quote),
+            status: status.as_str().to_string(),
+            reason,
+            timestamp: self.now,
+        });
+        self.respond(EngineResponse::MarketStatusChanged{ pair: pair.clone(), status });
+    }
+
+    fn handle_set_reference_price(&mut self, pair: TradingPair, price: Decimal){
+        let Some(orderbook) = self.orderbooks.get_mut(&pair) else {
+            self.send_error(format!("Market not found for pair: {:?}", pair));
+            return;
+        };
+
+        if price <= Decimal::ZERO {
+            self.send_error(format!("Invalid reference price {}", price));
+            return;
+        }
+
+        orderbook.guard.reference_price = Some(price);
+    }
+
+    // Cancel every resting and stop order of a market and give the owners their funds back
+    fn cancel_all_orders(&mut self, pair: &TradingPair){
+        let mut cancelled = self.orderbooks.get_mut(pair)
+            .map(|orderbook| orderbook.clear())
+            .unwrap_or_default();
+
+        if let Some(stop_book) = self.stop_books.insert(pair.clone(), StopBook::new()) {
+            cancelled.extend(stop_book.stops().into_iter().map(|stop| stop.order.clone()));
+        }
+
+        let reason = Some("market_delisted".to_string());
+
+        for order in cancelled {
+            let _ = self.balance_manager.unlock_funds(order.id);
+
+            self.broadcast(MarketDataEvent::order_update(&order, OrderStatus::Cancelled, reason.clone(), self.now));
+            self.persist(DatabaseMessage::UpdateOrderStatus{
+                order_id: order.id,
+                status: OrderStatus::Cancelled,
+                filled_size: order.filled_size,
+                remaining_size: order.size,
+            });
+            self.respond(EngineResponse::OrderCancelled{ order_id: order.id });
+        }
+    }
+
+    // Check an incoming order against the rules of its market. Unknown markets are left
+    // for `place_order` to report.
+    fn check_spec(&self, pair: &TradingPair, order: &Order, price: Option<Decimal>, reference_price: Option<Decimal>) -> Result<(), RejectReason> {
+        let Some(orderbook) = self.orderbooks.get(pair) else {
+            return Ok(());
+        };
+
+        let reference_price = reference_price.or(match order.bid_or_ask {
+            BidOrAsk::Bid => orderbook.best_ask(),
+            BidOrAsk::Ask => orderbook.best_bid(),
+        });
+
+        orderbook.spec.validate(order, price, reference_price)
+    }
+
+    // Limit prices have to stay inside the market's price band
+    fn check_band(&self, pair: &TradingPair, price: Option<Decimal>) -> Result<(), RejectReason> {
+        match (self.orderbooks.get(pair), price) {
+            (Some(orderbook), Some(price)) => orderbook.guard.check_price(&orderbook.spec, price),
+            _ => Ok(()),
+        }
+    }
+
+    fn reject(&self, order_id: Uuid, reason: RejectReason) {
+        self.respond(EngineResponse::OrderRejected{ order_id, reason });
+    }
+
+    fn market_closed(pair: &TradingPair, status: MarketStatus) -> String {
+        format!("Market {} is {}", pair.symbol(), status.as_str())
+    }
+
+    fn handle_create_sub_account(&mut self, user_id: String, name: String) {
+        match self.balance_manager.add_sub_account(&user_id, &name) {
+            Ok(()) => self.respond(EngineResponse::SubAccountCreated{ user_id, name }),
+            Err(message) => self.send_error(message),
+        }
+    }
+
+    // Rejected transfers are answered too, so they show up in the transfer history
+    fn handle_transfer(&mut self, transfer: InternalTransfer) {
+        let rejected = transfer.clone();
+        let transfer = match self.balance_manager.transfer(transfer) {
+            Ok(transfer) => transfer,
+            Err(message) => rejected.reject(message),
+        };
+        self.respond(EngineResponse::TransferProcessed{ transfer });
+    }
+
+    fn handle_credit_deposit(&mut self, deposit: Deposit) {
+        match self.balance_manager.credit_deposit(deposit, self.now) {
+            Ok(deposit) => self.respond(EngineResponse::DepositCredited{ deposit }),
+            Err(message) => self.send_error(message),
+        }
+    }
+
+    // A request the balances can't cover is answered as a failed withdrawal, so the user
+    // can see why
+    fn handle_request_withdrawal(&mut self, withdrawal: Withdrawal) {
+        let mut rejected = withdrawal.clone();
+        match self.balance_manager.request_withdrawal(withdrawal) {
+            Ok(withdrawal) => self.respond(EngineResponse::WithdrawalUpdated{ withdrawal }),
+            Err(message) => {
+                rejected.status = WithdrawalStatus::Failed;
+                rejected.failure = Some(message);
+                self.respond(EngineResponse::WithdrawalUpdated{ withdrawal: rejected });
+            }
+        }
+    }
+
+    fn send_withdrawal(&self, result: Result<Withdrawal, String>) {
+        match result {
+            Ok(withdrawal) => self.respond(EngineResponse::WithdrawalUpdated{ withdrawal }),
+            Err(message) => self.send_error(message),
+        }
+    }
+
+    pub fn check_invariants(&self) -> Vec<Violation> {
+        self.collect_violations(true)
+    }
+
+    // Replaying the ledger is only worth it on full checks, not after every message
+    fn collect_violations(&self, replay: bool) -> Vec<Violation> {
+        self.balance_manager.inspect(|state| {
+            let mut violations = invariants::check_balances(state, replay);
+            violations.extend(invariants::check_reservations(state, &self.orderbooks, &self.stop_books));
+            violations
+        })
+    }
+
+    // Reported checks are the full ones asked for with CheckInvariants
+    fn enforce_invariants(&mut self, report: bool) {
+        let violations = self.collect_violations(report);
+        self.halt_violated(violations, report);
+    }
+
+    // Money may have been created or destroyed, so halt every market a violation touches
+    // until an operator has looked. Reports the result when asked or when something is wrong.
+    fn halt_violated(&mut self, violations: Vec<Violation>, report: bool) {
+        let mut halted: Vec<TradingPair> = self.orderbooks.values()
+            .filter(|orderbook| !matches!(orderbook.status, MarketStatus::Halted | MarketStatus::Delisted))
+            .filter(|orderbook| violations.iter().any(|violation| violation.affects(&orderbook.pair)))
+            .map(|orderbook| orderbook.pair.clone())
+            .collect();
+        halted.sort_by_key(|pair| pair.symbol());
+
+        for pair in halted {
+            self.change_status(&pair, MarketStatus::Halted, Some("invariant_violation".to_string()));
+        }
+
+        if report || !violations.is_empty() {
+            self.respond(EngineResponse::InvariantsChecked{ violations });
+        }
+    }
+
+    pub fn add_user(&mut self, user_id: String, initial_balances: HashMap<String, Decimal>) -> Result<(), String> {
+        self.balance_manager.add_user(user_id, initial_balances)
+    }
+
+    pub fn set_fee_tier(&mut self, user_id: String, tier: u32) {
+        match tier {
+            0 => self.fee_tiers.remove(&user_id),
+            _ => self.fee_tiers.insert(user_id, tier),
+        };
+    }
+}
+
+// Charges each trade the fees of its market and the users' tiers, then settles it against
+// the funds both orders locked
+struct TradeSettlement<'a>{
+    balance_manager: &'a BalanceManager,
+    fee_tiers: &'a HashMap<String, u32>,
+    spec: MarketSpec,
 }
 
+impl<'a> TradeSettlement<'a>{
+    fn new(balance_manager: &'a BalanceManager, fee_tiers: &'a HashMap<String, u32>, spec: &MarketSpec) -> TradeSettlement<'a>{
+        TradeSettlement{ balance_manager, fee_tiers, spec: spec.clone() }
+    }
+}
+
+impl Settlement for TradeSettlement<'_>{
+    fn settle(&mut self, trade: &mut Trade) -> Result<(), String>{
+        let spec = &self.spec;
+        let tier = |user_id: &str| self.fee_tiers.get(user_id).copied().unwrap_or_default();
+        let (buyer_fee, seller_fee) = spec.fees.trade_fees(trade, tier(&trade.buyer_user_id), tier(&trade.seller_user_id), spec.base_precision, spec.quote_precision);
+        trade.buyer_fee = buyer_fee;
+        trade.seller_fee = seller_fee;
+        self.balance_manager.settle_trade(trade)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::matching_engine::fees::{FeeRates, FeeSchedule};
+
+    fn engine_with_users() -> (MatchingEngine, Receiver<EngineResponse>, TradingPair) {
+        let (mut engine, _msg_tx, resp_rx, _db_rx, _ws_rx) = MatchingEngine::new();
+        let pair = TradingPair::new("BTC".to_string(), "USD".to_string());
+        engine.add_market(pair.clone());
+
+        for user_id in ["buyer", "seller"] {
+            let mut balances = HashMap::new();
+            balances.insert("BTC".to_string(), Decimal::from(10));
+            balances.insert("USD".to_string(), Decimal::from(1000));
+            engine.add_user(user_id.to_string(), balances).unwrap();
+        }
+
+        (engine, resp_rx, pair)
+    }
+
+    fn order_for(user_id: &str, side: BidOrAsk, size: i64) -> Order {
+        let mut order = Order::new(side, Decimal::from(size));
+        order.user_id = user_id.to_string();
+        order
+    }
+
+    #[test]
+    fn test_trades_settle_against_locked_funds() {
+        let (mut engine, resp_rx, pair) = engine_with_users();
+
+        engine.handle_place_order(pair.clone(), order_for("seller", BidOrAsk::Ask, 2), Some(Decimal::from(90)));
+        let seller_btc = engine.balance_manager.get_balance("seller", "BTC").unwrap();
+        assert_eq!(seller_btc.locked, Decimal::from(2));
+
+        // Bid at 100 fills at 90, the price improvement goes straight back
+        engine.handle_place_order(pair.clone(), order_for("buyer", BidOrAsk::Bid, 3), Some(Decimal::from(100)));
+
+        let buyer_usd = engine.balance_manager.get_balance("buyer", "USD").unwrap();
+        assert_eq!(buyer_usd.locked, Decimal::from(100));
+        assert_eq!(buyer_usd.available, Decimal::from(720));
+        assert_eq!(engine.balance_manager.get_balance("buyer", "BTC").unwrap().available, Decimal::from(12));
+
+        let seller_usd = engine.balance_manager.get_balance("seller", "USD").unwrap();
+        assert_eq!(seller_usd.available, Decimal::from(1180));
+        assert_eq!(engine.balance_manager.get_balance("seller", "BTC").unwrap().locked, Decimal::ZERO);
+
+        // Not enough USD left for this one
+        while resp_rx.try_recv().is_ok() {}
+        engine.handle_place_order(pair, order_for("buyer", BidOrAsk::Bid, 10), Some(Decimal::from(100)));
+        assert!(matches!(resp_rx.try_recv(), Ok(EngineResponse::Error{ .. })));
+    }
+
+    #[test]
+    fn test_amend_keeps_priority_only_when_reducing() {
+        let (mut engine, _resp_rx, pair) = engine_with_users();
+        let price = Decimal::from(100);
+
+        let first = order_for("seller", BidOrAsk::Ask, 2);
+        let second = order_for("seller", BidOrAsk::Ask, 2);
+        let (first_id, second_id) = (first.id, second.id);
+        engine.handle_place_order(pair.clone(), first, Some(price));
+        engine.handle_place_order(pair.clone(), second, Some(price));
+
+        engine.handle_amend_order(pair.clone(), first_id, "seller".to_string(), None, Some(Decimal::from(1)));
+        assert_eq!(engine.balance_manager.get_balance("seller", "BTC").unwrap().locked, Decimal::from(3));
+        let orderbook = &engine.orderbooks[&pair];
+        assert_eq!(orderbook.asks[&price].orders.front().unwrap().1.id, first_id);
+
+        // Raising the size sends it to the back of the queue
+        engine.handle_amend_order(pair.clone(), first_id, "seller".to_string(), None, Some(Decimal::from(3)));
+        let orderbook = &engine.orderbooks[&pair];
+        assert_eq!(orderbook.asks[&price].orders.front().unwrap().1.id, second_id);
+        assert_eq!(engine.balance_manager.get_balance("seller", "BTC").unwrap().locked, Decimal::from(5));
+    }
+
+    #[test]
+    fn test_stops_trigger_in_cascade_and_cancel() {
+        let (mut engine, _resp_rx, pair) = engine_with_users();
+
+        for price in [100, 110, 120, 130] {
+            engine.handle_place_order(pair.clone(), order_for("seller", BidOrAsk::Ask, 1), Some(Decimal::from(price)));
+        }
+
+        let stop_market = order_for("buyer", BidOrAsk::Bid, 1);
+        engine.handle_place_stop_order(pair.clone(), stop_market, Decimal::from(105), None);
+        // Only reached through the fill of the first stop
+        let stop_limit = order_for("buyer", BidOrAsk::Bid, 1);
+        engine.handle_place_stop_order(pair.clone(), stop_limit, Decimal::from(115), Some(Decimal::from(130)));
+        let cancelled = order_for("buyer", BidOrAsk::Bid, 1);
+        let cancelled_id = cancelled.id;
+        engine.handle_place_stop_order(pair.clone(), cancelled, Decimal::from(200), None);
+
+        assert_eq!(engine.balance_manager.get_balance("buyer", "USD").unwrap().locked, Decimal::from(435));
+        engine.handle_cancel_order(pair.clone(), cancelled_id);
+        assert_eq!(engine.balance_manager.get_balance("buyer", "USD").unwrap().locked, Decimal::from(235));
+
+        engine.handle_place_order(pair.clone(), order_for("buyer", BidOrAsk::Bid, 2), Some(Decimal::from(110)));
+
+        assert!(engine.orderbooks[&pair].asks.is_empty());
+        assert!(engine.stop_books[&pair].is_empty());
+        assert_eq!(engine.orderbooks[&pair].last_trade_price, Some(Decimal::from(130)));
+
+        let buyer_usd = engine.balance_manager.get_balance("buyer", "USD").unwrap();
+        assert_eq!(buyer_usd.available, Decimal::from(540));
+        assert_eq!(buyer_usd.locked, Decimal::ZERO);
+        assert_eq!(engine.balance_manager.get_balance("buyer", "BTC").unwrap().available, Decimal::from(14));
+        assert!(engine.check_invariants().is_empty());
+    }
+
+    #[test]
+    fn test_triggered_stop_market_buy_keeps_its_reservation() {
+        let (mut engine, _resp_rx, pair) = engine_with_users();
+        let usd = |engine: &MatchingEngine| {
+            let balance = engine.balance_manager.get_balance("buyer", "USD").unwrap();
+            (balance.available, balance.locked)
+        };
+
+        for price in [100, 120, 130] {
+            engine.handle_place_order(pair.clone(), order_for("seller", BidOrAsk::Ask, 1), Some(Decimal::from(price)));
+        }
+
+        // Reserves 200 at the trigger, but sweeping 120 and 130 costs 250: the other 50 is added
+        engine.handle_place_stop_order(pair.clone(), order_for("buyer", BidOrAsk::Bid, 2), Decimal::from(100), None);
+        assert_eq!(usd(&engine), (Decimal::from(800), Decimal::from(200)));
+        engine.handle_place_order(pair.clone(), order_for("buyer", BidOrAsk::Bid, 1), Some(Decimal::from(100)));
+        assert!(engine.stop_books[&pair].is_empty() && engine.orderbooks[&pair].asks.is_empty());
+        assert_eq!(usd(&engine), (Decimal::from(650), Decimal::ZERO));
+
+        // 450 reserved for 3 at 150, then 170 goes on a trade at 170. Sweeping 3 at 170 needs
+        // 60 more but only 30 is left, so the stop buys the 2.6 steps of 0.1 the 450 pays for.
+        engine.orderbooks.get_mut(&pair).unwrap().spec.step_size = Decimal::new(1, 1);
+        engine.handle_place_order(pair.clone(), order_for("seller", BidOrAsk::Ask, 5), Some(Decimal::from(170)));
+        engine.handle_place_stop_order(pair.clone(), order_for("buyer", BidOrAsk::Bid, 3), Decimal::from(150), None);
+        engine.handle_place_order(pair.clone(), order_for("buyer", BidOrAsk::Bid, 1), Some(Decimal::from(170)));
+        assert!(engine.stop_books[&pair].is_empty());
+        assert_eq!(usd(&engine), (Decimal::from(38), Decimal::ZERO));
+        assert_eq!(engine.balance_manager.get_balance("buyer", "BTC").unwrap().available, Decimal::new(166, 1));
+        assert_eq!(engine.orderbooks[&pair].depth(1).1[0].quantity, Decimal::new(14, 1));
+        assert!(engine.check_invariants().is_empty());
+    }
+
+    #[test]
+    fn test_market_status_gates_orders_and_delisting_releases_funds() {
+        let (mut engine, resp_rx, pair) = engine_with_users();
+
+        let resting = order_for("buyer", BidOrAsk::Bid, 2);
+        let resting_id = resting.id;
+        engine.handle_place_order(pair.clone(), resting, Some(Decimal::from(100)));
+        engine.handle_place_stop_order(pair.clone(), order_for("seller", BidOrAsk::Ask, 1), Decimal::from(80), None);
+
+        // Halted: nothing gets in, not even cancels
+        engine.handle_set_market_status(pair.clone(), MarketStatus::Halted);
+        while resp_rx.try_recv().is_ok() {}
+        engine.handle_place_order(pair.clone(), order_for("seller", BidOrAsk::Ask, 1), Some(Decimal::from(100)));
+        assert!(matches!(resp_rx.try_recv(), Ok(EngineResponse::Error{ .. })));
+        engine.handle_cancel_order(pair.clone(), resting_id);
+        assert!(matches!(resp_rx.try_recv(), Ok(EngineResponse::Error{ .. })));
+        assert!(engine.orderbooks[&pair].contains(resting_id));
+
+        // Cancel-only: cancels go through, new orders don't
+        engine.handle_set_market_status(pair.clone(), MarketStatus::CancelOnly);
+        engine.handle_place_order(pair.clone(), order_for("seller", BidOrAsk::Ask, 1), Some(Decimal::from(100)));
+        assert!(engine.orderbooks[&pair].asks.is_empty());
+        engine.handle_cancel_order(pair.clone(), resting_id);
+        assert_eq!(engine.balance_manager.get_balance("buyer", "USD").unwrap().locked, Decimal::ZERO);
+
+        engine.handle_set_market_status(pair.clone(), MarketStatus::Active);
+        engine.handle_place_order(pair.clone(), order_for("buyer", BidOrAsk::Bid, 3), Some(Decimal::from(100)));
+        assert_eq!(engine.balance_manager.get_balance("buyer", "USD").unwrap().locked, Decimal::from(300));
+
+        // Delisting cancels resting and stop orders and frees their funds
+        engine.handle_set_market_status(pair.clone(), MarketStatus::Delisted);
+        assert!(engine.orderbooks[&pair].bids.is_empty());
+        assert!(engine.stop_books[&pair].is_empty());
+        assert_eq!(engine.balance_manager.get_balance("buyer", "USD").unwrap().locked, Decimal::ZERO);
+        assert_eq!(engine.balance_manager.get_balance("seller", "BTC").unwrap().locked, Decimal::ZERO);
+
+        // Only listing brings it back
+        while resp_rx.try_recv().is_ok() {}
+        engine.handle_set_market_status(pair.clone(), MarketStatus::Active);
+        assert!(matches!(resp_rx.try_recv(), Ok(EngineResponse::Error{ .. })));
+        let spec = MarketSpec{ tick_size: Decimal::from(10), ..MarketSpec::default() };
+        engine.handle_list_market(pair.clone(), spec);
+        assert_eq!(engine.orderbooks[&pair].status, MarketStatus::Active);
+
+        // Relisted with a coarser tick
+        while resp_rx.try_recv().is_ok() {}
+        engine.handle_place_order(pair.clone(), order_for("buyer", BidOrAsk::Bid, 1), Some(Decimal::from(95)));
+        assert!(matches!(resp_rx.try_recv(), Ok(EngineResponse::OrderRejected{ reason: RejectReason::PriceOffTick{ .. }, .. })));
+        assert!(engine.orderbooks[&pair].bids.is_empty());
+    }
+
+    #[test]
+    fn test_price_band_and_circuit_breaker() {
+        let (mut engine, resp_rx, pair) = engine_with_users();
+
+        for price in [100, 106, 120] {
+            engine.handle_place_order(pair.clone(), order_for("seller", BidOrAsk::Ask, 1), Some(Decimal::from(price)));
+        }
+
+        let orderbook = engine.orderbooks.get_mut(&pair).unwrap();
+        orderbook.spec.band_percent = Some(Decimal::from(10));
+        orderbook.spec.breaker_percent = Some(Decimal::from(5));
+        engine.handle_set_reference_price(pair.clone(), Decimal::from(100));
+
+        while resp_rx.try_recv().is_ok() {}
+        engine.handle_place_order(pair.clone(), order_for("buyer", BidOrAsk::Bid, 1), Some(Decimal::from(111)));
+        assert!(matches!(resp_rx.try_recv(), Ok(EngineResponse::OrderRejected{ reason: RejectReason::PriceOutsideBand{ .. }, .. })));
+
+        // The sweep stops at the top of the band, and moving 6% trips the breaker
+        let mut sweep = Order::new_market(BidOrAsk::Bid, Decimal::from(3));
+        sweep.user_id = "buyer".to_string();
+        engine.handle_place_order(pair.clone(), sweep, None);
+
+        let orderbook = &engine.orderbooks[&pair];
+        assert_eq!(orderbook.last_trade_price, Some(Decimal::from(106)));
+        assert_eq!(orderbook.best_ask(), Some(Decimal::from(120)));
+        assert_eq!(orderbook.status, MarketStatus::Halted);
+        assert!(resp_rx.try_iter().any(|response| matches!(response, EngineResponse::MarketStatusChanged{ status: MarketStatus::Halted, .. })));
+    }
+
+    #[test]
+    fn test_resuming_after_a_halt_runs_a_call_auction() {
+        let (mut engine, resp_rx, pair) = engine_with_users();
+        engine.orderbooks.get_mut(&pair).unwrap().spec.auction_secs = Some(60);
+        let start = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap();
+        let at = |secs: i64| start + Duration::seconds(secs);
+
+        engine.apply_message(1, at(0), EngineMessage::SetMarketStatus{ pair: pair.clone(), status: MarketStatus::Halted });
+        engine.apply_message(2, at(0), EngineMessage::SetMarketStatus{ pair: pair.clone(), status: MarketStatus::Active });
+        assert_eq!(engine.orderbooks[&pair].status, MarketStatus::Auction);
+        assert_eq!(engine.orderbooks[&pair].auction_ends_at, Some(at(60)));
+
+        // Crossing orders rest; market orders have nothing to match against yet
+        let place = |order: Order, price: Option<i64>| EngineMessage::PlaceOrder{ pair: pair.clone(), order, price: price.map(Decimal::from) };
+        engine.apply_message(3, at(10), place(order_for("buyer", BidOrAsk::Bid, 2), Some(105)));
+        engine.apply_message(4, at(20), place(order_for("seller", BidOrAsk::Ask, 3), Some(100)));
+        assert!(engine.orderbooks[&pair].last_trade_price.is_none());
+
+        // 2 trades at 100 and at 105, both leave a seller surplus of 1: the lower price wins
+        let indicative = resp_rx.try_iter().filter_map(|response| match response {
+            EngineResponse::AuctionIndicative{ auction, .. } => auction,
+            _ => None,
+        }).last();
+        assert_eq!(indicative.map(|auction| (auction.price, auction.volume)), Some((Decimal::from(100), Decimal::from(2))));
+
+        engine.apply_message(5, at(30), place(Order::new_market(BidOrAsk::Bid, Decimal::ONE), None));
+        assert!(matches!(resp_rx.try_recv(), Ok(EngineResponse::Error{ .. })));
+
+        // The first message after the auction ran out uncrosses it first
+        engine.apply_message(6, at(61), EngineMessage::SetReferencePrice{ pair: pair.clone(), price: Decimal::from(100) });
+        let orderbook = &engine.orderbooks[&pair];
+        assert_eq!(orderbook.status, MarketStatus::Active);
+        assert_eq!(orderbook.last_trade_price, Some(Decimal::from(100)));
+        assert!(orderbook.bids.is_empty());
+        assert_eq!(orderbook.depth(1).1[0].quantity, Decimal::ONE);
+        let uncrossed = resp_rx.try_iter().find_map(|response| match response {
+            EngineResponse::AuctionUncrossed{ price, volume, .. } => Some((price, volume)),
+            _ => None,
+        });
+        assert_eq!(uncrossed, Some((Some(Decimal::from(100)), Decimal::from(2))));
+
+        // The buyer locked 210 and paid 200
+        let buyer_usd = engine.balance_manager.get_balance("buyer", "USD").unwrap();
+        assert_eq!((buyer_usd.available, buyer_usd.locked), (Decimal::from(800), Decimal::ZERO));
+        assert_eq!(engine.balance_manager.get_balance("seller", "BTC").unwrap().locked, Decimal::ONE);
+    }
+
+    #[test]
+    fn test_fees_are_charged_on_settlement_and_collected() {
+        let (mut engine, resp_rx, pair) = engine_with_users();
+        let mut fees = FeeSchedule::flat(Decimal::new(1, 3), Decimal::new(2, 3));
+        fees.tiers.insert(1, FeeRates{ maker: Decimal::new(-1, 3), taker: Decimal::new(1, 3) });
+        engine.orderbooks.get_mut(&pair).unwrap().spec.fees = fees;
+        engine.set_fee_tier("seller".to_string(), 1);
+
+        // The seller makes with a rebate, the buyer takes at the tier 0 rate
+        engine.handle_place_order(pair.clone(), order_for("seller", BidOrAsk::Ask, 2), Some(Decimal::from(100)));
+        while resp_rx.try_recv().is_ok() {}
+        engine.handle_place_order(pair.clone(), order_for("buyer", BidOrAsk::Bid, 2), Some(Decimal::from(100)));
+
+        let trades = resp_rx.try_iter().find_map(|response| match response {
+            EngineResponse::OrderPlaced{ trades, .. } => Some(trades),
+            _ => None,
+        }).unwrap();
+        assert_eq!((trades[0].buyer_fee, trades[0].seller_fee), (Decimal::new(4, 3), Decimal::new(-2, 1)));
+
+        let buyer_btc = engine.balance_manager.get_balance("buyer", "BTC").unwrap();
+        assert_eq!(buyer_btc.available, Decimal::new(11996, 3));
+        let seller_usd = engine.balance_manager.get_balance("seller", "USD").unwrap();
+        assert_eq!(seller_usd.available, Decimal::new(12002, 1));
+
+        let collected = engine.balance_manager.fee_balances();
+        assert_eq!(collected.get("BTC"), Some(&Decimal::new(4, 3)));
+        assert_eq!(collected.get("USD"), Some(&Decimal::new(-2, 1)));
+        assert!(engine.check_invariants().is_empty());
 
+        // The ledger keeps count of the rebates, so the fee account still checks out once
+        // the entries that paid them are archived
+        engine.balance_manager.compact_ledger(u64::MAX);
+        engine.balance_manager.inspect(|state| assert_eq!(state.ledger().rebates("USD"), Decimal::new(2, 1)));
+        assert!(engine.check_invariants().is_empty());
+
+        // Markets can't be set up with rebates larger than the fees that pay for them
+        let mut generous = MarketSpec::default();
+        generous.fees.tiers.insert(0, FeeRates{ maker: Decimal::new(-3, 3), taker: Decimal::new(2, 3) });
+        let eth = TradingPair::new("ETH".to_string(), "USD".to_string());
+        assert!(engine.add_market_with_spec(eth.clone(), generous).is_err());
+        assert!(!engine.orderbooks.contains_key(&eth));
+    }
+
+    #[test]
+    fn test_sub_accounts_trade_apart_and_transfers_never_overdraw() {
+        let (mut engine, resp_rx, pair) = engine_with_users();
+        let transfer = |amount: i64, from: Option<&str>, to_user_id: &str, to: Option<&str>| EngineMessage::Transfer{
+            transfer_id: Uuid::new_v4(),
+            user_id: "buyer".to_string(),
+            from_sub_account: from.map(str::to_string),
+            to_user_id: to_user_id.to_string(),
+            to_sub_account: to.map(str::to_string),
+            asset: "USD".to_string(),
+            amount: Decimal::from(amount),
+        };
+        let transfer_status = |resp_rx: &Receiver<EngineResponse>| resp_rx.try_iter().find_map(|response| match response {
+            EngineResponse::TransferProcessed{ transfer } => Some(transfer.status),
+            _ => None,
+        });
+
+        engine.process_message(EngineMessage::CreateSubAccount{ user_id: "buyer".to_string(), name: "algo".to_string() });
+        engine.process_message(transfer(300, None, "buyer", Some("algo")));
+        assert_eq!(transfer_status(&resp_rx), Some(TransferStatus::Completed));
+        // The sub-account only has 300
+        engine.process_message(transfer(301, Some("algo"), "buyer", None));
+        assert_eq!(transfer_status(&resp_rx), Some(TransferStatus::Rejected));
+        // Other users' sub-accounts can't be funded
+        engine.process_message(transfer(1, None, "seller", Some("algo")));
+        assert_eq!(transfer_status(&resp_rx), Some(TransferStatus::Rejected));
+
+        let mut bid = order_for("buyer", BidOrAsk::Bid, 2);
+        bid.sub_account = Some("algo".to_string());
+        engine.handle_place_order(pair.clone(), bid, Some(Decimal::from(100)));
+        engine.handle_place_order(pair.clone(), order_for("seller", BidOrAsk::Ask, 2), Some(Decimal::from(100)));
+
+        // The trade settled in the sub-account, the main account only paid for the transfer
+        let algo_usd = engine.balance_manager.get_balance("buyer/algo", "USD").unwrap();
+        assert_eq!((algo_usd.available, algo_usd.locked), (Decimal::from(100), Decimal::ZERO));
+        assert_eq!(engine.balance_manager.get_balance("buyer/algo", "BTC").unwrap().available, Decimal::from(2));
+        assert_eq!(engine.balance_manager.get_balance("buyer", "USD").unwrap().available, Decimal::from(700));
+        assert_eq!(engine.balance_manager.get_balance("buyer", "BTC").unwrap().available, Decimal::from(10));
+
+        engine.process_message(transfer(100, Some("algo"), "seller", None));
+        assert_eq!(transfer_status(&resp_rx), Some(TransferStatus::Completed));
+        assert_eq!(engine.balance_manager.get_balance("seller", "USD").unwrap().available, Decimal::from(1300));
+        assert_eq!(engine.balance_manager.sub_accounts("buyer"), ["algo"]);
+    }
+
+    #[test]
+    fn test_invariant_violation_halts_only_the_affected_market() {
+        let (mut engine, resp_rx, pair) = engine_with_users();
+        let eth = TradingPair::new("ETH".to_string(), "USD".to_string());
+        engine.add_market(eth.clone());
+        let start = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap();
+        let report = |resp_rx: &Receiver<EngineResponse>| resp_rx.try_iter().find_map(|response| match response {
+            EngineResponse::InvariantsChecked{ violations } => Some(violations),
+            _ => None,
+        });
+
+        let ask = order_for("seller", BidOrAsk::Ask, 2);
+        let ask_id = ask.id;
+        engine.apply_message(1, start, EngineMessage::PlaceOrder{ pair: pair.clone(), order: ask, price: Some(Decimal::from(100)) });
+        engine.apply_message(2, start, EngineMessage::CheckInvariants);
+        assert_eq!(report(&resp_rx), Some(Vec::new()));
+
+        // A bug gives back funds the resting ask still needs
+        engine.balance_manager.release_funds(ask_id, Decimal::ONE).unwrap();
+        engine.apply_message(3, start, EngineMessage::CheckInvariants);
+
+        let violations = report(&resp_rx).unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!((violations[0].asset.as_str(), violations[0].pair.as_ref()), ("BTC", Some(&pair)));
+        assert_eq!(engine.orderbooks[&pair].status, MarketStatus::Halted);
+        assert_eq!(engine.orderbooks[&eth].status, MarketStatus::Active);
+    }
+
+    #[test]
+    fn test_trade_that_cannot_settle_stops_the_match_and_halts_the_market() {
+        let (mut engine, resp_rx, pair) = engine_with_users();
+
+        engine.handle_place_order(pair.clone(), order_for("seller", BidOrAsk::Ask, 1), Some(Decimal::from(100)));
+        let ask = order_for("seller", BidOrAsk::Ask, 1);
+        let ask_id = ask.id;
+        engine.handle_place_order(pair.clone(), ask, Some(Decimal::from(101)));
+
+        // A bug gives back what the second ask needs to deliver
+        engine.balance_manager.unlock_funds(ask_id).unwrap();
+        while resp_rx.try_recv().is_ok() {}
+        engine.handle_place_order(pair.clone(), order_for("buyer", BidOrAsk::Bid, 2), Some(Decimal::from(101)));
+
+        let responses: Vec<EngineResponse> = resp_rx.try_iter().collect();
+        let trades = responses.iter().find_map(|response| match response {
+            EngineResponse::OrderPlaced{ trades, .. } => Some(trades),
+            _ => None,
+        }).unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].price, Decimal::from(100));
+
+        // The ask that couldn't deliver is untouched and the bid doesn't rest
+        let orderbook = &engine.orderbooks[&pair];
+        assert!(orderbook.bids.is_empty());
+        assert_eq!(orderbook.depth(1).1[0].quantity, Decimal::ONE);
+        assert_eq!(orderbook.status, MarketStatus::Halted);
+        let buyer_usd = engine.balance_manager.get_balance("buyer", "USD").unwrap();
+        assert_eq!((buyer_usd.available, buyer_usd.locked), (Decimal::from(900), Decimal::ZERO));
+        assert_eq!(engine.balance_manager.get_balance("buyer", "BTC").unwrap().available, Decimal::from(11));
+
+        let violations = responses.iter().find_map(|response| match response {
+            EngineResponse::InvariantsChecked{ violations } => Some(violations),
+            _ => None,
+        }).unwrap();
+        assert!(violations.iter().any(|violation| violation.message.contains("couldn't settle")));
+    }
+
+    // Every level, bids then asks, with (order id, size, visible size) in queue order
+    type BookState = Vec<(Decimal, Vec<(Uuid, Decimal, Decimal)>)>;
+
+    fn book_state(engine: &MatchingEngine, pair: &TradingPair) -> BookState {
+        let orderbook = &engine.orderbooks[pair];
+        orderbook.bids.iter().chain(orderbook.asks.iter())
+            .map(|(price, limit)| (*price, limit.orders.iter().map(|order| (order.id, order.size, order.visible())).collect()))
+            .collect()
+    }
+
+    fn assert_same_state(rebuilt: &MatchingEngine, expected: &MatchingEngine, pair: &TradingPair) {
+        assert_eq!(book_state(rebuilt, pair), book_state(expected, pair));
+        assert_eq!(rebuilt.orderbooks[pair].last_trade_price, expected.orderbooks[pair].last_trade_price);
+
+        let stop_ids = |engine: &MatchingEngine| engine.stop_books[pair].stops().iter().map(|stop| stop.order.id).collect::<Vec<_>>();
+        assert_eq!(stop_ids(rebuilt), stop_ids(expected));
+
+        for user_id in ["buyer", "seller"] {
+            for asset in ["BTC", "USD"] {
+                let expected = expected.balance_manager.get_balance(user_id, asset).unwrap();
+                let rebuilt = rebuilt.balance_manager.get_balance(user_id, asset).unwrap();
+                assert_eq!((rebuilt.available, rebuilt.locked), (expected.available, expected.locked));
+            }
+        }
+    }
+
+    #[test]
+    fn test_journal_rebuilds_state_after_a_crash() {
+        let path = std::env::temp_dir().join(format!("cex-journal-{}.journal", Uuid::new_v4()));
+        let path = path.to_str().unwrap().to_string();
+
+        let (mut engine, _resp_rx, pair) = engine_with_users();
+        assert_eq!(engine.open_journal(&path).unwrap(), Recovery::default());
+
+        let resting = order_for("seller", BidOrAsk::Ask, 3);
+        let resting_id = resting.id;
+        let messages = vec![
+            EngineMessage::PlaceOrder{ pair: pair.clone(), order: resting, price: Some(Decimal::from(100)) },
+            EngineMessage::PlaceOrder{ pair: pair.clone(), order: order_for("seller", BidOrAsk::Ask, 2), price: Some(Decimal::from(110)) },
+            EngineMessage::PlaceOrder{ pair: pair.clone(), order: order_for("buyer", BidOrAsk::Bid, 4), price: Some(Decimal::from(105)) },
+            EngineMessage::PlaceOrder{ pair: pair.clone(), order: order_for("buyer", BidOrAsk::Bid, 1), price: Some(Decimal::from(90)) },
+            EngineMessage::CancelOrder{ pair: pair.clone(), order_id: resting_id },
+            EngineMessage::PlaceOrder{ pair: pair.clone(), order: order_for("buyer", BidOrAsk::Bid, 1), price: Some(Decimal::from(110)) },
+        ];
+
+        // The engine dies after the fourth message, halfway through writing the fifth
+        for msg in messages.into_iter().take(4) {
+            engine.accept_message(msg);
+        }
+        std::fs::OpenOptions::new().append(true).open(&path).unwrap()
+            .write_all(b"{\"seq\":5,\"message\":{\"CancelOr").unwrap();
+
+        let (mut recovered, _recovered_rx, _) = engine_with_users();
+        let recovery = recovered.open_journal(&path).unwrap();
+        assert_eq!(recovery.replayed, 4);
+        assert!(recovery.torn_bytes > 0);
+
+        assert_same_state(&recovered, &engine, &pair);
+
+        // The torn entry is gone and new messages carry on from the last good one
+        recovered.accept_message(EngineMessage::CancelOrder{ pair: pair.clone(), order_id: resting_id });
+        let (_, entries) = Journal::open(&path).unwrap();
+        assert_eq!(entries.last().map(|entry| entry.seq), Some(5));
+        assert!(!recovered.orderbooks[&pair].contains(resting_id));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_restart_from_snapshot_skips_bad_files() {
+        let dir = std::env::temp_dir().join(format!("cex-snapshots-{}", Uuid::new_v4()));
+        let journal_path = dir.join("engine.journal").to_str().unwrap().to_string();
+        let snapshot_dir = dir.join("snapshots");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let (mut engine, _resp_rx, pair) = engine_with_users();
+        engine.enable_snapshots(snapshot_dir.to_str().unwrap(), Some(3));
+        engine.open_journal(&journal_path).unwrap();
+
+        let mut iceberg = Order::new_iceberg(BidOrAsk::Ask, Decimal::from(5), Decimal::from(2));
+        iceberg.user_id = "seller".to_string();
+        let messages = vec![
+            EngineMessage::PlaceOrder{ pair: pair.clone(), order: iceberg, price: Some(Decimal::from(100)) },
+            EngineMessage::PlaceOrder{ pair: pair.clone(), order: order_for("seller", BidOrAsk::Ask, 1), price: Some(Decimal::from(100)) },
+            EngineMessage::PlaceOrder{ pair: pair.clone(), order: order_for("buyer", BidOrAsk::Bid, 1), price: Some(Decimal::from(100)) },
+            EngineMessage::PlaceStopOrder{ pair: pair.clone(), order: order_for("seller", BidOrAsk::Ask, 1), trigger_price: Decimal::from(80), limit_price: None },
+            EngineMessage::PlaceOrder{ pair: pair.clone(), order: order_for("buyer", BidOrAsk::Bid, 1), price: Some(Decimal::from(90)) },
+        ];
+        for msg in messages {
+            engine.accept_message(msg);
+        }
+
+        // The ledger entries from before the snapshot were archived next to it, and together
+        // with the ones still in memory they are the whole ledger
+        let archived = snapshot::load_ledger_archive(&snapshot_dir).unwrap();
+        engine.balance_manager.inspect(|state| {
+            let ledger = state.ledger();
+            assert!(!archived.is_empty());
+            assert_eq!(archived.len() as u64, ledger.archived());
+            let seqs: Vec<u64> = archived.iter().chain(ledger.entries()).map(|entry| entry.seq).collect();
+            assert_eq!(seqs, (1..=seqs.len() as u64).collect::<Vec<_>>());
+        });
+
+        // A newer snapshot that fails its checksum and one from a future format are both refused
+        let good = std::fs::read(snapshot_dir.join(format!("snapshot-{:020}.snap", 3))).unwrap();
+        let mut corrupt = good.clone();
+        *corrupt.last_mut().unwrap() ^= 0xff;
+        std::fs::write(snapshot_dir.join(format!("snapshot-{:020}.snap", 4)), corrupt).unwrap();
+        let mut future = good.clone();
+        future[8] = 99;
+        std::fs::write(snapshot_dir.join(format!("snapshot-{:020}.snap", 5)), future).unwrap();
+
+        let (mut recovered, _recovered_rx, _) = engine_with_users();
+        recovered.enable_snapshots(snapshot_dir.to_str().unwrap(), None);
+        let recovery = recovered.open_journal(&journal_path).unwrap();
+        assert_eq!((recovery.snapshot_seq, recovery.replayed), (Some(3), 2));
+        assert_eq!(recovery.rejected_snapshots.len(), 2);
+        assert_same_state(&recovered, &engine, &pair);
+        let ledger = |engine: &MatchingEngine| engine.balance_manager.inspect(|state| (state.ledger().archived(), state.ledger().entries().to_vec(), state.ledger().is_consistent()));
+        assert_eq!(ledger(&recovered), ledger(&engine));
+        assert_eq!(recovered.balance_manager.ledger_history("buyer", "BTC"), engine.balance_manager.ledger_history("buyer", "BTC"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_same_input_gives_identical_output() {
+        use crate::matching_engine::clock::{SimulatedClock, SequentialIds};
+        use chrono::{Duration, TimeZone};
+
+        let pair = TradingPair::new("BTC".to_string(), "USD".to_string());
+        let messages = vec![
+            EngineMessage::PlaceOrder{ pair: pair.clone(), order: order_for("seller", BidOrAsk::Ask, 2), price: Some(Decimal::from(100)) },
+            EngineMessage::PlaceOrder{ pair: pair.clone(), order: order_for("seller", BidOrAsk::Ask, 2), price: Some(Decimal::from(101)) },
+            EngineMessage::PlaceOrder{ pair: pair.clone(), order: order_for("buyer", BidOrAsk::Bid, 3), price: Some(Decimal::from(101)) },
+        ];
+
+        let run = |messages: Vec<EngineMessage>| {
+            let clock = SimulatedClock::new(Utc.timestamp_opt(1_700_000_000, 0).unwrap(), Duration::milliseconds(1));
+            let (mut engine, _msg_tx, resp_rx, db_rx, ws_rx) = MatchingEngine::with_sources(Box::new(clock), Box::new(SequentialIds::new()));
+            engine.add_market(pair.clone());
+            for user_id in ["buyer", "seller"] {
+                let balances = HashMap::from([("BTC".to_string(), Decimal::from(10)), ("USD".to_string(), Decimal::from(1000))]);
+                engine.add_user(user_id.to_string(), balances).unwrap();
+            }
+
+            for msg in messages {
+                engine.accept_message(msg);
+            }
+
+            let mut output: Vec<String> = resp_rx.try_iter().map(|response| format!("{:?}", response)).collect();
+            output.extend(ws_rx.try_iter().map(|event| event.to_json()));
+            output.extend(db_rx.try_iter().map(|message| format!("{:?}", message)));
+            output
+        };
+
+        let first = run(messages.clone());
+        assert_eq!(first, run(messages));
+
+        // Trade ids come from the input sequence number
+        assert!(first.iter().any(|line| line.contains(&Uuid::from_u64_pair(3, 0).to_string())));
+        assert!(first.iter().any(|line| line.contains("2023-11-14T22:13:20.002Z")));
+    }
+}
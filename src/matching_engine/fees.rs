@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use crate::matching_engine::types::{BidOrAsk, Trade};
+
+
+// Fractions of the traded amount, 0.001 is 0.1%. A negative maker rate is a rebate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeeRates{
+    pub maker: Decimal,
+    pub taker: Decimal,
+}
+
+// Fee rates of one market by user tier. A user pays the rates of the highest tier at or
+// below their own, and nothing when no tier is that low.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeeSchedule{
+    pub tiers: BTreeMap<u32, FeeRates>,
+}
+
+impl FeeSchedule{
+    // The same rates for every tier
+    pub fn flat(maker: Decimal, taker: Decimal) -> FeeSchedule{
+        FeeSchedule{ tiers: BTreeMap::from([(0, FeeRates{ maker, taker })]) }
+    }
+
+    pub fn rates(&self, tier: u32) -> FeeRates{
+        self.tiers.range(..=tier).next_back().map(|(_, rates)| *rates).unwrap_or_default()
+    }
+
+    // Takers always pay, and a maker rebate never exceeds what the taker pays
+    pub fn validate(&self) -> Result<(), String>{
+        for (tier, rates) in &self.tiers {
+            if rates.taker < Decimal::ZERO || rates.taker >= Decimal::ONE || rates.maker >= Decimal::ONE {
+                return Err(format!("Invalid fee rates for tier {}: maker {} taker {}", tier, rates.maker, rates.taker));
+            }
+            if rates.maker < Decimal::ZERO && self.tiers.values().any(|other| other.taker < -rates.maker) {
+                return Err(format!("Maker rebate {} of tier {} is larger than the lowest taker fee", rates.maker, tier));
+            }
+        }
+        Ok(())
+    }
+
+    // Fees of a trade, each charged in the asset the side receives: the buyer's in base,
+    // the seller's in quote. Fees round up and rebates round towards zero.
+    pub fn trade_fees(&self, trade: &Trade, buyer_tier: u32, seller_tier: u32, base_precision: u32, quote_precision: u32) -> (Decimal, Decimal){
+        let (buyer_rates, seller_rates) = (self.rates(buyer_tier), self.rates(seller_tier));
+        let (buyer_rate, seller_rate) = match trade.taker_side {
+            BidOrAsk::Bid => (buyer_rates.taker, seller_rates.maker),
+            BidOrAsk::Ask => (buyer_rates.maker, seller_rates.taker),
+        };
+
+        let round = |amount: Decimal, precision: u32| amount.round_dp_with_strategy(precision, RoundingStrategy::ToPositiveInfinity);
+        (
+            round(trade.quantity * buyer_rate, base_precision),
+            round(trade.quantity * trade.price * seller_rate, quote_precision),
+        )
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use uuid::Uuid;
+    use crate::matching_engine::types::{Order, TradingPair};
+
+    #[test]
+    fn test_fees_follow_tier_and_liquidity_side() {
+        let mut schedule = FeeSchedule::flat(Decimal::new(1, 3), Decimal::new(2, 3));
+        schedule.tiers.insert(3, FeeRates{ maker: Decimal::new(-5, 4), taker: Decimal::new(1, 3) });
+        assert_eq!(schedule.validate(), Ok(()));
+        assert_eq!(schedule.rates(2), schedule.rates(0));
+        assert_eq!(schedule.rates(7).maker, Decimal::new(-5, 4));
+
+        // A taker buy of 3 at 100.01
+        let pair = TradingPair::new("BTC".to_string(), "USD".to_string());
+        let buy = Order::new(BidOrAsk::Bid, Decimal::from(3));
+        let sell = Order::new(BidOrAsk::Ask, Decimal::from(3));
+        let trade = Trade::new(pair, &buy, &sell, Decimal::new(10001, 2), Decimal::from(3), Uuid::new_v4(), Utc::now());
+
+        // 3 * 0.002 BTC, and 300.03 * 0.001 = 0.30003 USD rounded up to cents
+        assert_eq!(schedule.trade_fees(&trade, 0, 0, 8, 2), (Decimal::new(6, 3), Decimal::new(31, 2)));
+        // A tier 3 maker gets 0.150015 back, rounded down to cents
+        assert_eq!(schedule.trade_fees(&trade, 0, 3, 8, 2), (Decimal::new(6, 3), Decimal::new(-15, 2)));
+
+        // The tier 3 rebate is more than tier 0 takers pay at 0.0004
+        schedule.tiers.insert(0, FeeRates{ maker: Decimal::ZERO, taker: Decimal::new(4, 4) });
+        assert!(schedule.validate().is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use crate::balance::{BalanceState, Reservation};
+use crate::balance::ledger::Account;
+use crate::matching_engine::{
+    orderbook::OrderBook,
+    stops::StopBook,
+    types::{BidOrAsk, Order, TradingPair},
+};
+
+
+// Something that should always hold about the money on the exchange but doesn't
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Violation{
+    pub asset: String,
+    // Set when the violation comes from an order on one market's book
+    pub pair: Option<TradingPair>,
+    pub message: String,
+}
+
+impl Violation{
+    pub fn new(asset: &str, pair: Option<&TradingPair>, message: String) -> Violation{
+        Violation{
+            asset: asset.to_string(),
+            pair: pair.cloned(),
+            message,
+        }
+    }
+
+    // Markets that can't be trusted to trade while this holds: the one the order rests on,
+    // or every market in the asset
+    pub fn affects(&self, pair: &TradingPair) -> bool{
+        match &self.pair {
+            Some(violating) => violating == pair,
+            None => pair.base == self.asset || pair.quote == self.asset,
+        }
+    }
+}
+
+
+// Check the balances on their own:
+// - no user account is negative
+// - the fee account is only negative by rebates: makers get them in the asset they receive,
+//   which can be a different one than the takers of the same trades paid their fees in
+// - every user's locked funds are exactly what their open orders and withdrawals reserved
+// - with `replay`, the balances still match a replay of the ledger since its checkpoint. That
+//   takes as long as the entries since the last snapshot, so it is left to full checks.
+pub fn check_balances(state: &BalanceState, replay: bool) -> Vec<Violation>{
+    let mut violations = Vec::new();
+    let ledger = state.ledger();
+
+    let mut assets = BTreeSet::new();
+    for (account, asset, balance) in ledger.balances() {
+        assets.insert(asset);
+        if account.user_id().is_some() && balance < Decimal::ZERO {
+            violations.push(Violation::new(asset, None, format!("{:?} holds {} {}", account, balance, asset)));
+        }
+    }
+
+    for asset in &assets {
+        let fees = ledger.balance(&Account::Fees, asset);
+        let rebated = ledger.rebates(asset);
+        if fees < -rebated {
+            violations.push(Violation::new(asset, None, format!("Fee account holds {} {} after paying {} in rebates", fees, asset, rebated)));
+        }
+    }
+
+    if replay && !ledger.is_consistent() {
+        for asset in &assets {
+            violations.push(Violation::new(asset, None, "Balances don't match the ledger entries".to_string()));
+        }
+    }
+
+    let mut reserved: HashMap<(&str, &str), Decimal> = HashMap::new();
+    for reservation in state.reservations().values() {
+        *reserved.entry((&reservation.user_id, &reservation.asset)).or_default() += reservation.amount;
+    }
+    for withdrawal in state.open_withdrawals() {
+        *reserved.entry((&withdrawal.user_id, &withdrawal.asset)).or_default() += withdrawal.amount;
+    }
+
+    let locked = ledger.balances().filter_map(|(account, asset, balance)| match account {
+        Account::Locked(user_id) => Some(((user_id.as_str(), asset), balance)),
+        _ => None,
+    });
+    let mut seen = HashSet::new();
+    for ((user_id, asset), balance) in locked {
+        seen.insert((user_id, asset));
+        let expected = reserved.get(&(user_id, asset)).copied().unwrap_or_default();
+        if balance != expected {
+            violations.push(Violation::new(asset, None, format!("User {} has {} {} locked but reserved {}", user_id, balance, asset, expected)));
+        }
+    }
+    for ((user_id, asset), expected) in &reserved {
+        if !seen.contains(&(*user_id, *asset)) && !expected.is_zero() {
+            violations.push(Violation::new(asset, None, format!("User {} reserved {} {} but has nothing locked", user_id, expected, asset)));
+        }
+    }
+
+    sort(&mut violations);
+    violations
+}
+
+// Check that every order on the books and stop books has reserved exactly what it can still
+// spend, and that no other reservation is left on these markets by an order that is gone.
+// Reservations of markets on other shards are theirs to check.
+pub fn check_reservations(
+    state: &BalanceState,
+    orderbooks: &HashMap<TradingPair, OrderBook>,
+    stop_books: &HashMap<TradingPair, StopBook>,
+) -> Vec<Violation>{
+    let mut violations = Vec::new();
+    let reservations = state.reservations();
+    let mut open = HashSet::new();
+
+    let mut check = |pair: &TradingPair, order: &Order, price: Decimal, violations: &mut Vec<Violation>| {
+        open.insert(order.id);
+        let (asset, amount) = match order.bid_or_ask {
+            BidOrAsk::Bid => (&pair.quote, price * order.size),
+            BidOrAsk::Ask => (&pair.base, order.size),
+        };
+
+        let expected = Reservation{ user_id: order.account(), pair: pair.clone(), asset: asset.clone(), amount };
+        match reservations.get(&order.id) {
+            Some(reserved) if *reserved == expected => {}
+            Some(reserved) => violations.push(Violation::new(asset, Some(pair), format!("Order {} reserved {} {} but needs {}", order.id, reserved.amount, reserved.asset, amount))),
+            None => violations.push(Violation::new(asset, Some(pair), format!("Order {} has nothing reserved", order.id))),
+        }
+    };
+
+    for (pair, orderbook) in orderbooks {
+        for (price, limit) in orderbook.bids.iter().chain(orderbook.asks.iter()) {
+            for order in limit.orders.iter() {
+                check(pair, order, *price, &mut violations);
+            }
+        }
+    }
+
+    // Stop-market buys hold the trigger price until they know their fill price
+    for (pair, stop_book) in stop_books {
+        for stop in stop_book.stops() {
+            check(pair, &stop.order, stop.limit_price.unwrap_or(stop.trigger_price), &mut violations);
+        }
+    }
+
+    let orphans = reservations.iter()
+        .filter(|(order_id, reservation)| orderbooks.contains_key(&reservation.pair) && !open.contains(*order_id));
+    for (order_id, reservation) in orphans {
+        let Reservation{ user_id, pair, asset, amount } = reservation;
+        violations.push(Violation::new(asset, Some(pair), format!("Order {} of {} is gone but still reserves {} {}", order_id, user_id, amount, asset)));
+    }
+
+    sort(&mut violations);
+    violations
+}
+
+// Hash map order would make reports differ between runs
+fn sort(violations: &mut [Violation]){
+    violations.sort_by(|a, b| (&a.asset, &a.message).cmp(&(&b.asset, &b.message)));
+}
+
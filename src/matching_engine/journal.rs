@@ -0,0 +1,167 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::matching_engine::messages::EngineMessage;
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry{
+    pub seq: u64,
+    // When the engine accepted the message; replays run at this time again
+    pub timestamp: DateTime<Utc>,
+    pub message: EngineMessage,
+}
+
+
+// Append-only log of every message the engine accepted, one JSON entry per line.
+// An entry is synced to disk before the engine acts on it, so replaying the
+// journal from the start rebuilds the exact same state.
+#[derive(Debug)]
+pub struct Journal{
+    path: PathBuf,
+    file: File,
+    last_seq: u64,
+    // Length of the half-written entry cut off the end when the journal was opened
+    torn_bytes: u64,
+}
+
+
+// What a restart found on disk, for the caller to report
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recovery{
+    // Sequence number of the snapshot the state was restored from
+    pub snapshot_seq: Option<u64>,
+    // Newer snapshot files that failed validation, with why
+    pub rejected_snapshots: Vec<String>,
+    // Journal entries applied on top of the snapshot, or of the initial state without one
+    pub replayed: usize,
+    // Bytes of half-written entries cut off the end of the journals
+    pub torn_bytes: u64,
+}
+
+impl Recovery{
+    // Add up the recoveries of several journals
+    pub fn merge(&mut self, other: Recovery){
+        self.snapshot_seq = self.snapshot_seq.max(other.snapshot_seq);
+        self.rejected_snapshots.extend(other.rejected_snapshots);
+        self.replayed += other.replayed;
+        self.torn_bytes += other.torn_bytes;
+    }
+}
+
+
+// Hands out journal sequence numbers. Engines that share balances share one sequencer, so
+// the numbers across all their journals give the order the messages arrived in. Taking a
+// number never waits for another engine.
+#[derive(Debug, Clone, Default)]
+pub struct Sequencer{
+    last_seq: Arc<AtomicU64>,
+}
+
+impl Sequencer{
+    pub fn new() -> Sequencer{
+        Sequencer::default()
+    }
+
+    pub fn next(&self) -> u64{
+        self.last_seq.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    // Carry on after `seq`, when recovery found it already used
+    pub fn advance_to(&self, seq: u64){
+        self.last_seq.fetch_max(seq, Ordering::SeqCst);
+    }
+}
+
+impl Journal{
+    // Open or create the journal at `path` and return the entries already in it.
+    // A half-written last line from a crash is cut off, everything before it is kept.
+    pub fn open(path: impl AsRef<Path>) -> Result<(Journal, Vec<JournalEntry>), String>{
+        let path = path.as_ref().to_path_buf();
+        let mut entries = Vec::new();
+        let mut valid_len = 0u64;
+
+        if path.exists() {
+            let file = File::open(&path).map_err(|e| format!("Failed to open journal {}: {}", path.display(), e))?;
+            let mut reader = BufReader::new(file);
+            let mut line = String::new();
+
+            loop {
+                line.clear();
+                let read = reader.read_line(&mut line).map_err(|e| format!("Failed to read journal: {}", e))?;
+                if read == 0 || !line.ends_with('\n') {
+                    break;
+                }
+
+                match serde_json::from_str::<JournalEntry>(&line) {
+                    Ok(entry) => entries.push(entry),
+                    // Only the last line can be torn; a bad entry with more after it is corruption
+                    Err(e) => {
+                        let mut rest = String::new();
+                        if reader.read_line(&mut rest).map_err(|e| format!("Failed to read journal: {}", e))? > 0 {
+                            return Err(format!("Corrupt journal entry after sequence {}: {}",
+                                entries.last().map(|entry: &JournalEntry| entry.seq).unwrap_or(0), e));
+                        }
+                        break;
+                    }
+                }
+
+                valid_len += read as u64;
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("Failed to open journal {}: {}", path.display(), e))?;
+
+        let len = file.metadata().map_err(|e| format!("Failed to read journal: {}", e))?.len();
+        let torn_bytes = len.saturating_sub(valid_len);
+        if torn_bytes > 0 {
+            file.set_len(valid_len).map_err(|e| format!("Failed to truncate journal: {}", e))?;
+        }
+
+        let last_seq = entries.last().map(|entry: &JournalEntry| entry.seq).unwrap_or(0);
+
+        Ok((Journal{ path, file, last_seq, torn_bytes }, entries))
+    }
+
+    pub fn path(&self) -> &Path{
+        &self.path
+    }
+
+    pub fn last_seq(&self) -> u64{
+        self.last_seq
+    }
+
+    pub fn torn_bytes(&self) -> u64{
+        self.torn_bytes
+    }
+
+    // Write the message durably under `seq`, which has to be past every entry already written
+    pub fn append(&mut self, seq: u64, message: &EngineMessage, timestamp: DateTime<Utc>) -> Result<(), String>{
+        if seq <= self.last_seq {
+            return Err(format!("Journal sequence {} is not after {}", seq, self.last_seq));
+        }
+
+        let entry = JournalEntry{
+            seq,
+            timestamp,
+            message: message.clone(),
+        };
+
+        let mut line = serde_json::to_string(&entry).map_err(|e| format!("Failed to encode journal entry: {}", e))?;
+        line.push('\n');
+
+        self.file.write_all(line.as_bytes()).map_err(|e| format!("Failed to write journal: {}", e))?;
+        self.file.sync_data().map_err(|e| format!("Failed to sync journal: {}", e))?;
+
+        self.last_seq = seq;
+        Ok(())
+    }
+}
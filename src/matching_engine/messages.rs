@@ -1,16 +1,120 @@
 use rust_decimal::Decimal;
-use crate::matching_engine::types::{Order, Trade, TradingPair};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::matching_engine::types::{Order, Trade, TradingPair, MarketStatus};
+use crate::matching_engine::orderbook::PreventedMatch;
+use crate::matching_engine::auction::AuctionPrice;
+use crate::matching_engine::invariants::Violation;
+use crate::matching_engine::spec::{MarketSpec, RejectReason};
+use crate::websocket::events::{OrderStatus, PriceLevel};
+use crate::wallet::{Deposit, Withdrawal};
+use crate::balance::transfer::InternalTransfer;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum EngineMessage {
     PlaceOrder {
         pair: TradingPair,
         order: Order,
-        price: Decimal,
+        // None for market orders
+        price: Option<Decimal>,
+    },
+    // Held back until the last trade price reaches `trigger_price`, then placed
+    // as a limit order at `limit_price` or as a market order when there is none
+    PlaceStopOrder {
+        pair: TradingPair,
+        order: Order,
+        trigger_price: Decimal,
+        limit_price: Option<Decimal>,
     },
     CancelOrder {
+        pair: TradingPair,
         order_id: uuid::Uuid,
-    },    
+    },
+    // Write a snapshot of the whole engine now; never journaled
+    TakeSnapshot,
+    // Change a resting order; `size` is the new open quantity
+    AmendOrder {
+        pair: TradingPair,
+        order_id: uuid::Uuid,
+        user_id: String,
+        price: Option<Decimal>,
+        size: Option<Decimal>,
+    },
+    // Open a new market, or reopen a delisted one with an empty book
+    ListMarket {
+        pair: TradingPair,
+        #[serde(default)]
+        spec: MarketSpec,
+    },
+    // Centre the price band of a market on `price` until the next trade moves it
+    SetReferencePrice {
+        pair: TradingPair,
+        price: Decimal,
+    },
+    // Halt, resume, set cancel-only or delist a market. Delisting cancels every order.
+    // Resuming a halted market whose spec has an auction starts a call auction.
+    SetMarketStatus {
+        pair: TradingPair,
+        status: MarketStatus,
+    },
+    // End the call auction of a market now and go back to continuous matching
+    Uncross {
+        pair: TradingPair,
+    },
+    // Fee tier a user pays from now on, in every market
+    SetFeeTier {
+        user_id: String,
+        tier: u32,
+    },
+    // Open an empty sub-account under a user
+    CreateSubAccount {
+        user_id: String,
+        name: String,
+    },
+    // Move available funds from one of the user's accounts to another one of theirs, or to
+    // another user's main account
+    Transfer {
+        transfer_id: uuid::Uuid,
+        user_id: String,
+        from_sub_account: Option<String>,
+        to_user_id: String,
+        to_sub_account: Option<String>,
+        asset: String,
+        amount: Decimal,
+    },
+    // Credit a deposit that has enough confirmations; each transaction is credited once
+    CreditDeposit {
+        deposit: Deposit,
+    },
+    // Hold `amount` for a withdrawal, which then waits for approval
+    RequestWithdrawal {
+        withdrawal_id: uuid::Uuid,
+        user_id: String,
+        asset: String,
+        amount: Decimal,
+        address: String,
+    },
+    ApproveWithdrawal {
+        withdrawal_id: uuid::Uuid,
+    },
+    // The withdrawal went out to the chain as `tx_hash`
+    WithdrawalBroadcast {
+        withdrawal_id: uuid::Uuid,
+        tx_hash: String,
+    },
+    // Enough confirmations: the held funds leave the exchange
+    ConfirmWithdrawal {
+        withdrawal_id: uuid::Uuid,
+        confirmations: u32,
+    },
+    // Give the held funds back; also how an operator rejects a withdrawal
+    FailWithdrawal {
+        withdrawal_id: uuid::Uuid,
+        reason: String,
+    },
+    // Check that balances and reservations add up, halting the markets of any violation.
+    // Debug builds do this after every message.
+    CheckInvariants,
 }
 
 #[derive(Debug, Clone)]
@@ -18,10 +122,76 @@ pub enum EngineResponse {
     OrderPlaced {
         order_id: uuid::Uuid,
         trades: Vec<Trade>,
+        status: OrderStatus,
+        filled_size: Decimal,
+        remaining_size: Decimal,
+        self_trade_prevented: Vec<PreventedMatch>,
+    },
+    OrderAmended {
+        order_id: uuid::Uuid,
+        trades: Vec<Trade>,
+        status: OrderStatus,
+        filled_size: Decimal,
+        remaining_size: Decimal,
+        self_trade_prevented: Vec<PreventedMatch>,
     },
     OrderCancelled {
         order_id: uuid::Uuid,
     },
+    // The order broke a rule of its market and never reached the book
+    OrderRejected {
+        order_id: uuid::Uuid,
+        reason: RejectReason,
+    },
+    SnapshotTaken {
+        seq: u64,
+        path: String,
+    },
+    // The book of a market changed, displayed quantity only
+    DepthUpdated {
+        pair: TradingPair,
+        bids: Vec<PriceLevel>,
+        asks: Vec<PriceLevel>,
+    },
+    MarketStatusChanged {
+        pair: TradingPair,
+        status: MarketStatus,
+    },
+    MarketSpecChanged {
+        pair: TradingPair,
+        spec: MarketSpec,
+    },
+    // Where a call auction would uncross right now; `auction` is None while nothing crosses
+    AuctionIndicative {
+        pair: TradingPair,
+        auction: Option<AuctionPrice>,
+        ends_at: Option<DateTime<Utc>>,
+    },
+    // A call auction ended; `price` is None when nothing crossed
+    AuctionUncrossed {
+        pair: TradingPair,
+        price: Option<Decimal>,
+        volume: Decimal,
+    },
+    SubAccountCreated {
+        user_id: String,
+        name: String,
+    },
+    // A transfer was carried out or rejected
+    TransferProcessed {
+        transfer: InternalTransfer,
+    },
+    DepositCredited {
+        deposit: Deposit,
+    },
+    // A withdrawal was requested or moved on; rejected requests come back failed
+    WithdrawalUpdated {
+        withdrawal: Withdrawal,
+    },
+    // Result of an invariant check, sent for every check asked for and whenever one fails
+    InvariantsChecked {
+        violations: Vec<Violation>,
+    },
     Error {
         message: String,
     },    
@@ -35,4 +205,10 @@ pub enum DatabaseMessage {
         trades: Vec<Trade>,
     },
     SaveOrder(Order),
+    UpdateOrderStatus {
+        order_id: uuid::Uuid,
+        status: OrderStatus,
+        filled_size: Decimal,
+        remaining_size: Decimal,
+    },
 }
\ No newline at end of file
@@ -1,4 +1,16 @@
 pub mod types;
 pub mod orderbook;
+pub mod queue;
+pub mod stops;
 pub mod engine;
-pub mod messages;
\ No newline at end of file
+pub mod messages;
+pub mod journal;
+pub mod snapshot;
+pub mod clock;
+pub mod router;
+pub mod spec;
+pub mod risk;
+pub mod auction;
+pub mod fees;
+pub mod invariants;
+pub mod simulation;
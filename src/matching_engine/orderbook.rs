@@ -1,63 +1,476 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use rust_decimal::Decimal;
-use crate::matching_engine::types::{Order, Limit, BidOrAsk, Trade};
+use uuid::Uuid;
+use crate::matching_engine::types::{Order, Limit, BidOrAsk, Trade, TimeInForce, TradingPair, SelfTradePrevention, MarketStatus};
+use crate::matching_engine::clock::{IdSource, RandomIds};
+use crate::matching_engine::spec::MarketSpec;
+use crate::matching_engine::risk::PriceGuard;
+use crate::matching_engine::auction::{AuctionPrice, equilibrium_price};
+use crate::websocket::events::PriceLevel;
+use chrono::{DateTime, Utc};
 
 
+// Where a resting order lives: which side, which price level and which queue slot
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrderLocation{
+    pub side: BidOrAsk,
+    pub price: Decimal,
+    pub slot: usize,
+}
+
+// A match between two orders of the same user that self-trade prevention stopped.
+// `maker` is the resting order as it stands afterwards.
+#[derive(Debug, Clone)]
+pub struct PreventedMatch{
+    pub mode: SelfTradePrevention,
+    pub maker: Order,
+    pub price: Decimal,
+    pub maker_removed: Decimal,
+    pub taker_removed: Decimal,
+    pub maker_cancelled: bool,
+    pub taker_cancelled: bool,
+}
+
+// Time, ids and settlement for the trades of one match, so the engine decides all three
+pub struct MatchContext<'a>{
+    pub timestamp: DateTime<Utc>,
+    pub ids: &'a mut dyn IdSource,
+    pub settlement: &'a mut dyn Settlement,
+}
+
+// Pays out each trade before the book changes for it. A trade that can't be settled
+// doesn't happen and the match stops there.
+pub trait Settlement{
+    fn settle(&mut self, trade: &mut Trade) -> Result<(), String>;
+}
+
+// For books that match without any balances behind them
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unsettled;
+
+impl Settlement for Unsettled{
+    fn settle(&mut self, _trade: &mut Trade) -> Result<(), String>{
+        Ok(())
+    }
+}
+
+// What matching needs to know about a book while one of its sides is borrowed
+#[derive(Clone, Copy)]
+struct BookRules<'a>{
+    pair: &'a TradingPair,
+    // Market-wide default for orders that don't pick a mode themselves
+    self_trade_prevention: SelfTradePrevention,
+    spec: &'a MarketSpec,
+}
+
+// Outcome of submitting an order: its trades, the order as it stands afterwards and whether it rests
+#[derive(Debug, Clone)]
+pub struct MatchResult{
+    pub trades: Vec<Trade>,
+    pub order: Order,
+    pub rested: bool,
+    pub prevented: Vec<PreventedMatch>,
+    // Why the next trade couldn't be settled, matching stopped before it
+    pub failed: Option<String>,
+}
+
+impl MatchResult{
+    pub fn untouched(order: Order) -> MatchResult{
+        MatchResult{
+            trades: Vec::new(),
+            order,
+            rested: false,
+            prevented: Vec::new(),
+            failed: None,
+        }
+    }
+
+    // Self-trade prevention cancelled what was left of the incoming order
+    pub fn taker_cancelled(&self) -> bool{
+        self.prevented.iter().any(|prevented| prevented.taker_cancelled)
+    }
+
+    fn can_continue(&self) -> bool{
+        self.order.has_remaining() && !self.taker_cancelled() && self.failed.is_none()
+    }
+}
+
 #[derive(Debug)]
 pub struct OrderBook{
+    pub pair: TradingPair,
     pub bids: BTreeMap<Decimal, Limit>,
     pub asks: BTreeMap<Decimal, Limit>,
+    // Market-wide default for orders that don't pick a mode themselves
+    pub self_trade_prevention: SelfTradePrevention,
+    // Price of the most recent trade, what stop orders trigger on
+    pub last_trade_price: Option<Decimal>,
+    pub status: MarketStatus,
+    pub spec: MarketSpec,
+    pub guard: PriceGuard,
+    // When the running call auction uncrosses on its own; only an explicit uncross ends it when unset
+    pub auction_ends_at: Option<DateTime<Utc>>,
+    index: HashMap<Uuid, OrderLocation>,
 }
 
 
 impl OrderBook{
-    pub fn new() -> OrderBook{
+    pub fn new(pair: TradingPair) -> OrderBook{
         OrderBook{
+            pair,
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
+            self_trade_prevention: SelfTradePrevention::None,
+            last_trade_price: None,
+            status: MarketStatus::Active,
+            spec: MarketSpec::default(),
+            guard: PriceGuard::default(),
+            auction_ends_at: None,
+            index: HashMap::new(),
         }
     }
 
 
-    pub fn add_order(&mut self, price: Decimal, mut order: Order) -> Vec<Trade> {
-        let mut trades = Vec::new();
+    pub fn add_order(&mut self, price: Decimal, order: Order) -> MatchResult {
+        self.add_order_with(&mut MatchContext{ timestamp: Utc::now(), ids: &mut RandomIds, settlement: &mut Unsettled }, price, order)
+    }
+
+    pub fn add_order_with(&mut self, ctx: &mut MatchContext, price: Decimal, order: Order) -> MatchResult {
+        // Orders only collect during a call auction, `uncross_with` matches them
+        if self.status == MarketStatus::Auction {
+            let rested = order.time_in_force.rests();
+            if rested {
+                self.rest_order(price, order.clone());
+            }
+            return MatchResult{ rested, ..MatchResult::untouched(order) };
+        }
+
+        match order.time_in_force {
+            TimeInForce::PostOnly if self.would_cross(order.bid_or_ask, price) => return MatchResult::untouched(order),
+            TimeInForce::Fok if !self.can_fill(&order, Some(price)) => return MatchResult::untouched(order),
+            _ => {}
+        }
+
+        let mut result = MatchResult::untouched(order);
         
-        match order.bid_or_ask {
+        match result.order.bid_or_ask {
             BidOrAsk::Bid => {
-                // Actually call the matching logic!
-                trades.extend(self.try_match_buy_order(&mut order, price));
-                
-                // Only add if there's remaining quantity
-                if order.size > Decimal::ZERO {
-                    let limit = self.bids.entry(price).or_insert_with(|| Limit::new(price));
-                    limit.add_order(order);
-                }
+                self.try_match_buy_order(ctx, &mut result, Some(price));
             },
             BidOrAsk::Ask => {
-                trades.extend(self.try_match_sell_order(&mut order, price));
-                
-                if order.size > Decimal::ZERO {
-                    let limit = self.asks.entry(price).or_insert_with(|| Limit::new(price));
-                    limit.add_order(order);
+                self.try_match_sell_order(ctx, &mut result, Some(price));
+            }
+        }
+
+        // Only add if there's remaining quantity and the order is allowed to rest
+        result.rested = result.order.size > Decimal::ZERO && result.order.time_in_force.rests() && !result.taker_cancelled() && result.failed.is_none();
+        if result.rested {
+            self.rest_order(price, result.order.clone());
+        }
+
+        self.record_last_trade(&result);
+        result
+    }
+
+    // Sweep the opposite side until the order is filled or the book runs out.
+    // Market orders never rest; whatever is left unfilled is dropped.
+    pub fn add_market_order(&mut self, order: Order) -> MatchResult {
+        self.add_market_order_with(&mut MatchContext{ timestamp: Utc::now(), ids: &mut RandomIds, settlement: &mut Unsettled }, order)
+    }
+
+    pub fn add_market_order_with(&mut self, ctx: &mut MatchContext, order: Order) -> MatchResult {
+        self.add_capped_market_order_with(ctx, order, None)
+    }
+
+    // A market order that stops sweeping at `worst_price`, used for price bands
+    pub fn add_capped_market_order_with(&mut self, ctx: &mut MatchContext, order: Order, worst_price: Option<Decimal>) -> MatchResult {
+        if order.time_in_force == TimeInForce::Fok && !self.can_fill(&order, worst_price) {
+            return MatchResult::untouched(order);
+        }
+
+        let mut result = MatchResult::untouched(order);
+
+        match result.order.bid_or_ask {
+            BidOrAsk::Bid => self.try_match_buy_order(ctx, &mut result, worst_price),
+            BidOrAsk::Ask => self.try_match_sell_order(ctx, &mut result, worst_price),
+        }
+
+        self.record_last_trade(&result);
+        result
+    }
+
+    // Price and volume the book would uncross at right now
+    pub fn indicative_price(&self) -> Option<AuctionPrice> {
+        equilibrium_price(&self.bids, &self.asks, self.guard.reference_price.or(self.last_trade_price))
+    }
+
+    // End a call auction: every order that crosses the equilibrium price trades at that one price.
+    // Bids take from the asks in price-time priority and keep their place in the queue when
+    // they are not filled. Returns the match of each bid with the price it rests at, up to
+    // the first one that couldn't settle a trade.
+    pub fn uncross_with(&mut self, ctx: &mut MatchContext) -> Vec<(Decimal, MatchResult)> {
+        let Some(auction) = self.indicative_price() else {
+            return Vec::new();
+        };
+
+        let mut results = Vec::new();
+        while let Some(bid_price) = self.best_bid().filter(|bid| *bid >= auction.price) {
+            if self.best_ask().is_none_or(|ask| ask > auction.price) {
+                break;
+            }
+
+            let (slot, bid) = self.bids[&bid_price].orders.front()
+                .map(|(slot, order)| (slot, order.clone()))
+                .expect("price levels are never empty");
+            let mut result = MatchResult::untouched(bid);
+            self.match_asks_at(ctx, &mut result, auction.price);
+
+            result.rested = result.can_continue();
+            let limit = self.bids.get_mut(&bid_price).expect("bid level is still there");
+            if result.rested {
+                let mut bid = result.order.clone();
+                bid.shrink_to(bid.size);
+                if bid.visible() == Decimal::ZERO {
+                    bid.refill();
+                }
+                if let Some(queued) = limit.orders.get_mut(slot) {
+                    *queued = bid;
+                }
+            } else {
+                limit.remove_order(slot);
+                if limit.orders.is_empty() {
+                    self.bids.remove(&bid_price);
                 }
+                self.index.remove(&result.order.id);
+            }
+
+            self.record_last_trade(&result);
+            let failed = result.failed.is_some();
+            results.push((bid_price, result));
+            if failed {
+                break;
             }
         }
-        
-        trades
+
+        results
+    }
+
+    fn record_last_trade(&mut self, result: &MatchResult) {
+        if let Some(trade) = result.trades.last() {
+            self.last_trade_price = Some(trade.price);
+        }
+    }
+
+    pub fn best_bid(&self) -> Option<Decimal> {
+        self.bids.keys().next_back().copied()
+    }
+
+    pub fn best_ask(&self) -> Option<Decimal> {
+        self.asks.keys().next().copied()
+    }
+
+    // Best `levels` prices on each side with their displayed quantity, best first
+    pub fn depth(&self, levels: usize) -> (Vec<PriceLevel>, Vec<PriceLevel>) {
+        let level = |(price, limit): (&Decimal, &Limit)| PriceLevel{ price: *price, quantity: limit.total_volume() };
+
+        let bids = self.bids.iter().rev().take(levels).map(level).collect();
+        let asks = self.asks.iter().take(levels).map(level).collect();
+        (bids, asks)
+    }
+
+    // Whether an order on `side` at `price` would take liquidity
+    pub fn would_cross(&self, side: BidOrAsk, price: Decimal) -> bool {
+        match side {
+            BidOrAsk::Bid => self.best_ask().is_some_and(|ask| ask <= price),
+            BidOrAsk::Ask => self.best_bid().is_some_and(|bid| bid >= price),
+        }
     }
 
-    fn try_match_buy_order(&mut self, buy_order: &mut Order, buy_price: Decimal) -> Vec<Trade> {
-        let mut trades = Vec::new();
+    // Whether the opposite side holds enough liquidity within `limit_price` to fill the whole order.
+    // Orders of the same user don't count: self-trade prevention removes them, or stops the
+    // incoming order before it's filled.
+    pub fn can_fill(&self, order: &Order, limit_price: Option<Decimal>) -> bool {
+        let levels: Box<dyn Iterator<Item = (&Decimal, &Limit)>> = match order.bid_or_ask {
+            BidOrAsk::Bid => Box::new(self.asks.iter()),
+            BidOrAsk::Ask => Box::new(self.bids.iter().rev()),
+        };
+
+        let stp = order.self_trade_prevention.unwrap_or(self.self_trade_prevention);
+        let mut remaining = order.quote_size.unwrap_or(order.size);
+        let worth = |volume: Decimal, price: Decimal| match order.quote_size {
+            Some(_) => volume * price,
+            None => volume,
+        };
+
+        for (price, limit) in levels {
+            let within_limit = match (order.bid_or_ask, limit_price) {
+                (_, None) => true,
+                (BidOrAsk::Bid, Some(limit_price)) => *price <= limit_price,
+                (BidOrAsk::Ask, Some(limit_price)) => *price >= limit_price,
+            };
+
+            if !within_limit || remaining <= Decimal::ZERO {
+                break;
+            }
+
+            // Hidden iceberg slices refill at the back of the level, so an order that stops
+            // the incoming one keeps them out of reach
+            let (mut shown, mut hidden) = (Decimal::ZERO, Decimal::ZERO);
+            for resting in limit.orders.iter() {
+                if resting.user_id == order.user_id && stp != SelfTradePrevention::None {
+                    if stp == SelfTradePrevention::CancelOldest {
+                        continue;
+                    }
+                    return remaining <= worth(shown, *price);
+                }
+                shown += resting.visible();
+                hidden += resting.size - resting.visible();
+            }
+
+            remaining -= worth(shown + hidden, *price);
+        }
+
+        remaining <= Decimal::ZERO
+    }
+
+    pub fn contains(&self, order_id: Uuid) -> bool {
+        self.index.contains_key(&order_id)
+    }
+
+    pub fn locate(&self, order_id: Uuid) -> Option<OrderLocation> {
+        self.index.get(&order_id).copied()
+    }
+
+    pub fn get_order(&self, order_id: Uuid) -> Option<&Order> {
+        let location = self.locate(order_id)?;
+        self.side(location.side).get(&location.price)?.orders.get(location.slot)
+    }
+
+    // Remove a resting order from the book, dropping its price level if it becomes empty
+    pub fn cancel_order(&mut self, order_id: Uuid) -> Option<Order> {
+        let location = self.index.remove(&order_id)?;
+        let side = match location.side {
+            BidOrAsk::Bid => &mut self.bids,
+            BidOrAsk::Ask => &mut self.asks,
+        };
+
+        let limit = side.get_mut(&location.price)?;
+        let order = limit.remove_order(location.slot);
+
+        if limit.orders.is_empty() {
+            side.remove(&location.price);
+        }
+
+        order
+    }
+
+    // Take every resting order off the book, bids first, each level in queue order
+    pub fn clear(&mut self) -> Vec<Order> {
+        self.index.clear();
+        let bids = std::mem::take(&mut self.bids);
+        let asks = std::mem::take(&mut self.asks);
+
+        bids.into_values().rev()
+            .chain(asks.into_values())
+            .flat_map(|limit| limit.orders.iter().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+
+    // Base and quote a market order would consume if it swept the book right now
+    pub fn market_sweep(&self, order: &Order) -> (Decimal, Decimal) {
+        let levels: Box<dyn Iterator<Item = (&Decimal, &Limit)>> = match order.bid_or_ask {
+            BidOrAsk::Bid => Box::new(self.asks.iter()),
+            BidOrAsk::Ask => Box::new(self.bids.iter().rev()),
+        };
+
+        let (mut base, mut quote) = (Decimal::ZERO, Decimal::ZERO);
+        let mut remaining = order.quote_size.unwrap_or(order.size);
+
+        for (price, limit) in levels {
+            for resting in limit.orders.iter() {
+                let quantity = match order.quote_size {
+                    Some(_) => affordable_quantity(remaining, *price, &self.spec),
+                    None => remaining,
+                };
+
+                // Same rule as matching: once the budget runs out at an order, it's spent
+                if order.quote_size.is_some() && quantity <= resting.size {
+                    return (base + quantity, quote + quantity * price);
+                }
+
+                let quantity = quantity.min(resting.size);
+                base += quantity;
+                quote += quantity * price;
+                remaining -= match order.quote_size {
+                    Some(_) => quantity * price,
+                    None => quantity,
+                };
+
+                if remaining <= Decimal::ZERO {
+                    return (base, quote);
+                }
+            }
+        }
+
+        (base, quote)
+    }
+
+    // Shrink a resting order in place so it keeps its queue position
+    pub fn reduce_order(&mut self, order_id: Uuid, new_size: Decimal) -> bool {
+        let Some(location) = self.locate(order_id) else {
+            return false;
+        };
+
+        let side = match location.side {
+            BidOrAsk::Bid => &mut self.bids,
+            BidOrAsk::Ask => &mut self.asks,
+        };
+
+        match side.get_mut(&location.price).and_then(|limit| limit.orders.get_mut(location.slot)) {
+            Some(order) if new_size > Decimal::ZERO && new_size <= order.size => {
+                order.shrink_to(new_size);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn rest_order(&mut self, price: Decimal, mut order: Order) {
+        order.refill();
+        self.restore_order(price, order);
+    }
+
+    // Put an order back at the end of its price level exactly as it is, iceberg slice included.
+    // Used to rebuild a book from a snapshot in queue order.
+    pub fn restore_order(&mut self, price: Decimal, order: Order) {
+        let order_id = order.id;
+        let side = order.bid_or_ask;
+        let limit = match side {
+            BidOrAsk::Bid => self.bids.entry(price).or_insert_with(|| Limit::new(price)),
+            BidOrAsk::Ask => self.asks.entry(price).or_insert_with(|| Limit::new(price)),
+        };
+
+        let slot = limit.add_order(order);
+        self.index.insert(order_id, OrderLocation{ side, price, slot });
+    }
+
+    fn side(&self, side: BidOrAsk) -> &BTreeMap<Decimal, Limit> {
+        match side {
+            BidOrAsk::Bid => &self.bids,
+            BidOrAsk::Ask => &self.asks,
+        }
+    }
+
+    fn try_match_buy_order(&mut self, ctx: &mut MatchContext, result: &mut MatchResult, buy_price: Option<Decimal>) {
+        let rules = BookRules{ pair: &self.pair, self_trade_prevention: self.self_trade_prevention, spec: &self.spec };
         let mut prices_to_remove = Vec::new();
         
         // BTreeMap already iterates in sorted order (lowest to highest)
         for (ask_price, limit) in self.asks.iter_mut() {
-            if buy_price < *ask_price || buy_order.size == Decimal::ZERO {
+            if buy_price.is_some_and(|price| price < *ask_price) || !result.can_continue() {
                 break;
             }
             
-            let trade_result = OrderBook::match_orders_at_price(buy_order, limit, *ask_price);
-            trades.extend(trade_result);
+            OrderBook::match_orders_at_price(rules, ctx, result, limit, *ask_price, &mut self.index);
             
             if limit.orders.is_empty() {
                 prices_to_remove.push(*ask_price);
@@ -67,72 +480,472 @@ impl OrderBook{
         for price in prices_to_remove {
             self.asks.remove(&price);
         }
-        
-        trades
     }
 
-    fn try_match_sell_order(&mut self, sell_order: &mut Order, sell_price: Decimal) -> Vec<Trade> {
-        let mut trades = Vec::new();
+    // Match a bid against every ask up to `price`, all of it trading at `price`
+    fn match_asks_at(&mut self, ctx: &mut MatchContext, result: &mut MatchResult, price: Decimal) {
+        let rules = BookRules{ pair: &self.pair, self_trade_prevention: self.self_trade_prevention, spec: &self.spec };
+        let mut prices_to_remove = Vec::new();
+
+        for (ask_price, limit) in self.asks.range_mut(..=price) {
+            if !result.can_continue() {
+                break;
+            }
+
+            OrderBook::match_orders_at_price(rules, ctx, result, limit, price, &mut self.index);
+
+            if limit.orders.is_empty() {
+                prices_to_remove.push(*ask_price);
+            }
+        }
+
+        for ask_price in prices_to_remove {
+            self.asks.remove(&ask_price);
+        }
+    }
+
+    fn try_match_sell_order(&mut self, ctx: &mut MatchContext, result: &mut MatchResult, sell_price: Option<Decimal>) {
+        let rules = BookRules{ pair: &self.pair, self_trade_prevention: self.self_trade_prevention, spec: &self.spec };
         let mut prices_to_remove = Vec::new();
         
-        let mut bid_prices: Vec<Decimal> = self.bids.keys().cloned().collect();
-        bid_prices.sort_by(|a, b| b.cmp(a));
-        
-        for bid_price in bid_prices {
-            if sell_price > bid_price || sell_order.size == Decimal::ZERO {
+        // Best bid first (highest to lowest)
+        for (bid_price, limit) in self.bids.iter_mut().rev() {
+            if sell_price.is_some_and(|price| price > *bid_price) || !result.can_continue() {
                 break;
             }
             
-            if let Some(limit) = self.bids.get_mut(&bid_price) {
-                let trade_result = OrderBook::match_orders_at_price(sell_order, limit, bid_price);
-                trades.extend(trade_result);
-                
-                if limit.orders.is_empty() {
-                    prices_to_remove.push(bid_price);
-                }
+            OrderBook::match_orders_at_price(rules, ctx, result, limit, *bid_price, &mut self.index);
+            
+            if limit.orders.is_empty() {
+                prices_to_remove.push(*bid_price);
             }
         }
 
         for price in prices_to_remove {
             self.bids.remove(&price);
         }
-        
-        trades
     }
 
 
 
-    fn match_orders_at_price(incoming_order: &mut Order, limit: &mut Limit, price: Decimal) -> Vec<Trade>{
-        let mut trades = Vec::new();
-        let mut orders_to_remove = Vec::new();
-
-        for (i, existing_order) in limit.orders.iter_mut().enumerate() {
-            if incoming_order.size == Decimal::ZERO{
+    fn match_orders_at_price(
+        rules: BookRules,
+        ctx: &mut MatchContext,
+        result: &mut MatchResult,
+        limit: &mut Limit,
+        price: Decimal,
+        index: &mut HashMap<Uuid, OrderLocation>,
+    ){
+        while result.can_continue() {
+            let incoming_order = &mut result.order;
+            let Some((slot, existing_order)) = limit.orders.front_mut() else {
                 break;
-            }
+            };
+
+            let stp = incoming_order.self_trade_prevention.unwrap_or(rules.self_trade_prevention);
+            if existing_order.user_id == incoming_order.user_id && stp != SelfTradePrevention::None {
+                let prevented = prevent_self_trade(stp, incoming_order, existing_order, price, rules.spec);
+                if prevented.maker_cancelled {
+                    limit.remove_order(slot);
+                    index.remove(&prevented.maker.id);
+                }
 
-            let trade_quantity = incoming_order.size.min(existing_order.size);
+                result.prevented.push(prevented);
+                continue;
+            }
 
-            let (buyer_id, seller_id) = match incoming_order.bid_or_ask {
-                BidOrAsk::Bid => (incoming_order.id, existing_order.id),
-                BidOrAsk::Ask => (existing_order.id, incoming_order.id),
+            // Icebergs trade one shown slice at a time
+            let available = existing_order.visible();
+            let trade_quantity = match incoming_order.quote_size {
+                Some(quote_size) => affordable_quantity(quote_size, price, rules.spec).min(available),
+                None => incoming_order.size.min(available),
             };
 
-            trades.push(Trade::new(buyer_id, seller_id, price, trade_quantity ));
+            // What is left of the budget doesn't buy a single step
+            if trade_quantity.is_zero() {
+                incoming_order.quote_size = Some(Decimal::ZERO);
+                break;
+            }
 
-            incoming_order.size -= trade_quantity;
+            let trade_id = ctx.ids.next_id();
+            let mut trade = Trade::new(rules.pair.clone(), incoming_order, existing_order, price, trade_quantity, trade_id, ctx.timestamp);
+            if let Err(message) = ctx.settlement.settle(&mut trade) {
+                result.failed = Some(message);
+                break;
+            }
+            result.trades.push(trade);
+
+            incoming_order.quote_size = match incoming_order.quote_size {
+                // The budget ran out at this order, don't carry rounding dust forward
+                Some(quote_size) if affordable_quantity(quote_size, price, rules.spec) <= available => Some(Decimal::ZERO),
+                Some(quote_size) => Some(quote_size - trade_quantity * price),
+                None => {
+                    incoming_order.size -= trade_quantity;
+                    None
+                }
+            };
             existing_order.size -= trade_quantity;
+            if existing_order.display_size.is_some() {
+                existing_order.visible_size -= trade_quantity;
+            }
+            incoming_order.filled_size += trade_quantity;
+            existing_order.filled_size += trade_quantity;
 
             if existing_order.size == Decimal::ZERO{
-                orders_to_remove.push(i);
+                let filled_id = existing_order.id;
+                limit.remove_order(slot);
+                index.remove(&filled_id);
+            } else if existing_order.visible() == Decimal::ZERO {
+                // The shown slice is used up: show the next one at the back of the queue
+                let mut refilled = limit.remove_order(slot).expect("front order is queued");
+                refilled.refill();
+                let refilled_id = refilled.id;
+                let new_slot = limit.add_order(refilled);
+                if let Some(location) = index.get_mut(&refilled_id) {
+                    location.slot = new_slot;
+                }
             }
         }
+    }
+}
+
+// Apply a self-trade prevention mode to an incoming order that met a resting order of the same user
+fn prevent_self_trade(mode: SelfTradePrevention, taker: &mut Order, maker: &mut Order, price: Decimal, spec: &MarketSpec) -> PreventedMatch {
+    let taker_remaining = match taker.quote_size {
+        Some(quote_size) => affordable_quantity(quote_size, price, spec),
+        None => taker.size,
+    };
+
+    let (maker_removed, taker_removed) = match mode {
+        SelfTradePrevention::CancelNewest | SelfTradePrevention::None => (Decimal::ZERO, taker_remaining),
+        SelfTradePrevention::CancelOldest => (maker.size, Decimal::ZERO),
+        SelfTradePrevention::CancelBoth => (maker.size, taker_remaining),
+        // Shrink both by the smaller size, whichever runs out is cancelled
+        SelfTradePrevention::DecrementAndCancel => {
+            let quantity = taker_remaining.min(maker.size);
+            (quantity, quantity)
+        }
+    };
 
-        for &index in orders_to_remove.iter().rev(){
-            limit.orders.remove(index);
+    let maker_cancelled = maker_removed == maker.size;
+    let taker_cancelled = match mode {
+        SelfTradePrevention::CancelOldest => false,
+        SelfTradePrevention::DecrementAndCancel => taker_removed == taker_remaining,
+        _ => true,
+    };
+
+    maker.shrink_to(maker.size - maker_removed);
+    if !taker_cancelled {
+        match taker.quote_size.as_mut() {
+            Some(quote_size) => *quote_size -= taker_removed * price,
+            None => taker.size -= taker_removed,
         }
+    }
 
-        trades
+    PreventedMatch{
+        mode,
+        maker: maker.clone(),
+        price,
+        maker_removed,
+        taker_removed,
+        maker_cancelled,
+        taker_cancelled,
     }
 }
 
+// Largest quantity on the market's step whose cost at `price` stays within `budget`,
+// even after rounding
+fn affordable_quantity(budget: Decimal, price: Decimal, spec: &MarketSpec) -> Decimal {
+    let quantity = budget / price;
+    let quantity = if quantity * price > budget {
+        quantity - Decimal::new(1, quantity.scale())
+    } else {
+        quantity
+    };
+    spec.floor_quantity(quantity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_order_removes_it_from_the_book() {
+        let mut orderbook = OrderBook::new(TradingPair::new("BTC".to_string(), "USD".to_string()));
+
+        let resting = Order::new(BidOrAsk::Ask, Decimal::from(2));
+        let resting_id = resting.id;
+        orderbook.add_order(Decimal::from(100), resting);
+
+        let cancelled = orderbook.cancel_order(resting_id).unwrap();
+        assert_eq!(cancelled.size, Decimal::from(2));
+        assert!(orderbook.asks.is_empty());
+
+        // Already gone, nothing left to cancel or fill against
+        assert!(orderbook.cancel_order(resting_id).is_none());
+        let trades = orderbook.add_order(Decimal::from(100), Order::new(BidOrAsk::Bid, Decimal::from(1))).trades;
+        assert!(trades.is_empty());
+    }
+
+    #[test]
+    fn test_index_tracks_fills_and_cancels_from_the_middle() {
+        let mut orderbook = OrderBook::new(TradingPair::new("BTC".to_string(), "USD".to_string()));
+        let price = Decimal::from(100);
+
+        let orders: Vec<Order> = (0..3).map(|_| Order::new(BidOrAsk::Ask, Decimal::from(1))).collect();
+        let ids: Vec<Uuid> = orders.iter().map(|o| o.id).collect();
+        for order in orders {
+            orderbook.add_order(price, order);
+        }
+
+        // Removing the middle order keeps the other two in time priority
+        assert!(orderbook.cancel_order(ids[1]).is_some());
+        assert_eq!(orderbook.asks[&price].orders.len(), 2);
+
+        let trades = orderbook.add_order(price, Order::new(BidOrAsk::Bid, Decimal::from(1))).trades;
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].seller_order_id, ids[0]);
+        assert!(!orderbook.contains(ids[0]));
+
+        let location = orderbook.locate(ids[2]).unwrap();
+        assert_eq!(location.side, BidOrAsk::Ask);
+        assert_eq!(location.price, price);
+        assert_eq!(orderbook.get_order(ids[2]).unwrap().id, ids[2]);
+    }
+
+    #[test]
+    fn test_market_orders_sweep_and_never_rest() {
+        let mut orderbook = OrderBook::new(TradingPair::new("BTC".to_string(), "USD".to_string()));
+        orderbook.add_order(Decimal::from(100), Order::new(BidOrAsk::Ask, Decimal::from(1)));
+        orderbook.add_order(Decimal::from(110), Order::new(BidOrAsk::Ask, Decimal::from(1)));
+
+        let trades = orderbook.add_market_order(Order::new_market(BidOrAsk::Bid, Decimal::from(3))).trades;
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[1].price, Decimal::from(110));
+        assert!(orderbook.asks.is_empty());
+        assert!(orderbook.bids.is_empty());
+
+        // Spending 150 quote against 2 @ 100 fills 1.5 and leaves 0.5 resting
+        orderbook.add_order(Decimal::from(100), Order::new(BidOrAsk::Ask, Decimal::from(2)));
+        let trades = orderbook.add_market_order(Order::new_quote_market(BidOrAsk::Bid, Decimal::from(150))).trades;
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].quantity, Decimal::new(15, 1));
+        assert_eq!(orderbook.asks[&Decimal::from(100)].total_volume(), Decimal::new(5, 1));
+    }
+
+    #[test]
+    fn test_quote_sized_orders_trade_on_the_step() {
+        let mut orderbook = OrderBook::new(TradingPair::new("BTC".to_string(), "USD".to_string()));
+        orderbook.spec = MarketSpec{ step_size: Decimal::new(1, 2), base_precision: 2, ..MarketSpec::default() };
+        orderbook.add_order(Decimal::from(3), Order::new(BidOrAsk::Ask, Decimal::from(10)));
+
+        // 10 at 3 would be 3.333..., the step allows 3.33
+        let order = Order::new_quote_market(BidOrAsk::Bid, Decimal::from(10));
+        assert_eq!(orderbook.market_sweep(&order), (Decimal::new(333, 2), Decimal::new(999, 2)));
+        let result = orderbook.add_market_order(order);
+        assert_eq!(result.trades.len(), 1);
+        assert_eq!(result.trades[0].quantity, Decimal::new(333, 2));
+        assert_eq!(orderbook.asks[&Decimal::from(3)].total_volume(), Decimal::new(667, 2));
+
+        // A budget below one step's cost doesn't trade
+        let result = orderbook.add_market_order(Order::new_quote_market(BidOrAsk::Bid, Decimal::new(2, 2)));
+        assert!(result.trades.is_empty() && !result.order.has_remaining());
+    }
+
+    #[test]
+    fn test_time_in_force() {
+        let mut orderbook = OrderBook::new(TradingPair::new("BTC".to_string(), "USD".to_string()));
+        let price = Decimal::from(100);
+        orderbook.add_order(price, Order::new(BidOrAsk::Ask, Decimal::from(1)));
+
+        // FOK that can't fill completely leaves the book untouched
+        let mut fok = Order::new(BidOrAsk::Bid, Decimal::from(2));
+        fok.time_in_force = TimeInForce::Fok;
+        assert!(orderbook.add_order(price, fok).trades.is_empty());
+        assert_eq!(orderbook.asks[&price].total_volume(), Decimal::from(1));
+
+        // Post-only that would take does nothing
+        let mut post_only = Order::new(BidOrAsk::Bid, Decimal::from(1));
+        post_only.time_in_force = TimeInForce::PostOnly;
+        assert!(orderbook.add_order(price, post_only).trades.is_empty());
+        assert!(orderbook.bids.is_empty());
+
+        // IOC fills what it can and the rest is cancelled
+        let mut ioc = Order::new(BidOrAsk::Bid, Decimal::from(2));
+        ioc.time_in_force = TimeInForce::Ioc;
+        let ioc_id = ioc.id;
+        let result = orderbook.add_order(price, ioc);
+        assert_eq!(result.trades.len(), 1);
+        assert_eq!(result.order.size, Decimal::from(1));
+        assert!(!result.rested);
+        assert!(!orderbook.contains(ioc_id));
+        assert!(orderbook.asks.is_empty() && orderbook.bids.is_empty());
+    }
+
+    #[test]
+    fn test_self_trade_prevention() {
+        let mut orderbook = OrderBook::new(TradingPair::new("BTC".to_string(), "USD".to_string()));
+        orderbook.self_trade_prevention = SelfTradePrevention::CancelNewest;
+        let price = Decimal::from(100);
+
+        let resting = Order::new(BidOrAsk::Ask, Decimal::from(2));
+        let resting_id = resting.id;
+        orderbook.add_order(price, resting);
+
+        // Market default: the incoming order is cancelled and doesn't rest
+        let result = orderbook.add_order(price, Order::new(BidOrAsk::Bid, Decimal::from(1)));
+        assert!(result.trades.is_empty());
+        assert!(result.taker_cancelled() && !result.rested);
+        assert!(orderbook.contains(resting_id) && orderbook.bids.is_empty());
+
+        // Decrement and cancel shrinks the resting order by the smaller size
+        let mut decrement = Order::new(BidOrAsk::Bid, Decimal::from(1));
+        decrement.self_trade_prevention = Some(SelfTradePrevention::DecrementAndCancel);
+        let result = orderbook.add_order(price, decrement);
+        assert!(result.taker_cancelled() && !result.prevented[0].maker_cancelled);
+        assert_eq!(orderbook.get_order(resting_id).unwrap().size, Decimal::from(1));
+
+        // Cancel oldest removes the resting order and the incoming one rests instead
+        let mut oldest = Order::new(BidOrAsk::Bid, Decimal::from(3));
+        oldest.self_trade_prevention = Some(SelfTradePrevention::CancelOldest);
+        let oldest_id = oldest.id;
+        let result = orderbook.add_order(price, oldest);
+        assert!(result.prevented[0].maker_cancelled && result.rested);
+        assert!(!orderbook.contains(resting_id) && orderbook.asks.is_empty());
+
+        // Other users still trade against it
+        let mut other = Order::new(BidOrAsk::Ask, Decimal::from(1));
+        other.user_id = "other".to_string();
+        let result = orderbook.add_order(price, other);
+        assert_eq!(result.trades.len(), 1);
+        assert!(result.prevented.is_empty());
+        assert_eq!(orderbook.get_order(oldest_id).unwrap().size, Decimal::from(2));
+    }
+
+    #[test]
+    fn test_fill_or_kill_skips_liquidity_of_the_same_user() {
+        let mut orderbook = OrderBook::new(TradingPair::new("BTC".to_string(), "USD".to_string()));
+        orderbook.self_trade_prevention = SelfTradePrevention::CancelOldest;
+        let price = Decimal::from(100);
+
+        let own = Order::new(BidOrAsk::Ask, Decimal::from(2));
+        let own_id = own.id;
+        orderbook.add_order(price, own);
+        let mut other = Order::new(BidOrAsk::Ask, Decimal::from(1));
+        other.user_id = "other".to_string();
+        let other_id = other.id;
+        orderbook.add_order(price, other);
+
+        // Three on the book, but the user's own two would be cancelled rather than traded
+        let mut fok = Order::new(BidOrAsk::Bid, Decimal::from(2));
+        fok.time_in_force = TimeInForce::Fok;
+        let result = orderbook.add_order(price, fok);
+        assert!(result.trades.is_empty() && result.prevented.is_empty());
+        assert!(orderbook.contains(other_id) && orderbook.contains(own_id));
+
+        let mut fok = Order::new(BidOrAsk::Bid, Decimal::from(1));
+        fok.time_in_force = TimeInForce::Fok;
+        let result = orderbook.add_order(price, fok);
+        assert_eq!(result.trades.len(), 1);
+        assert!(result.prevented[0].maker_cancelled && orderbook.asks.is_empty());
+
+        // Cancel newest stops the incoming order at the user's own order, even with liquidity behind it
+        let mut other = Order::new(BidOrAsk::Ask, Decimal::from(1));
+        other.user_id = "other".to_string();
+        orderbook.add_order(price, Order::new(BidOrAsk::Ask, Decimal::from(1)));
+        orderbook.add_order(price, other);
+        let mut fok = Order::new(BidOrAsk::Bid, Decimal::from(1));
+        fok.time_in_force = TimeInForce::Fok;
+        fok.self_trade_prevention = Some(SelfTradePrevention::CancelNewest);
+        let result = orderbook.add_order(price, fok);
+        assert!(result.trades.is_empty() && result.prevented.is_empty());
+        assert_eq!(orderbook.asks[&price].orders.len(), 2);
+    }
+
+    #[test]
+    fn test_iceberg_shows_a_slice_and_refills_at_the_back() {
+        let mut orderbook = OrderBook::new(TradingPair::new("BTC".to_string(), "USD".to_string()));
+        let price = Decimal::from(100);
+
+        let iceberg = Order::new_iceberg(BidOrAsk::Ask, Decimal::from(10), Decimal::from(2));
+        let iceberg_id = iceberg.id;
+        orderbook.add_order(price, iceberg);
+        let behind = Order::new(BidOrAsk::Ask, Decimal::from(1));
+        let behind_id = behind.id;
+        orderbook.add_order(price, behind);
+
+        assert_eq!(orderbook.asks[&price].total_volume(), Decimal::from(3));
+        assert_eq!(orderbook.depth(5).1[0].quantity, Decimal::from(3));
+
+        // Using up the slice sends the refilled iceberg behind the other order
+        let trades = orderbook.add_order(price, Order::new(BidOrAsk::Bid, Decimal::from(2))).trades;
+        assert_eq!(trades.len(), 1);
+        let front = orderbook.asks[&price].orders.front().unwrap().1;
+        assert_eq!(front.id, behind_id);
+        assert_eq!(orderbook.get_order(iceberg_id).unwrap().visible(), Decimal::from(2));
+        assert_eq!(orderbook.asks[&price].total_volume(), Decimal::from(3));
+
+        // A sweep still takes the hidden quantity, slice by slice
+        let mut sweep = Order::new(BidOrAsk::Bid, Decimal::from(9));
+        sweep.time_in_force = TimeInForce::Fok;
+        let result = orderbook.add_order(price, sweep);
+        assert_eq!(result.order.filled_size, Decimal::from(9));
+        assert_eq!(result.trades[0].maker_order_id, behind_id);
+        assert_eq!(result.trades.len(), 5);
+        assert!(orderbook.asks.is_empty());
+    }
+
+    #[test]
+    fn test_auction_collects_orders_and_uncrosses_at_one_price() {
+        let mut orderbook = OrderBook::new(TradingPair::new("BTC".to_string(), "USD".to_string()));
+        orderbook.status = MarketStatus::Auction;
+
+        for (side, price, size) in [
+            (BidOrAsk::Bid, 102, 10), (BidOrAsk::Bid, 101, 15), (BidOrAsk::Bid, 100, 20),
+            (BidOrAsk::Ask, 99, 12), (BidOrAsk::Ask, 100, 10), (BidOrAsk::Ask, 101, 18), (BidOrAsk::Ask, 103, 5),
+        ] {
+            let result = orderbook.add_order(Decimal::from(price), Order::new(side, Decimal::from(size)));
+            assert!(result.rested && result.trades.is_empty());
+        }
+
+        let auction = orderbook.indicative_price().unwrap();
+        assert_eq!((auction.price, auction.volume), (Decimal::from(101), Decimal::from(25)));
+
+        // 102 takes 10 of the 99s, then 101 takes the last 2 at 99, all 10 at 100 and 3 at 101
+        let results = orderbook.uncross_with(&mut MatchContext{ timestamp: Utc::now(), ids: &mut RandomIds, settlement: &mut Unsettled });
+        let trades: Vec<&Trade> = results.iter().flat_map(|(_, result)| &result.trades).collect();
+        let sizes: Vec<Decimal> = trades.iter().map(|trade| trade.quantity).collect();
+        assert_eq!(sizes, [10, 2, 10, 3].map(Decimal::from));
+        assert!(trades.iter().all(|trade| trade.price == Decimal::from(101)));
+        assert_eq!(results.iter().map(|(price, _)| *price).collect::<Vec<_>>(), [Decimal::from(102), Decimal::from(101)]);
+
+        let (bids, asks) = orderbook.depth(5);
+        assert_eq!(bids.iter().map(|level| (level.price, level.quantity)).collect::<Vec<_>>(), [(Decimal::from(100), Decimal::from(20))]);
+        assert_eq!(asks.iter().map(|level| (level.price, level.quantity)).collect::<Vec<_>>(),
+            [(Decimal::from(101), Decimal::from(15)), (Decimal::from(103), Decimal::from(5))]);
+        assert_eq!(orderbook.last_trade_price, Some(Decimal::from(101)));
+        assert!(orderbook.indicative_price().is_none());
+    }
+
+    #[test]
+    fn test_partly_filled_auction_bid_keeps_its_place() {
+        let mut orderbook = OrderBook::new(TradingPair::new("BTC".to_string(), "USD".to_string()));
+        orderbook.status = MarketStatus::Auction;
+        let price = Decimal::from(100);
+
+        let first = Order::new(BidOrAsk::Bid, Decimal::from(10));
+        let first_id = first.id;
+        orderbook.add_order(price, first);
+        orderbook.add_order(price, Order::new(BidOrAsk::Bid, Decimal::from(5)));
+        orderbook.add_order(price, Order::new(BidOrAsk::Ask, Decimal::from(4)));
+
+        let results = orderbook.uncross_with(&mut MatchContext{ timestamp: Utc::now(), ids: &mut RandomIds, settlement: &mut Unsettled });
+        assert_eq!(results.len(), 1);
+        assert!(results[0].1.rested);
+
+        let (_, front) = orderbook.bids[&price].orders.front().unwrap();
+        assert_eq!((front.id, front.size, front.filled_size), (first_id, Decimal::from(6), Decimal::from(4)));
+        assert_eq!(orderbook.get_order(first_id).unwrap().size, Decimal::from(6));
+        assert!(orderbook.asks.is_empty());
+    }
+}
@@ -0,0 +1,116 @@
+use crate::matching_engine::types::Order;
+
+
+// Doubly linked list stored in a slab. A slot stays valid for as long as its order
+// is queued, so an order can be unlinked from the middle in O(1) without
+// disturbing the time priority of the orders around it.
+#[derive(Debug, Clone)]
+struct Node{
+    order: Order,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OrderQueue{
+    nodes: Vec<Option<Node>>,
+    free_slots: Vec<usize>,
+    head: Option<usize>,
+    tail: Option<usize>,
+    len: usize,
+}
+
+impl OrderQueue{
+    pub fn new() -> OrderQueue{
+        OrderQueue::default()
+    }
+
+    pub fn len(&self) -> usize{
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.len == 0
+    }
+
+    // Append an order at the back of the queue and return the slot it lives in
+    pub fn push_back(&mut self, order: Order) -> usize{
+        let node = Node{
+            order,
+            prev: self.tail,
+            next: None,
+        };
+
+        let slot = match self.free_slots.pop(){
+            Some(slot) => {
+                self.nodes[slot] = Some(node);
+                slot
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+
+        match self.tail{
+            Some(tail) => self.node_mut(tail).next = Some(slot),
+            None => self.head = Some(slot),
+        }
+
+        self.tail = Some(slot);
+        self.len += 1;
+        slot
+    }
+
+    // Unlink the order in `slot`, wherever it is in the queue
+    pub fn remove(&mut self, slot: usize) -> Option<Order>{
+        let node = self.nodes.get_mut(slot)?.take()?;
+
+        match node.prev{
+            Some(prev) => self.node_mut(prev).next = node.next,
+            None => self.head = node.next,
+        }
+
+        match node.next{
+            Some(next) => self.node_mut(next).prev = node.prev,
+            None => self.tail = node.prev,
+        }
+
+        self.free_slots.push(slot);
+        self.len -= 1;
+        Some(node.order)
+    }
+
+    pub fn front(&self) -> Option<(usize, &Order)>{
+        let slot = self.head?;
+        self.get(slot).map(|order| (slot, order))
+    }
+
+    pub fn front_mut(&mut self) -> Option<(usize, &mut Order)>{
+        let slot = self.head?;
+        self.get_mut(slot).map(|order| (slot, order))
+    }
+
+    pub fn get(&self, slot: usize) -> Option<&Order>{
+        self.nodes.get(slot)?.as_ref().map(|node| &node.order)
+    }
+
+    pub fn get_mut(&mut self, slot: usize) -> Option<&mut Order>{
+        self.nodes.get_mut(slot)?.as_mut().map(|node| &mut node.order)
+    }
+
+    // Orders in time priority, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &Order>{
+        let mut cursor = self.head;
+
+        std::iter::from_fn(move ||{
+            let node = self.nodes[cursor?].as_ref()?;
+            cursor = node.next;
+            Some(&node.order)
+        })
+    }
+
+    fn node_mut(&mut self, slot: usize) -> &mut Node{
+        self.nodes[slot].as_mut().expect("linked slot must be occupied")
+    }
+}
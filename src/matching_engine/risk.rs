@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use crate::matching_engine::spec::{MarketSpec, RejectReason};
+use crate::matching_engine::types::{BidOrAsk, Trade};
+
+
+// Price protection state of one market: the reference price the band is centred on
+// and the trades the circuit breaker looks back over. The limits themselves are part
+// of the market spec.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PriceGuard{
+    // Last trade price, or whatever an admin set before the first trade
+    pub reference_price: Option<Decimal>,
+    // Trade prices inside the breaker window, oldest first
+    recent_prices: VecDeque<(DateTime<Utc>, Decimal)>,
+}
+
+impl PriceGuard{
+    // Lowest and highest price orders may have, when the market has a band and a reference
+    pub fn band(&self, spec: &MarketSpec) -> Option<(Decimal, Decimal)>{
+        let band_percent = spec.band_percent?;
+        let reference_price = self.reference_price?;
+        let width = reference_price * band_percent / Decimal::ONE_HUNDRED;
+        Some((reference_price - width, reference_price + width))
+    }
+
+    pub fn check_price(&self, spec: &MarketSpec, price: Decimal) -> Result<(), RejectReason>{
+        match self.band(spec){
+            Some((low, high)) if price < low || price > high => Err(RejectReason::PriceOutsideBand{ price, low, high }),
+            _ => Ok(()),
+        }
+    }
+
+    // The furthest a market order on `side` may sweep
+    pub fn worst_price(&self, spec: &MarketSpec, side: BidOrAsk) -> Option<Decimal>{
+        let (low, high) = self.band(spec)?;
+        match side{
+            BidOrAsk::Bid => Some(high),
+            BidOrAsk::Ask => Some(low),
+        }
+    }
+
+    // Move the reference to the latest trade and return true when the trades moved the price
+    // further than the breaker allows within its window
+    pub fn record_trades(&mut self, spec: &MarketSpec, trades: &[Trade], now: DateTime<Utc>) -> bool{
+        let Some(last) = trades.last() else {
+            return false;
+        };
+        self.reference_price = Some(last.price);
+
+        let Some(breaker_percent) = spec.breaker_percent else {
+            return false;
+        };
+
+        let window_start = now.checked_sub_signed(Duration::seconds(spec.breaker_window_secs as i64)).unwrap_or(DateTime::<Utc>::MIN_UTC);
+        self.recent_prices.extend(trades.iter().map(|trade| (trade.timestamp, trade.price)));
+        while self.recent_prices.front().is_some_and(|(timestamp, _)| *timestamp < window_start) {
+            self.recent_prices.pop_front();
+        }
+
+        let low = self.recent_prices.iter().map(|(_, price)| *price).min().unwrap_or(last.price);
+        let high = self.recent_prices.iter().map(|(_, price)| *price).max().unwrap_or(last.price);
+        let tripped = low > Decimal::ZERO && (high - low) / low * Decimal::ONE_HUNDRED > breaker_percent;
+
+        // A tripped breaker starts over from the price it stopped at
+        if tripped {
+            self.recent_prices.clear();
+        }
+        tripped
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+    use crate::matching_engine::types::{Order, TradingPair};
+
+    fn trade_at(price: i64, timestamp: DateTime<Utc>) -> Trade {
+        Trade::new(
+            TradingPair::new("BTC".to_string(), "USD".to_string()),
+            &Order::new(BidOrAsk::Bid, Decimal::ONE),
+            &Order::new(BidOrAsk::Ask, Decimal::ONE),
+            Decimal::from(price),
+            Decimal::ONE,
+            Uuid::new_v4(),
+            timestamp,
+        )
+    }
+
+    #[test]
+    fn test_band_follows_trades_and_breaker_trips_within_window() {
+        let spec = MarketSpec{
+            band_percent: Some(Decimal::from(10)),
+            breaker_percent: Some(Decimal::from(5)),
+            breaker_window_secs: 60,
+            ..MarketSpec::default()
+        };
+        let mut guard = PriceGuard::default();
+        let start = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap();
+
+        // No reference yet, anything goes
+        assert!(guard.check_price(&spec, Decimal::from(1_000_000)).is_ok());
+
+        assert!(!guard.record_trades(&spec, &[trade_at(100, start)], start));
+        assert_eq!(guard.band(&spec), Some((Decimal::from(90), Decimal::from(110))));
+        assert!(matches!(guard.check_price(&spec, Decimal::from(111)), Err(RejectReason::PriceOutsideBand{ .. })));
+        assert_eq!(guard.worst_price(&spec, BidOrAsk::Ask), Some(Decimal::from(90)));
+
+        // 4% then another 4% a minute and a half later: never 5% inside one window
+        let later = start + Duration::seconds(30);
+        assert!(!guard.record_trades(&spec, &[trade_at(104, later)], later));
+        let much_later = start + Duration::seconds(90);
+        assert!(!guard.record_trades(&spec, &[trade_at(108, much_later)], much_later));
+
+        let soon_after = much_later + Duration::seconds(10);
+        assert!(guard.record_trades(&spec, &[trade_at(102, soon_after)], soon_after));
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::thread::{self, JoinHandle};
+use crossbeam::channel::{Receiver, Sender, unbounded};
+use rust_decimal::Decimal;
+use crate::balance::BalanceManager;
+use crate::websocket::events::MarketDataEvent;
+use crate::matching_engine::{
+    engine::MatchingEngine,
+    clock::{Clock, IdSource, SystemClock, RandomIds},
+    journal::{Recovery, Sequencer},
+    spec::MarketSpec,
+    types::{TradingPair, SelfTradePrevention},
+    messages::{EngineMessage, EngineResponse, DatabaseMessage}
+};
+
+
+// Spreads markets over several engines, each running on its own thread with its own
+// input channel, and forwards every message to the engine that owns its pair.
+//
+// All shards share one BalanceManager (see its locking protocol) and the same output
+// channels, so the rest of the system sees one engine. Shards only wait for each other
+// inside the BalanceManager.
+//
+// Each shard journals its own messages, numbered from one shared sequencer as they arrive;
+// on restart the journals are merged by sequence number. A message sent after the reply to
+// another always replays after it, even when a user's funds moved across markets. Two
+// messages that different shards handle at the same moment replay in number order, which
+// may not be the order they reserved the same user's funds in.
+//
+// Snapshots are not supported with more than one shard: they are taken per engine, and a
+// snapshot of one shard's books and the shared balances would not match the other shards.
+pub struct Router{
+    shards: Vec<MatchingEngine>,
+    shard_senders: Vec<Sender<EngineMessage>>,
+    routes: HashMap<TradingPair, usize>,
+    balance_manager: BalanceManager,
+    message_receiver: Receiver<EngineMessage>,
+    message_sender: Sender<EngineResponse>,
+}
+
+impl Router{
+    pub fn new(shard_count: usize) -> (
+        Self,
+        Sender<EngineMessage>,
+        Receiver<EngineResponse>,
+        Receiver<DatabaseMessage>,
+        Receiver<MarketDataEvent>
+    ){
+        Router::with_sources(shard_count, |_| (Box::new(SystemClock), Box::new(RandomIds)))
+    }
+
+    // `sources` gives each shard its clock and ids, given the shard index
+    pub fn with_sources<F>(shard_count: usize, sources: F) -> (
+        Self,
+        Sender<EngineMessage>,
+        Receiver<EngineResponse>,
+        Receiver<DatabaseMessage>,
+        Receiver<MarketDataEvent>
+    )
+    where
+        F: Fn(usize) -> (Box<dyn Clock>, Box<dyn IdSource>),
+    {
+        let (msg_tx, msg_rx) = unbounded();
+        let (resp_tx, resp_rx) = unbounded();
+        let (db_tx, db_rx) = unbounded();
+        let (ws_tx, ws_rx) = unbounded();
+
+        let balance_manager = BalanceManager::new();
+        let sequencer = Sequencer::new();
+        let mut shards = Vec::new();
+        let mut shard_senders = Vec::new();
+
+        for shard in 0..shard_count.max(1) {
+            let (shard_tx, shard_rx) = unbounded();
+            let (clock, ids) = sources(shard);
+            let mut engine = MatchingEngine::from_parts(
+                clock,
+                ids,
+                balance_manager.clone(),
+                shard_rx,
+                resp_tx.clone(),
+                db_tx.clone(),
+                ws_tx.clone(),
+            );
+            if shard_count > 1 {
+                engine.share_sequencer(sequencer.clone());
+            }
+            shards.push(engine);
+            shard_senders.push(shard_tx);
+        }
+
+        let router = Router{
+            shards,
+            shard_senders,
+            routes: HashMap::new(),
+            balance_manager,
+            message_receiver: msg_rx,
+            message_sender: resp_tx,
+        };
+
+        (router, msg_tx, resp_rx, db_rx, ws_rx)
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shard_senders.len()
+    }
+
+    pub fn shard_of(&self, pair: &TradingPair) -> Option<usize> {
+        self.routes.get(pair).copied()
+    }
+
+    pub fn add_market(&mut self, pair: TradingPair) {
+        self.add_market_with_spec(pair, MarketSpec::default()).expect("the default spec is valid");
+    }
+
+    // Markets are dealt out to the shards in turn
+    pub fn add_market_with_spec(&mut self, pair: TradingPair, spec: MarketSpec) -> Result<(), String> {
+        if self.routes.contains_key(&pair) {
+            return Ok(());
+        }
+
+        let shard = self.routes.len() % self.shards.len();
+        self.shards[shard].add_market_with_spec(pair.clone(), spec)?;
+        self.routes.insert(pair, shard);
+        Ok(())
+    }
+
+    pub fn add_user(&mut self, user_id: String, initial_balances: HashMap<String, Decimal>) -> Result<(), String> {
+        self.balance_manager.add_user(user_id, initial_balances)
+    }
+
+    pub fn balance_manager(&self) -> &BalanceManager {
+        &self.balance_manager
+    }
+
+    pub fn set_self_trade_prevention(&mut self, pair: &TradingPair, mode: SelfTradePrevention) -> Result<(), String> {
+        let shard = self.shard_of(pair).ok_or("Trading pair not found")?;
+        self.shards[shard].set_self_trade_prevention(pair, mode)
+    }
+
+    pub fn enable_snapshots(&mut self, dir: &str, interval: Option<u64>) -> Result<(), String> {
+        if self.shards.len() > 1 {
+            return Err("Snapshots need a single engine shard".to_string());
+        }
+
+        self.shards[0].enable_snapshots(dir, interval);
+        Ok(())
+    }
+
+    // A single shard keeps the journal at `path`, otherwise shard n uses `{path}.{n}`.
+    // The entries of all shards are replayed in one go in sequence order.
+    pub fn open_journal(&mut self, path: &str) -> Result<Recovery, String> {
+        let single = self.shards.len() == 1;
+        let mut recovery = Recovery::default();
+        let mut entries = Vec::new();
+
+        for (shard, engine) in self.shards.iter_mut().enumerate() {
+            let shard_path = if single { path.to_string() } else { format!("{}.{}", path, shard) };
+            let (shard_recovery, shard_entries) = engine.load_journal(&shard_path)?;
+            recovery.merge(shard_recovery);
+            entries.extend(shard_entries.into_iter().map(|entry| (shard, entry)));
+        }
+
+        entries.sort_by_key(|(_, entry)| entry.seq);
+        if let Some(pair) = entries.windows(2).find(|pair| pair[0].1.seq == pair[1].1.seq) {
+            return Err(format!("Sequence {} is in more than one shard journal", pair[0].1.seq));
+        }
+
+        recovery.replayed = entries.len();
+        for (shard, entry) in entries {
+            self.shards[shard].replay(entry);
+        }
+
+        // Markets listed at runtime before the restart come back with the replay
+        for (shard, engine) in self.shards.iter().enumerate() {
+            for pair in engine.orderbooks.keys() {
+                self.routes.entry(pair.clone()).or_insert(shard);
+            }
+        }
+
+        Ok(recovery)
+    }
+
+    // Start every shard on its own thread and route messages until the input channel closes.
+    // Returns the shard threads, which finish once the router is gone.
+    pub fn start(self) -> (JoinHandle<()>, Vec<JoinHandle<()>>) {
+        let Router{ shards, shard_senders, mut routes, message_receiver, message_sender, .. } = self;
+
+        let handles = shards.into_iter()
+            .enumerate()
+            .map(|(shard, engine)| {
+                thread::Builder::new()
+                    .name(format!("engine-shard-{}", shard))
+                    .spawn(move || engine.run())
+                    .expect("Failed to spawn engine shard")
+            })
+            .collect();
+
+        let router = thread::Builder::new()
+            .name("engine-router".to_string())
+            .spawn(move || {
+                while let Ok(msg) = message_receiver.recv() {
+                    route(&mut routes, &shard_senders, &message_sender, msg);
+                }
+            })
+            .expect("Failed to spawn engine router");
+
+        (router, handles)
+    }
+}
+
+fn route(
+    routes: &mut HashMap<TradingPair, usize>,
+    shard_senders: &[Sender<EngineMessage>],
+    message_sender: &Sender<EngineResponse>,
+    msg: EngineMessage,
+) {
+    let pair = match &msg {
+        EngineMessage::PlaceOrder{ pair, .. }
+        | EngineMessage::PlaceStopOrder{ pair, .. }
+        | EngineMessage::CancelOrder{ pair, .. }
+        | EngineMessage::AmendOrder{ pair, .. }
+        | EngineMessage::SetMarketStatus{ pair, .. }
+        | EngineMessage::SetReferencePrice{ pair, .. }
+        | EngineMessage::Uncross{ pair } => pair,
+        // New markets go to the next shard in turn, like at startup
+        EngineMessage::ListMarket{ pair, .. } => {
+            let next_shard = routes.len() % shard_senders.len();
+            let shard = *routes.entry(pair.clone()).or_insert(next_shard);
+            let _ = shard_senders[shard].send(msg);
+            return;
+        }
+        // Every shard needs these
+        EngineMessage::TakeSnapshot | EngineMessage::SetFeeTier{ .. } | EngineMessage::CheckInvariants => {
+            for sender in shard_senders {
+                let _ = sender.send(msg.clone());
+            }
+            return;
+        }
+        // Balances are shared, so any shard can apply these; the first keeps them in one journal
+        EngineMessage::CreateSubAccount{ .. }
+        | EngineMessage::Transfer{ .. }
+        | EngineMessage::CreditDeposit{ .. }
+        | EngineMessage::RequestWithdrawal{ .. }
+        | EngineMessage::ApproveWithdrawal{ .. }
+        | EngineMessage::WithdrawalBroadcast{ .. }
+        | EngineMessage::ConfirmWithdrawal{ .. }
+        | EngineMessage::FailWithdrawal{ .. } => {
+            let _ = shard_senders[0].send(msg);
+            return;
+        }
+    };
+
+    match routes.get(pair) {
+        Some(shard) => {
+            let _ = shard_senders[*shard].send(msg);
+        }
+        None => {
+            let _ = message_sender.send(EngineResponse::Error{ message: "Trading pair not found".to_string() });
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::matching_engine::types::{Order, BidOrAsk};
+
+    #[test]
+    fn test_routes_markets_to_their_shards_with_shared_balances() {
+        let (mut router, msg_tx, resp_rx, _db_rx, _ws_rx) = Router::new(2);
+        let btc = TradingPair::new("BTC".to_string(), "USD".to_string());
+        let eth = TradingPair::new("ETH".to_string(), "USD".to_string());
+        router.add_market(btc.clone());
+        router.add_market(eth.clone());
+        assert_eq!(router.shard_of(&btc), Some(0));
+        assert_eq!(router.shard_of(&eth), Some(1));
+
+        router.add_user("buyer".to_string(), HashMap::from([("USD".to_string(), Decimal::from(150))])).unwrap();
+        let balances = router.balance_manager().clone();
+        let _handles = router.start();
+
+        // Both shards reserve from the same USD balance; only one of the two bids fits
+        for pair in [&btc, &eth] {
+            let mut order = Order::new(BidOrAsk::Bid, Decimal::ONE);
+            order.user_id = "buyer".to_string();
+            msg_tx.send(EngineMessage::PlaceOrder{ pair: pair.clone(), order, price: Some(Decimal::from(100)) }).unwrap();
+        }
+        msg_tx.send(EngineMessage::CancelOrder{
+            pair: TradingPair::new("SOL".to_string(), "USD".to_string()),
+            order_id: uuid::Uuid::new_v4(),
+        }).unwrap();
+
+        let mut placed = 0;
+        let mut errors = 0;
+        while placed + errors < 3 {
+            match resp_rx.recv_timeout(Duration::from_secs(5)).expect("engine did not answer") {
+                EngineResponse::OrderPlaced{ .. } => placed += 1,
+                EngineResponse::Error{ .. } => errors += 1,
+                _ => {}
+            }
+        }
+
+        assert_eq!(placed, 1);
+        assert_eq!(errors, 2);
+        let usd = balances.get_balance("buyer", "USD").unwrap();
+        assert_eq!(usd.locked, Decimal::from(100));
+        assert_eq!(usd.available, Decimal::from(50));
+    }
+
+    #[test]
+    fn test_each_shard_reports_orphaned_reservations_of_its_markets() {
+        let (mut router, msg_tx, resp_rx, _db_rx, _ws_rx) = Router::new(2);
+        let eth = TradingPair::new("ETH".to_string(), "USD".to_string());
+        router.add_market(TradingPair::new("BTC".to_string(), "USD".to_string()));
+        router.add_market(eth.clone());
+        router.add_user("buyer".to_string(), HashMap::from([("USD".to_string(), Decimal::from(100))])).unwrap();
+
+        // Left behind by an order on shard 1 that is gone
+        router.balance_manager().lock_funds(uuid::Uuid::new_v4(), &eth, "buyer", "USD", Decimal::from(10)).unwrap();
+        let _handles = router.start();
+        msg_tx.send(EngineMessage::CheckInvariants).unwrap();
+
+        let mut reports = Vec::new();
+        while reports.len() < 2 {
+            if let EngineResponse::InvariantsChecked{ violations } = resp_rx.recv_timeout(Duration::from_secs(5)).expect("engine did not answer") {
+                reports.push(violations);
+            }
+        }
+
+        let violations: Vec<_> = reports.concat();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].pair, Some(eth));
+        assert!(violations[0].message.contains("is gone"));
+    }
+
+    #[test]
+    fn test_snapshots_need_a_single_shard() {
+        let dir = std::env::temp_dir().join(format!("cex-router-{}", uuid::Uuid::new_v4()));
+        let dir = dir.to_str().unwrap();
+
+        assert!(Router::new(2).0.enable_snapshots(dir, None).is_err());
+        assert!(Router::new(1).0.enable_snapshots(dir, None).is_ok());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_replay_merges_shard_journals_in_sequence_order() {
+        let dir = std::env::temp_dir().join(format!("cex-router-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("engine.journal").to_str().unwrap().to_string();
+        let eth = TradingPair::new("ETH".to_string(), "USD".to_string());
+
+        let setup = || {
+            let (mut router, msg_tx, resp_rx, _db_rx, _ws_rx) = Router::new(2);
+            router.add_market(TradingPair::new("BTC".to_string(), "USD".to_string()));
+            router.add_market(eth.clone());
+            router.add_user("buyer".to_string(), HashMap::from([("USD".to_string(), Decimal::from(100))])).unwrap();
+            router.add_user("seller".to_string(), HashMap::from([("ETH".to_string(), Decimal::ONE)])).unwrap();
+            (router, msg_tx, resp_rx)
+        };
+
+        let (mut router, msg_tx, resp_rx) = setup();
+        router.open_journal(&path).unwrap();
+        let balances = router.balance_manager().clone();
+        let _handles = router.start();
+
+        // The seller can only withdraw the USD from a trade on shard 1 through shard 0
+        let mut bid = Order::new(BidOrAsk::Bid, Decimal::ONE);
+        bid.user_id = "buyer".to_string();
+        let mut ask = Order::new(BidOrAsk::Ask, Decimal::ONE);
+        ask.user_id = "seller".to_string();
+        let messages = vec![
+            EngineMessage::PlaceOrder{ pair: eth.clone(), order: bid, price: Some(Decimal::from(100)) },
+            EngineMessage::PlaceOrder{ pair: eth.clone(), order: ask, price: Some(Decimal::from(100)) },
+            EngineMessage::RequestWithdrawal{
+                withdrawal_id: uuid::Uuid::new_v4(),
+                user_id: "seller".to_string(),
+                asset: "USD".to_string(),
+                amount: Decimal::from(100),
+                address: "address".to_string(),
+            },
+        ];
+        for msg in messages {
+            msg_tx.send(msg).unwrap();
+            loop {
+                match resp_rx.recv_timeout(Duration::from_secs(5)).expect("engine did not answer") {
+                    EngineResponse::OrderPlaced{ .. } | EngineResponse::WithdrawalUpdated{ .. } => break,
+                    EngineResponse::Error{ message } => panic!("{}", message),
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(balances.get_balance("seller", "USD").unwrap().locked, Decimal::from(100));
+
+        let (mut recovered, _msg_tx, _resp_rx) = setup();
+        assert_eq!(recovered.open_journal(&path).unwrap().replayed, 3);
+        let usd = recovered.balance_manager().get_balance("seller", "USD").unwrap();
+        assert_eq!((usd.available, usd.locked), (Decimal::ZERO, Decimal::from(100)));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use rust_decimal::Decimal;
+use uuid::Uuid;
+use crate::matching_engine::{
+    orderbook::{OrderBook, MatchResult},
+    types::{BidOrAsk, Limit, Order, OrderType, TimeInForce, TradingPair},
+};
+
+
+// Random order flow against one OrderBook, checked after every step against a plain reference
+// matcher and the invariants every book has to keep. The property tests below and the fuzz
+// target in fuzz/ both drive it.
+
+// Prices are ticks above this, so random orders cross often
+const BASE_PRICE: i64 = 100;
+const PRICE_TICKS: u8 = 16;
+const MAX_SIZE: u8 = 10;
+
+#[derive(Debug, Clone)]
+pub enum Action{
+    // A limit order `price` ticks up from the base price, or a market order without one.
+    // `display` makes a limit order an iceberg.
+    Place{ side: BidOrAsk, price: Option<u8>, size: u8, time_in_force: TimeInForce, display: Option<u8> },
+    // Orders are picked among all placed so far, so some are already gone
+    Cancel{ pick: usize },
+    // New open size and optionally a new price, like an amend through the engine
+    Amend{ pick: usize, price: Option<u8>, size: u8 },
+}
+
+impl Action{
+    // Four bytes per action, for fuzzers; a short tail is ignored
+    pub fn from_bytes(data: &[u8]) -> Vec<Action>{
+        data.chunks_exact(4).map(|chunk| {
+            let [kind, price, size, extra] = [chunk[0], chunk[1], chunk[2], chunk[3]];
+            let side = if kind & 1 == 0 { BidOrAsk::Bid } else { BidOrAsk::Ask };
+            let price = price % PRICE_TICKS;
+            let size = size % MAX_SIZE + 1;
+
+            match (kind >> 1) % 8 {
+                0 => Action::Cancel{ pick: extra as usize },
+                1 => Action::Amend{ pick: extra as usize, price: (extra & 1 == 0).then_some(price), size },
+                2 => Action::Place{ side, price: None, size, time_in_force: TimeInForce::Ioc, display: None },
+                3 => Action::Place{ side, price: Some(price), size, time_in_force: TimeInForce::Gtc, display: Some(extra % size + 1) },
+                mode => {
+                    let time_in_force = [TimeInForce::Gtc, TimeInForce::Ioc, TimeInForce::Fok, TimeInForce::PostOnly][(mode % 4) as usize];
+                    Action::Place{ side, price: Some(price), size, time_in_force, display: None }
+                }
+            }
+        }).collect()
+    }
+}
+
+fn price_of(ticks: u8) -> Decimal{
+    Decimal::from(BASE_PRICE + ticks as i64)
+}
+
+// Run every action and check the book after each one. The error says which step went wrong.
+pub fn run(actions: &[Action]) -> Result<(), String>{
+    let mut simulation = Simulation::new();
+    for (step, action) in actions.iter().enumerate() {
+        simulation.apply(action).map_err(|message| format!("Step {} {:?}: {}", step, action, message))?;
+    }
+    Ok(())
+}
+
+// (maker, taker, price, quantity) of a trade
+type Fill = (Uuid, Uuid, Decimal, Decimal);
+// Every level, bids best first then asks best first, with (order id, size, visible size) in queue order
+type BookState = Vec<(BidOrAsk, Decimal, Vec<(Uuid, Decimal, Decimal)>)>;
+
+struct Simulation{
+    book: OrderBook,
+    reference: ReferenceBook,
+    placed: Vec<Uuid>,
+    // When each plain order last joined the back of its level
+    queued_at: HashMap<Uuid, u64>,
+    step: u64,
+}
+
+impl Simulation{
+    fn new() -> Simulation{
+        Simulation{
+            book: OrderBook::new(TradingPair::new("BTC".to_string(), "USD".to_string())),
+            reference: ReferenceBook::default(),
+            placed: Vec::new(),
+            queued_at: HashMap::new(),
+            step: 0,
+        }
+    }
+
+    fn apply(&mut self, action: &Action) -> Result<(), String>{
+        self.step += 1;
+        let before = self.state();
+
+        let (result, expected) = match action {
+            Action::Place{ side, price, size, time_in_force, display } => {
+                let mut order = Order::new(*side, Decimal::from(*size));
+                order.time_in_force = *time_in_force;
+                order.display_size = display.map(Decimal::from);
+                let price = price.map(price_of);
+                if price.is_none() {
+                    order.order_type = OrderType::Market;
+                }
+                self.placed.push(order.id);
+
+                let expected = self.reference.submit(&order, price);
+                let result = match price {
+                    Some(price) => self.book.add_order(price, order),
+                    None => self.book.add_market_order(order),
+                };
+                (Some(result), expected)
+            }
+            Action::Cancel{ pick } => {
+                let Some(order_id) = self.pick(*pick) else {
+                    return Ok(());
+                };
+                let cancelled = self.book.cancel_order(order_id).map(|order| order.id);
+                let expected = self.reference.cancel(order_id).map(|order| order.id);
+                if cancelled != expected {
+                    return Err(format!("Cancel gave {:?}, reference {:?}", cancelled, expected));
+                }
+                (None, Vec::new())
+            }
+            Action::Amend{ pick, price, size } => match self.pick(*pick) {
+                Some(order_id) => self.amend(order_id, price.map(price_of), Decimal::from(*size)),
+                None => return Ok(()),
+            },
+        };
+
+        if let Some(result) = &result {
+            let fills: Vec<Fill> = result.trades.iter().map(|trade| (trade.maker_order_id, trade.taker_order_id, trade.price, trade.quantity)).collect();
+            if fills != expected {
+                return Err(format!("Fills {:?}, reference {:?}", fills, expected));
+            }
+            if result.rested && result.order.display_size.is_none() {
+                self.queued_at.insert(result.order.id, self.step);
+            }
+            check_fills(&before, &self.state(), result)?;
+        }
+
+        let state = self.state();
+        let reference = self.reference.state();
+        if state != reference {
+            return Err(format!("Book {:?}, reference {:?}", state, reference));
+        }
+        self.check_book()
+    }
+
+    fn pick(&self, pick: usize) -> Option<Uuid>{
+        match self.placed.len() {
+            0 => None,
+            placed => Some(self.placed[pick % placed]),
+        }
+    }
+
+    // Same as the engine: shrinking in place keeps priority, anything else re-queues and may match
+    fn amend(&mut self, order_id: Uuid, price: Option<Decimal>, size: Decimal) -> (Option<MatchResult>, Vec<Fill>){
+        let Some(location) = self.book.locate(order_id) else {
+            return (None, Vec::new());
+        };
+        let current = self.book.get_order(order_id).expect("located order is resting").clone();
+        let price = price.unwrap_or(location.price);
+
+        if price == location.price && size <= current.size {
+            self.book.reduce_order(order_id, size);
+            self.reference.reduce(order_id, size);
+            return (None, Vec::new());
+        }
+
+        if current.time_in_force == TimeInForce::PostOnly && price != location.price && self.book.would_cross(current.bid_or_ask, price) {
+            return (None, Vec::new());
+        }
+
+        let mut order = self.book.cancel_order(order_id).expect("located order is resting");
+        self.reference.cancel(order_id);
+        order.size = size;
+        let expected = self.reference.submit(&order, Some(price));
+        (Some(self.book.add_order(price, order)), expected)
+    }
+
+    fn state(&self) -> BookState{
+        let level = |side: BidOrAsk| move |(price, limit): (&Decimal, &Limit)| {
+            (side, *price, limit.orders.iter().map(|order| (order.id, order.size, order.visible())).collect())
+        };
+        self.book.bids.iter().rev().map(level(BidOrAsk::Bid))
+            .chain(self.book.asks.iter().map(level(BidOrAsk::Ask)))
+            .collect()
+    }
+
+    // Never crossed, no empty level, no empty order, every order findable and plain orders
+    // queued in the order they arrived
+    fn check_book(&self) -> Result<(), String>{
+        if let (Some(bid), Some(ask)) = (self.book.best_bid(), self.book.best_ask()) && bid >= ask {
+            return Err(format!("Crossed book: bid {} ask {}", bid, ask));
+        }
+
+        for (side, levels) in [(BidOrAsk::Bid, &self.book.bids), (BidOrAsk::Ask, &self.book.asks)] {
+            for (price, limit) in levels {
+                if limit.orders.is_empty() || limit.price != *price {
+                    return Err(format!("Level {} is empty or mislabelled", price));
+                }
+
+                let mut last_queued = 0;
+                for order in limit.orders.iter() {
+                    if order.size <= Decimal::ZERO || order.visible() <= Decimal::ZERO || order.visible() > order.size || order.bid_or_ask != side {
+                        return Err(format!("Order {} at {} has size {} showing {}", order.id, price, order.size, order.visible()));
+                    }
+
+                    let location = self.book.locate(order.id);
+                    if location.is_none_or(|location| location.price != *price || location.side != side) {
+                        return Err(format!("Order {} at {} is indexed at {:?}", order.id, price, location));
+                    }
+
+                    if let Some(queued_at) = self.queued_at.get(&order.id).filter(|_| order.display_size.is_none()) {
+                        if *queued_at < last_queued {
+                            return Err(format!("Order {} at {} jumped the queue", order.id, price));
+                        }
+                        last_queued = *queued_at;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Every trade takes from the taker and one maker exactly what it fills, walking the book from
+// the best price within the taker's limit
+fn check_fills(before: &BookState, after: &BookState, result: &MatchResult) -> Result<(), String>{
+    let sizes = |state: &BookState| -> HashMap<Uuid, Decimal> {
+        state.iter().flat_map(|(_, _, orders)| orders.iter().map(|(id, size, _)| (*id, *size))).collect()
+    };
+    let (mut before, after) = (sizes(before), sizes(after));
+    // An amended order left the book before it matched again
+    before.remove(&result.order.id);
+
+    let mut traded: HashMap<Uuid, Decimal> = HashMap::new();
+    for trade in &result.trades {
+        if trade.quantity <= Decimal::ZERO {
+            return Err(format!("Trade of {} at {}", trade.quantity, trade.price));
+        }
+        *traded.entry(trade.maker_order_id).or_default() += trade.quantity;
+    }
+    for (maker_id, quantity) in &traded {
+        let left = after.get(maker_id).copied().unwrap_or_default();
+        if before.get(maker_id).copied().unwrap_or_default() - left != *quantity {
+            return Err(format!("Maker {} traded {} but went from {:?} to {}", maker_id, quantity, before.get(maker_id), left));
+        }
+    }
+
+    let volume: Decimal = traded.values().sum();
+    let rested = after.get(&result.order.id).copied().unwrap_or_default();
+    if result.rested != (rested > Decimal::ZERO) || (result.rested && rested != result.order.size) {
+        return Err(format!("Order {} rested {} but the book holds {}", result.order.id, result.order.size, rested));
+    }
+
+    let resting_before: Decimal = before.values().sum();
+    let resting_after: Decimal = after.values().sum();
+    if resting_before - volume + rested != resting_after {
+        return Err(format!("Book went from {} to {} trading {} and resting {}", resting_before, resting_after, volume, rested));
+    }
+
+    let better = |a: Decimal, b: Decimal| match result.order.bid_or_ask {
+        BidOrAsk::Bid => a <= b,
+        BidOrAsk::Ask => a >= b,
+    };
+    if result.trades.windows(2).any(|pair| !better(pair[0].price, pair[1].price)) {
+        return Err("Trades walked the book out of price order".to_string());
+    }
+
+    Ok(())
+}
+
+
+// Resting orders in a flat list; the best one is found by scanning for the best price, then
+// the earliest arrival
+#[derive(Debug, Clone)]
+struct Resting{
+    id: Uuid,
+    side: BidOrAsk,
+    price: Decimal,
+    size: Decimal,
+    display: Option<Decimal>,
+    visible: Decimal,
+    seq: u64,
+}
+
+#[derive(Debug, Default)]
+struct ReferenceBook{
+    orders: Vec<Resting>,
+    next_seq: u64,
+}
+
+impl ReferenceBook{
+    fn submit(&mut self, order: &Order, price: Option<Decimal>) -> Vec<Fill>{
+        let side = order.bid_or_ask;
+        let acceptable = |resting: &Resting| resting.side != side && match (side, price) {
+            (_, None) => true,
+            (BidOrAsk::Bid, Some(price)) => resting.price <= price,
+            (BidOrAsk::Ask, Some(price)) => resting.price >= price,
+        };
+
+        let crosses = self.orders.iter().any(acceptable);
+        let fillable: Decimal = self.orders.iter().filter(|resting| acceptable(resting)).map(|resting| resting.size).sum();
+        match order.time_in_force {
+            TimeInForce::PostOnly if crosses => return Vec::new(),
+            TimeInForce::Fok if fillable < order.size => return Vec::new(),
+            _ => {}
+        }
+
+        let mut fills = Vec::new();
+        let mut remaining = order.size;
+        while remaining > Decimal::ZERO {
+            let best = self.orders.iter().enumerate()
+                .filter(|(_, resting)| acceptable(resting))
+                .min_by_key(|(_, resting)| (if side == BidOrAsk::Bid { resting.price } else { -resting.price }, resting.seq))
+                .map(|(index, _)| index);
+            let Some(index) = best else {
+                break;
+            };
+
+            let maker = &mut self.orders[index];
+            let quantity = remaining.min(maker.visible);
+            fills.push((maker.id, order.id, maker.price, quantity));
+            remaining -= quantity;
+            maker.size -= quantity;
+            maker.visible -= quantity;
+
+            if maker.size == Decimal::ZERO {
+                self.orders.remove(index);
+            } else if maker.visible == Decimal::ZERO {
+                // Next slice of an iceberg, at the back of the queue
+                maker.visible = maker.display.unwrap_or(maker.size).min(maker.size);
+                maker.seq = self.next_seq;
+                self.next_seq += 1;
+            }
+        }
+
+        if let (Some(price), true) = (price, remaining > Decimal::ZERO && order.time_in_force.rests()) {
+            self.orders.push(Resting{
+                id: order.id,
+                side,
+                price,
+                size: remaining,
+                display: order.display_size,
+                visible: order.display_size.unwrap_or(remaining).min(remaining),
+                seq: self.next_seq,
+            });
+            self.next_seq += 1;
+        }
+
+        fills
+    }
+
+    fn cancel(&mut self, order_id: Uuid) -> Option<Resting>{
+        let index = self.orders.iter().position(|resting| resting.id == order_id)?;
+        Some(self.orders.remove(index))
+    }
+
+    fn reduce(&mut self, order_id: Uuid, size: Decimal){
+        if let Some(resting) = self.orders.iter_mut().find(|resting| resting.id == order_id) {
+            resting.size = size;
+            resting.visible = resting.visible.min(size);
+        }
+    }
+
+    fn state(&self) -> BookState{
+        let mut orders: Vec<&Resting> = self.orders.iter().collect();
+        orders.sort_by_key(|resting| match resting.side {
+            BidOrAsk::Bid => (0, -resting.price, resting.seq),
+            BidOrAsk::Ask => (1, resting.price, resting.seq),
+        });
+
+        let mut state: BookState = Vec::new();
+        for resting in orders {
+            let entry = (resting.id, resting.size, resting.visible);
+            match state.last_mut() {
+                Some((side, price, level)) if *side == resting.side && *price == resting.price => level.push(entry),
+                _ => state.push((resting.side, resting.price, vec![entry])),
+            }
+        }
+        state
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn action() -> impl Strategy<Value = Action> {
+        let side = prop_oneof![Just(BidOrAsk::Bid), Just(BidOrAsk::Ask)];
+        let time_in_force = prop_oneof![
+            4 => Just(TimeInForce::Gtc),
+            1 => Just(TimeInForce::Ioc),
+            1 => Just(TimeInForce::Fok),
+            1 => Just(TimeInForce::PostOnly),
+        ];
+        let price = 0..PRICE_TICKS;
+        let size = 1..=MAX_SIZE;
+
+        prop_oneof![
+            6 => (side.clone(), price.clone(), size.clone(), time_in_force)
+                .prop_map(|(side, price, size, time_in_force)| Action::Place{ side, price: Some(price), size, time_in_force, display: None }),
+            1 => (side.clone(), size.clone())
+                .prop_map(|(side, size)| Action::Place{ side, price: None, size, time_in_force: TimeInForce::Ioc, display: None }),
+            1 => (side, price.clone(), size.clone(), 1..=MAX_SIZE)
+                .prop_map(|(side, price, size, display)| Action::Place{ side, price: Some(price), size, time_in_force: TimeInForce::Gtc, display: Some(display.min(size)) }),
+            2 => any::<usize>().prop_map(|pick| Action::Cancel{ pick }),
+            2 => (any::<usize>(), proptest::option::of(price), size)
+                .prop_map(|(pick, price, size)| Action::Amend{ pick, price, size }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_random_order_flow_matches_the_reference_book(actions in proptest::collection::vec(action(), 1..200)) {
+            if let Err(message) = run(&actions) {
+                prop_assert!(false, "{}", message);
+            }
+        }
+
+        // What the fuzz target feeds in
+        #[test]
+        fn test_fuzz_input_matches_the_reference_book(data in proptest::collection::vec(any::<u8>(), 0..800)) {
+            if let Err(message) = run(&Action::from_bytes(&data)) {
+                prop_assert!(false, "{}", message);
+            }
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use rust_decimal::Decimal;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::balance::{BalanceManager, BalanceState};
+use crate::balance::ledger::LedgerEntry;
+use crate::matching_engine::{
+    orderbook::OrderBook,
+    risk::PriceGuard,
+    spec::MarketSpec,
+    stops::{StopBook, StopOrder},
+    types::{Limit, Order, SelfTradePrevention, TradingPair, MarketStatus},
+};
+
+
+// Bump whenever the layout of `EngineSnapshot` changes; older files are refused
+pub const SNAPSHOT_VERSION: u32 = 6;
+
+// File layout: magic, version (u32 LE), CRC32 of the payload (u32 LE), JSON payload
+const MAGIC: &[u8; 8] = b"CEXSNAP\0";
+const HEADER_LEN: usize = MAGIC.len() + 8;
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LevelSnapshot{
+    pub price: Decimal,
+    // Queue order, oldest first
+    pub orders: Vec<Order>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookSnapshot{
+    pub pair: TradingPair,
+    pub self_trade_prevention: SelfTradePrevention,
+    pub last_trade_price: Option<Decimal>,
+    pub status: MarketStatus,
+    pub spec: MarketSpec,
+    pub guard: PriceGuard,
+    pub auction_ends_at: Option<DateTime<Utc>>,
+    pub bids: Vec<LevelSnapshot>,
+    pub asks: Vec<LevelSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StopBookSnapshot{
+    pub pair: TradingPair,
+    // Placement order
+    pub stops: Vec<StopOrder>,
+}
+
+// Everything the engine needs to carry on after journal entry `seq`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EngineSnapshot{
+    pub seq: u64,
+    pub books: Vec<BookSnapshot>,
+    pub stop_books: Vec<StopBookSnapshot>,
+    pub balances: BalanceState,
+    pub fee_tiers: HashMap<String, u32>,
+}
+
+impl BookSnapshot{
+    pub fn capture(orderbook: &OrderBook) -> BookSnapshot{
+        let levels = |side: &BTreeMap<Decimal, Limit>| side.iter()
+            .map(|(price, limit)| LevelSnapshot{ price: *price, orders: limit.orders.iter().cloned().collect() })
+            .collect();
+
+        BookSnapshot{
+            pair: orderbook.pair.clone(),
+            self_trade_prevention: orderbook.self_trade_prevention,
+            last_trade_price: orderbook.last_trade_price,
+            status: orderbook.status,
+            spec: orderbook.spec.clone(),
+            guard: orderbook.guard.clone(),
+            auction_ends_at: orderbook.auction_ends_at,
+            bids: levels(&orderbook.bids),
+            asks: levels(&orderbook.asks),
+        }
+    }
+
+    pub fn restore(self) -> OrderBook{
+        let mut orderbook = OrderBook::new(self.pair);
+        orderbook.self_trade_prevention = self.self_trade_prevention;
+        orderbook.last_trade_price = self.last_trade_price;
+        orderbook.status = self.status;
+        orderbook.spec = self.spec;
+        orderbook.guard = self.guard;
+        orderbook.auction_ends_at = self.auction_ends_at;
+
+        for level in self.bids.into_iter().chain(self.asks) {
+            for order in level.orders {
+                orderbook.restore_order(level.price, order);
+            }
+        }
+
+        orderbook
+    }
+}
+
+impl EngineSnapshot{
+    pub fn capture(
+        seq: u64,
+        orderbooks: &HashMap<TradingPair, OrderBook>,
+        stop_books: &HashMap<TradingPair, StopBook>,
+        balances: &BalanceManager,
+        fee_tiers: &HashMap<String, u32>,
+    ) -> EngineSnapshot{
+        EngineSnapshot{
+            seq,
+            books: orderbooks.values().map(BookSnapshot::capture).collect(),
+            stop_books: stop_books.iter()
+                .map(|(pair, stop_book)| StopBookSnapshot{ pair: pair.clone(), stops: stop_book.stops().into_iter().cloned().collect() })
+                .collect(),
+            balances: balances.snapshot(),
+            fee_tiers: fee_tiers.clone(),
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, String>{
+        let payload = serde_json::to_vec(self).map_err(|e| format!("Failed to encode snapshot: {}", e))?;
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<EngineSnapshot, String>{
+        if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+            return Err("Not a snapshot file".to_string());
+        }
+
+        let version = u32::from_le_bytes(bytes[8..12].try_into().expect("header is long enough"));
+        if version != SNAPSHOT_VERSION {
+            return Err(format!("Unsupported snapshot version {} (expected {})", version, SNAPSHOT_VERSION));
+        }
+
+        let checksum = u32::from_le_bytes(bytes[12..16].try_into().expect("header is long enough"));
+        let payload = &bytes[HEADER_LEN..];
+        if crc32fast::hash(payload) != checksum {
+            return Err("Snapshot checksum mismatch".to_string());
+        }
+
+        serde_json::from_slice(payload).map_err(|e| format!("Failed to decode snapshot: {}", e))
+    }
+
+    // Write to a temporary file first so a crash never leaves a half-written snapshot behind
+    pub fn write(&self, dir: &Path) -> Result<PathBuf, String>{
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create snapshot dir {}: {}", dir.display(), e))?;
+
+        let path = dir.join(format!("snapshot-{:020}.snap", self.seq));
+        let tmp_path = path.with_extension("tmp");
+        let bytes = self.encode()?;
+
+        let mut file = fs::File::create(&tmp_path).map_err(|e| format!("Failed to create snapshot: {}", e))?;
+        file.write_all(&bytes).map_err(|e| format!("Failed to write snapshot: {}", e))?;
+        file.sync_all().map_err(|e| format!("Failed to sync snapshot: {}", e))?;
+        fs::rename(&tmp_path, &path).map_err(|e| format!("Failed to move snapshot into place: {}", e))?;
+
+        Ok(path)
+    }
+
+    // Newest snapshot in `dir` that passes validation, and why each newer file was skipped
+    pub fn load_latest(dir: &Path) -> (Option<EngineSnapshot>, Vec<String>){
+        let mut rejected = Vec::new();
+        let Ok(files) = fs::read_dir(dir) else {
+            return (None, rejected);
+        };
+
+        let mut paths: Vec<PathBuf> = files
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "snap"))
+            .collect();
+
+        // Zero-padded sequence numbers sort by name
+        paths.sort();
+
+        for path in paths.iter().rev() {
+            let loaded = fs::read(path)
+                .map_err(|e| format!("Failed to read snapshot: {}", e))
+                .and_then(|bytes| EngineSnapshot::decode(&bytes));
+
+            match loaded {
+                Ok(snapshot) => return (Some(snapshot), rejected),
+                Err(e) => rejected.push(format!("{}: {}", path.display(), e)),
+            }
+        }
+
+        (None, rejected)
+    }
+}
+
+
+// Ledger entries a snapshot no longer carries go to "ledger-{first seq}.jsonl" next to it,
+// one JSON entry per line. Written before the snapshot that leaves them out; after a restart
+// from an older snapshot the same file is written again with at least the same entries.
+pub fn archive_ledger(dir: &Path, entries: &[LedgerEntry]) -> Result<(), String>{
+    let Some(first) = entries.first() else {
+        return Ok(());
+    };
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create snapshot dir {}: {}", dir.display(), e))?;
+
+    let mut bytes = Vec::new();
+    for entry in entries {
+        serde_json::to_writer(&mut bytes, entry).map_err(|e| format!("Failed to encode ledger entry: {}", e))?;
+        bytes.push(b'\n');
+    }
+
+    let path = dir.join(format!("ledger-{:020}.jsonl", first.seq));
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path).map_err(|e| format!("Failed to create ledger archive: {}", e))?;
+    file.write_all(&bytes).map_err(|e| format!("Failed to write ledger archive: {}", e))?;
+    file.sync_all().map_err(|e| format!("Failed to sync ledger archive: {}", e))?;
+    fs::rename(&tmp_path, &path).map_err(|e| format!("Failed to move ledger archive into place: {}", e))?;
+    Ok(())
+}
+
+// Every archived ledger entry in `dir`, oldest first
+pub fn load_ledger_archive(dir: &Path) -> Result<Vec<LedgerEntry>, String>{
+    let files = fs::read_dir(dir).map_err(|e| format!("Failed to read snapshot dir {}: {}", dir.display(), e))?;
+    let mut paths: Vec<PathBuf> = files
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "jsonl"))
+        .filter(|path| path.file_name().is_some_and(|name| name.to_string_lossy().starts_with("ledger-")))
+        .collect();
+    paths.sort();
+
+    let mut entries: Vec<LedgerEntry> = Vec::new();
+    for path in paths {
+        let contents = fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        for line in contents.lines() {
+            let entry: LedgerEntry = serde_json::from_str(line).map_err(|e| format!("Bad entry in {}: {}", path.display(), e))?;
+            // A file rewritten after a restart can overlap the next one
+            if entries.last().is_none_or(|last| entry.seq > last.seq) {
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(entries)
+}
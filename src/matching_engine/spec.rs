@@ -0,0 +1,229 @@
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use crate::matching_engine::types::{Order, OrderType};
+use crate::matching_engine::fees::FeeSchedule;
+
+
+// Trading rules of a market. Prices have to sit on the tick, quantities on the step,
+// and neither may carry more decimals than its asset allows.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketSpec{
+    pub tick_size: Decimal,
+    pub step_size: Decimal,
+    pub min_quantity: Decimal,
+    // No upper limit when missing
+    pub max_quantity: Option<Decimal>,
+    // Smallest price * quantity an order may have, in the quote asset
+    pub min_notional: Decimal,
+    // Decimal places of the base asset, which quantities are in
+    pub base_precision: u32,
+    // Decimal places of the quote asset, which prices and notionals are in
+    pub quote_precision: u32,
+    // Orders may be priced at most this many percent away from the reference price,
+    // and market orders stop sweeping there
+    pub band_percent: Option<Decimal>,
+    // Halt the market when the price moves more than this many percent
+    // within `breaker_window_secs`
+    pub breaker_percent: Option<Decimal>,
+    pub breaker_window_secs: u64,
+    // Open with a call auction of this many seconds after listing, a halt or a tripped
+    // breaker; straight to continuous matching when missing
+    pub auction_secs: Option<u64>,
+    // Maker and taker rates by user tier; no fees when empty
+    pub fees: FeeSchedule,
+}
+
+impl Default for MarketSpec{
+    fn default() -> MarketSpec{
+        MarketSpec{
+            tick_size: Decimal::new(1, 8),
+            step_size: Decimal::new(1, 8),
+            min_quantity: Decimal::ZERO,
+            max_quantity: None,
+            min_notional: Decimal::ZERO,
+            base_precision: 8,
+            quote_precision: 8,
+            band_percent: None,
+            breaker_percent: None,
+            breaker_window_secs: 300,
+            auction_secs: None,
+            fees: FeeSchedule::default(),
+        }
+    }
+}
+
+
+// Why an order broke the rules of its market
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RejectReason{
+    InvalidPrice{ price: Decimal },
+    PriceOffTick{ price: Decimal, tick_size: Decimal },
+    PriceTooPrecise{ price: Decimal, precision: u32 },
+    InvalidQuantity{ quantity: Decimal },
+    InvalidQuoteQuantity{ quote_size: Decimal, precision: u32 },
+    QuantityOffStep{ quantity: Decimal, step_size: Decimal },
+    QuantityTooPrecise{ quantity: Decimal, precision: u32 },
+    QuantityBelowMinimum{ quantity: Decimal, min_quantity: Decimal },
+    QuantityAboveMaximum{ quantity: Decimal, max_quantity: Decimal },
+    NotionalBelowMinimum{ notional: Decimal, min_notional: Decimal },
+    PriceOutsideBand{ price: Decimal, low: Decimal, high: Decimal },
+}
+
+impl RejectReason{
+    pub fn as_str(&self) -> &'static str{
+        match self{
+            RejectReason::InvalidPrice{ .. } => "invalid_price",
+            RejectReason::PriceOffTick{ .. } => "price_off_tick",
+            RejectReason::PriceTooPrecise{ .. } => "price_too_precise",
+            RejectReason::InvalidQuantity{ .. } => "invalid_quantity",
+            RejectReason::InvalidQuoteQuantity{ .. } => "invalid_quote_quantity",
+            RejectReason::QuantityOffStep{ .. } => "quantity_off_step",
+            RejectReason::QuantityTooPrecise{ .. } => "quantity_too_precise",
+            RejectReason::QuantityBelowMinimum{ .. } => "quantity_below_minimum",
+            RejectReason::QuantityAboveMaximum{ .. } => "quantity_above_maximum",
+            RejectReason::NotionalBelowMinimum{ .. } => "notional_below_minimum",
+            RejectReason::PriceOutsideBand{ .. } => "price_outside_band",
+        }
+    }
+
+    pub fn message(&self) -> String{
+        match self{
+            RejectReason::InvalidPrice{ price } => format!("Invalid price {}", price),
+            RejectReason::PriceOffTick{ price, tick_size } => format!("Price {} is not a multiple of the tick size {}", price, tick_size),
+            RejectReason::PriceTooPrecise{ price, precision } => format!("Price {} has more than {} decimals", price, precision),
+            RejectReason::InvalidQuantity{ quantity } => format!("Invalid quantity {}", quantity),
+            RejectReason::InvalidQuoteQuantity{ quote_size, precision } => format!("Quote quantity {} has to be positive with at most {} decimals", quote_size, precision),
+            RejectReason::QuantityOffStep{ quantity, step_size } => format!("Quantity {} is not a multiple of the step size {}", quantity, step_size),
+            RejectReason::QuantityTooPrecise{ quantity, precision } => format!("Quantity {} has more than {} decimals", quantity, precision),
+            RejectReason::QuantityBelowMinimum{ quantity, min_quantity } => format!("Quantity {} is below the minimum of {}", quantity, min_quantity),
+            RejectReason::QuantityAboveMaximum{ quantity, max_quantity } => format!("Quantity {} is above the maximum of {}", quantity, max_quantity),
+            RejectReason::NotionalBelowMinimum{ notional, min_notional } => format!("Notional {} is below the minimum of {}", notional, min_notional),
+            RejectReason::PriceOutsideBand{ price, low, high } => format!("Price {} is outside the band {} - {}", price, low, high),
+        }
+    }
+}
+
+
+impl MarketSpec{
+    pub fn check_price(&self, price: Decimal) -> Result<(), RejectReason>{
+        if price <= Decimal::ZERO {
+            return Err(RejectReason::InvalidPrice{ price });
+        }
+        if decimals(price) > self.quote_precision {
+            return Err(RejectReason::PriceTooPrecise{ price, precision: self.quote_precision });
+        }
+        if !on_increment(price, self.tick_size) {
+            return Err(RejectReason::PriceOffTick{ price, tick_size: self.tick_size });
+        }
+        Ok(())
+    }
+
+    pub fn check_quantity(&self, quantity: Decimal) -> Result<(), RejectReason>{
+        if quantity <= Decimal::ZERO {
+            return Err(RejectReason::InvalidQuantity{ quantity });
+        }
+        if decimals(quantity) > self.base_precision {
+            return Err(RejectReason::QuantityTooPrecise{ quantity, precision: self.base_precision });
+        }
+        if !on_increment(quantity, self.step_size) {
+            return Err(RejectReason::QuantityOffStep{ quantity, step_size: self.step_size });
+        }
+        if quantity < self.min_quantity {
+            return Err(RejectReason::QuantityBelowMinimum{ quantity, min_quantity: self.min_quantity });
+        }
+        if let Some(max_quantity) = self.max_quantity.filter(|max_quantity| quantity > *max_quantity) {
+            return Err(RejectReason::QuantityAboveMaximum{ quantity, max_quantity });
+        }
+        Ok(())
+    }
+
+    // Round a quantity down onto the step and the precision of the base asset
+    pub fn floor_quantity(&self, quantity: Decimal) -> Decimal{
+        let quantity = quantity.round_dp_with_strategy(self.base_precision, RoundingStrategy::ToZero);
+        if self.step_size <= Decimal::ZERO {
+            return quantity;
+        }
+        quantity - quantity % self.step_size
+    }
+
+    pub fn check_notional(&self, notional: Decimal) -> Result<(), RejectReason>{
+        if notional < self.min_notional {
+            return Err(RejectReason::NotionalBelowMinimum{ notional, min_notional: self.min_notional });
+        }
+        Ok(())
+    }
+
+    // Limit orders are checked at their price. Market orders sized in base are checked
+    // at `reference_price` (the best opposite price) when there is one; market orders
+    // sized in quote only have their budget checked.
+    pub fn validate(&self, order: &Order, price: Option<Decimal>, reference_price: Option<Decimal>) -> Result<(), RejectReason>{
+        if let Some(quote_size) = order.quote_size {
+            if quote_size <= Decimal::ZERO || decimals(quote_size) > self.quote_precision {
+                return Err(RejectReason::InvalidQuoteQuantity{ quote_size, precision: self.quote_precision });
+            }
+            return self.check_notional(quote_size);
+        }
+
+        if let (OrderType::Limit, Some(price)) = (order.order_type, price) {
+            self.check_price(price)?;
+        }
+
+        self.check_quantity(order.size)?;
+        if let Some(display_size) = order.display_size {
+            self.check_quantity(display_size)?;
+        }
+
+        match price.or(reference_price) {
+            Some(price) => self.check_notional(price * order.size),
+            None => Ok(()),
+        }
+    }
+}
+
+fn decimals(value: Decimal) -> u32{
+    value.normalize().scale()
+}
+
+fn on_increment(value: Decimal, increment: Decimal) -> bool{
+    increment <= Decimal::ZERO || (value % increment).is_zero()
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matching_engine::types::BidOrAsk;
+
+    #[test]
+    fn test_spec_rejects_each_rule_separately() {
+        let spec = MarketSpec{
+            tick_size: Decimal::new(5, 1),
+            step_size: Decimal::new(1, 2),
+            min_quantity: Decimal::new(1, 1),
+            max_quantity: Some(Decimal::from(100)),
+            min_notional: Decimal::from(10),
+            base_precision: 4,
+            quote_precision: 2,
+            ..MarketSpec::default()
+        };
+        let bid = |size: Decimal| Order::new(BidOrAsk::Bid, size);
+
+        assert_eq!(spec.validate(&bid(Decimal::ONE), Some(Decimal::new(1005, 1)), None), Ok(()));
+        assert_eq!(spec.validate(&bid(Decimal::ONE), Some(Decimal::new(1003, 1)), None).unwrap_err().as_str(), "price_off_tick");
+        assert_eq!(spec.validate(&bid(Decimal::ONE), Some(Decimal::new(100001, 3)), None).unwrap_err().as_str(), "price_too_precise");
+        assert_eq!(spec.validate(&bid(Decimal::new(1005, 3)), Some(Decimal::from(100)), None).unwrap_err().as_str(), "quantity_off_step");
+        assert_eq!(spec.validate(&bid(Decimal::new(100001, 5)), Some(Decimal::from(100)), None).unwrap_err().as_str(), "quantity_too_precise");
+        assert_eq!(spec.validate(&bid(Decimal::new(5, 2)), Some(Decimal::from(1000)), None).unwrap_err().as_str(), "quantity_below_minimum");
+        assert_eq!(spec.validate(&bid(Decimal::from(101)), Some(Decimal::from(100)), None).unwrap_err().as_str(), "quantity_above_maximum");
+        assert_eq!(spec.validate(&bid(Decimal::new(5, 1)), Some(Decimal::from(10)), None).unwrap_err().as_str(), "notional_below_minimum");
+
+        // Market orders are held to the notional at the best opposite price
+        let market = Order::new_market(BidOrAsk::Bid, Decimal::new(5, 1));
+        assert_eq!(spec.validate(&market, None, Some(Decimal::from(10))).unwrap_err().as_str(), "notional_below_minimum");
+        assert_eq!(spec.validate(&market, None, None), Ok(()));
+
+        let quote_market = Order::new_quote_market(BidOrAsk::Bid, Decimal::new(10001, 3));
+        assert_eq!(spec.validate(&quote_market, None, None).unwrap_err().as_str(), "invalid_quote_quantity");
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::{BTreeMap, HashMap};
+use rust_decimal::Decimal;
+use uuid::Uuid;
+use serde::{Deserialize, Serialize};
+use crate::matching_engine::types::Order;
+
+
+// Which way the last trade price has to move for a stop to trigger
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TriggerDirection{
+    // Triggers once the last trade price is at or above the trigger price
+    Above,
+    // Triggers once the last trade price is at or below the trigger price
+    Below,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StopOrder{
+    pub order: Order,
+    pub trigger_price: Decimal,
+    // None for stop-market orders
+    pub limit_price: Option<Decimal>,
+    pub direction: TriggerDirection,
+}
+
+
+// Stops waiting for the last trade price of one market. Keys carry the placement
+// sequence so stops at the same trigger price keep their arrival order.
+#[derive(Debug, Clone, Default)]
+pub struct StopBook{
+    above: BTreeMap<(Decimal, u64), StopOrder>,
+    below: BTreeMap<(Decimal, u64), StopOrder>,
+    index: HashMap<Uuid, (TriggerDirection, Decimal, u64)>,
+    next_seq: u64,
+}
+
+impl StopBook{
+    pub fn new() -> StopBook{
+        StopBook::default()
+    }
+
+    pub fn len(&self) -> usize{
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.index.is_empty()
+    }
+
+    pub fn contains(&self, order_id: Uuid) -> bool{
+        self.index.contains_key(&order_id)
+    }
+
+    pub fn add(&mut self, order: Order, trigger_price: Decimal, limit_price: Option<Decimal>, direction: TriggerDirection){
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.index.insert(order.id, (direction, trigger_price, seq));
+        let stop = StopOrder{ order, trigger_price, limit_price, direction };
+
+        match direction{
+            TriggerDirection::Above => self.above.insert((trigger_price, seq), stop),
+            TriggerDirection::Below => self.below.insert((trigger_price, seq), stop),
+        };
+    }
+
+    // Waiting stops in the order they were placed
+    pub fn stops(&self) -> Vec<&StopOrder>{
+        let mut stops: Vec<(u64, &StopOrder)> = self.above.iter().chain(self.below.iter())
+            .map(|((_, seq), stop)| (*seq, stop))
+            .collect();
+
+        stops.sort_by_key(|(seq, _)| *seq);
+        stops.into_iter().map(|(_, stop)| stop).collect()
+    }
+
+    pub fn cancel(&mut self, order_id: Uuid) -> Option<StopOrder>{
+        let (direction, trigger_price, seq) = self.index.remove(&order_id)?;
+
+        match direction{
+            TriggerDirection::Above => self.above.remove(&(trigger_price, seq)),
+            TriggerDirection::Below => self.below.remove(&(trigger_price, seq)),
+        }
+    }
+
+    // Take the stop that `last_price` triggers, oldest first when several trigger at once.
+    // Callers pop one at a time so fills from a triggered stop can move the price in between.
+    pub fn pop_triggered(&mut self, last_price: Decimal) -> Option<StopOrder>{
+        let above = self.above.range(..=(last_price, u64::MAX)).map(|(key, _)| *key);
+        let below = self.below.range((last_price, 0)..).map(|(key, _)| *key);
+
+        let oldest_above = above.min_by_key(|(_, seq)| *seq);
+        let oldest_below = below.min_by_key(|(_, seq)| *seq);
+
+        let stop = match (oldest_above, oldest_below){
+            (Some(above), Some(below)) if below.1 < above.1 => self.below.remove(&below),
+            (Some(above), _) => self.above.remove(&above),
+            (None, Some(below)) => self.below.remove(&below),
+            (None, None) => None,
+        }?;
+
+        self.index.remove(&stop.order.id);
+        Some(stop)
+    }
+}
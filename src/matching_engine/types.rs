@@ -1,21 +1,146 @@
 use uuid::Uuid;
+use serde::{Deserialize, Serialize};
 use rust_decimal::Decimal;
 use chrono::{DateTime, Utc};
+use crate::matching_engine::queue::OrderQueue;
+use crate::balance::account_id;
 
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum BidOrAsk{
     Bid,
     Ask,
 }
 
+impl BidOrAsk{
+    pub fn as_str(&self) -> &'static str{
+        match self{
+            BidOrAsk::Bid => "buy",
+            BidOrAsk::Ask => "sell",
+        }
+    }
+}
 
-#[derive(Debug, Clone)]
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderType{
+    Limit,
+    Market,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeInForce{
+    // Good till cancelled: whatever is left after matching rests on the book
+    Gtc,
+    // Immediate or cancel: the unfilled part is cancelled
+    Ioc,
+    // Fill or kill: fills completely or does nothing
+    Fok,
+    // Only ever adds liquidity, rejected if it would take
+    PostOnly,
+}
+
+impl TimeInForce{
+    pub fn rests(&self) -> bool{
+        matches!(self, TimeInForce::Gtc | TimeInForce::PostOnly)
+    }
+}
+
+
+// What happens when an incoming order would match a resting order of the same user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SelfTradePrevention{
+    // Self-trades are allowed
+    None,
+    // Cancel the incoming order, the resting one stays
+    CancelNewest,
+    // Cancel the resting order and keep matching the incoming one
+    CancelOldest,
+    // Cancel both orders
+    CancelBoth,
+    // Reduce both by the smaller size and cancel whichever is left with nothing
+    DecrementAndCancel,
+}
+
+impl SelfTradePrevention{
+    pub fn as_str(&self) -> &'static str{
+        match self{
+            SelfTradePrevention::None => "none",
+            SelfTradePrevention::CancelNewest => "cancel_newest",
+            SelfTradePrevention::CancelOldest => "cancel_oldest",
+            SelfTradePrevention::CancelBoth => "cancel_both",
+            SelfTradePrevention::DecrementAndCancel => "decrement_and_cancel",
+        }
+    }
+}
+
+
+// Trading state of a market, changed at runtime by admin messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MarketStatus{
+    // Orders are accepted and matched
+    #[default]
+    Active,
+    // Nothing changes on the book, not even cancels
+    Halted,
+    // Resting orders can be cancelled, nothing new is accepted
+    CancelOnly,
+    // Every order was cancelled; the market only comes back by listing it again
+    Delisted,
+    // Call auction: limit orders collect without matching until the book is uncrossed
+    Auction,
+}
+
+impl MarketStatus{
+    pub fn as_str(&self) -> &'static str{
+        match self{
+            MarketStatus::Active => "active",
+            MarketStatus::Halted => "halted",
+            MarketStatus::CancelOnly => "cancel_only",
+            MarketStatus::Delisted => "delisted",
+            MarketStatus::Auction => "auction",
+        }
+    }
+
+    // Any live market can move to any other state; a delisted one only comes back as active
+    pub fn can_become(&self, next: MarketStatus) -> bool{
+        match (self, next){
+            (current, next) if *current == next => false,
+            (MarketStatus::Delisted, next) => next == MarketStatus::Active,
+            _ => true,
+        }
+    }
+
+    pub fn accepts_orders(&self) -> bool{
+        matches!(self, MarketStatus::Active | MarketStatus::Auction)
+    }
+
+    pub fn accepts_cancels(&self) -> bool{
+        matches!(self, MarketStatus::Active | MarketStatus::CancelOnly | MarketStatus::Auction)
+    }
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Order{
     pub id: Uuid,
     pub user_id: String,
     pub bid_or_ask : BidOrAsk,
     pub size: Decimal,
+    pub filled_size: Decimal,
+    pub order_type: OrderType,
+    // Remaining quote budget for market orders sized in the quote asset ("spend 1000 USD")
+    pub quote_size: Option<Decimal>,
+    pub time_in_force: TimeInForce,
+    // Overrides the market's self-trade prevention mode when set
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    // Icebergs show at most this much of their size on the book at a time
+    pub display_size: Option<Decimal>,
+    // The slice of an iceberg that is currently shown, refilled from the hidden rest
+    pub visible_size: Decimal,
+    // Sub-account of the user the order trades from, the main account when None
+    pub sub_account: Option<String>,
 }
 
 
@@ -26,6 +151,66 @@ impl Order{
             user_id: "user123".to_string(),
             bid_or_ask,
             size,
+            filled_size: Decimal::ZERO,
+            order_type: OrderType::Limit,
+            quote_size: None,
+            time_in_force: TimeInForce::Gtc,
+            self_trade_prevention: None,
+            display_size: None,
+            visible_size: Decimal::ZERO,
+            sub_account: None,
+        }
+    }
+
+    // Account whose balances the order reserves and settles against
+    pub fn account(&self) -> String {
+        account_id(&self.user_id, self.sub_account.as_deref())
+    }
+
+    pub fn new_market(bid_or_ask: BidOrAsk, size: Decimal) -> Order {
+        let mut order = Order::new(bid_or_ask, size);
+        order.order_type = OrderType::Market;
+        order
+    }
+
+    pub fn new_quote_market(bid_or_ask: BidOrAsk, quote_size: Decimal) -> Order {
+        let mut order = Order::new_market(bid_or_ask, Decimal::ZERO);
+        order.quote_size = Some(quote_size);
+        order
+    }
+
+    pub fn new_iceberg(bid_or_ask: BidOrAsk, size: Decimal, display_size: Decimal) -> Order {
+        let mut order = Order::new(bid_or_ask, size);
+        order.display_size = Some(display_size);
+        order
+    }
+
+    // What the rest of the book can see of this order
+    pub fn visible(&self) -> Decimal {
+        match self.display_size {
+            Some(_) => self.visible_size,
+            None => self.size,
+        }
+    }
+
+    // Show the next slice of an iceberg
+    pub fn refill(&mut self) {
+        if let Some(display_size) = self.display_size {
+            self.visible_size = display_size.min(self.size);
+        }
+    }
+
+    // Take quantity off the order without it trading, the shown slice shrinks with it
+    pub fn shrink_to(&mut self, size: Decimal) {
+        self.size = size;
+        self.visible_size = self.visible_size.min(size);
+    }
+
+    // Whether there is still something left to fill, in base or quote terms
+    pub fn has_remaining(&self) -> bool {
+        match self.quote_size {
+            Some(quote_size) => quote_size > Decimal::ZERO,
+            None => self.size > Decimal::ZERO,
         }
     }
 }
@@ -33,23 +218,34 @@ impl Order{
 #[derive(Debug, Clone)]
 pub struct Limit {
     pub price: Decimal,
-    pub orders: Vec<Order>,
+    pub orders: OrderQueue,
 }
 
 impl Limit{
     pub fn new(price: Decimal) -> Limit{
         Limit{
             price,
-            orders: Vec::new(),
+            orders: OrderQueue::new(),
         }
     }
 
 
-    pub fn add_order(&mut self, order: Order){
-        self.orders.push(order);
+    // Returns the queue slot the order was placed in
+    pub fn add_order(&mut self, order: Order) -> usize{
+        self.orders.push_back(order)
+    }
+
+    pub fn remove_order(&mut self, slot: usize) -> Option<Order>{
+        self.orders.remove(slot)
     }
 
+    // Displayed quantity only, the hidden part of icebergs is left out
     pub fn total_volume(&self) -> Decimal{
+        self.orders.iter().map(|o| o.visible()).sum()
+    }
+
+    // Everything that can trade at this level, hidden quantity included
+    pub fn total_size(&self) -> Decimal{
         self.orders.iter().map(|o| o.size).sum()
     }
 }
@@ -58,28 +254,64 @@ impl Limit{
 #[derive(Debug, Clone)]
 pub struct Trade{
     pub id: Uuid,
+    pub pair: TradingPair,
     pub buyer_order_id: Uuid,
     pub seller_order_id: Uuid,
+    pub buyer_user_id: String,
+    pub seller_user_id: String,
+    pub buyer_sub_account: Option<String>,
+    pub seller_sub_account: Option<String>,
+    pub maker_order_id: Uuid,
+    pub taker_order_id: Uuid,
+    // Side of the incoming order that took liquidity
+    pub taker_side: BidOrAsk,
     pub price: Decimal,
     pub quantity: Decimal,
     pub timestamp: DateTime<Utc>,
+    // Charged in base on what the buyer receives; negative for a maker rebate
+    pub buyer_fee: Decimal,
+    // Charged in quote on what the seller receives; negative for a maker rebate
+    pub seller_fee: Decimal,
 }
 
 
 impl Trade{
-    pub fn new(buyer_order_id: Uuid, seller_order_id: Uuid, price: Decimal, quantity: Decimal) -> Self{
+    pub fn new(pair: TradingPair, taker: &Order, maker: &Order, price: Decimal, quantity: Decimal, id: Uuid, timestamp: DateTime<Utc>) -> Self{
+        let (buyer, seller) = match taker.bid_or_ask{
+            BidOrAsk::Bid => (taker, maker),
+            BidOrAsk::Ask => (maker, taker),
+        };
+
         Trade{
-            id: Uuid::new_v4(),
-            buyer_order_id,
-            seller_order_id,
+            id,
+            pair,
+            buyer_order_id: buyer.id,
+            seller_order_id: seller.id,
+            buyer_user_id: buyer.user_id.clone(),
+            seller_user_id: seller.user_id.clone(),
+            buyer_sub_account: buyer.sub_account.clone(),
+            seller_sub_account: seller.sub_account.clone(),
+            maker_order_id: maker.id,
+            taker_order_id: taker.id,
+            taker_side: taker.bid_or_ask,
             price,
             quantity,
-            timestamp: Utc::now(),
+            timestamp,
+            buyer_fee: Decimal::ZERO,
+            seller_fee: Decimal::ZERO,
         }
     }
+
+    pub fn buyer_account(&self) -> String{
+        account_id(&self.buyer_user_id, self.buyer_sub_account.as_deref())
+    }
+
+    pub fn seller_account(&self) -> String{
+        account_id(&self.seller_user_id, self.seller_sub_account.as_deref())
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct TradingPair {
     pub base: String,
     pub quote: String,
@@ -89,4 +321,18 @@ impl TradingPair {
     pub fn new(base: String, quote: String) -> Self {
         TradingPair { base, quote }
     }
+
+    // Market symbol as used by the API and Redis, e.g. "BTC_USD"
+    pub fn symbol(&self) -> String {
+        format!("{}_{}", self.base, self.quote)
+    }
+
+    pub fn from_symbol(symbol: &str) -> Option<TradingPair> {
+        let (base, quote) = symbol.split_once('_')?;
+        if base.is_empty() || quote.is_empty() || quote.contains('_') {
+            return None;
+        }
+
+        Some(TradingPair::new(base.to_string(), quote.to_string()))
+    }
 }
\ No newline at end of file
@@ -1,7 +1,12 @@
 use serde::{Deserialize, Serialize};
 use rust_decimal::Decimal;
 use uuid::Uuid;
-use crate::matching_engine::types::{Trade, TradingPair, Order, BidOrAsk};
+use crate::matching_engine::types::{Trade, TradingPair, Order, BidOrAsk, TimeInForce, SelfTradePrevention, MarketStatus};
+use crate::matching_engine::messages::EngineMessage;
+use crate::matching_engine::orderbook::PreventedMatch;
+use crate::matching_engine::spec::MarketSpec;
+use crate::matching_engine::invariants::Violation;
+use crate::websocket::events::{OrderStatus, PriceLevel};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RedisOrderRequest{
@@ -11,7 +16,93 @@ pub struct RedisOrderRequest{
     pub side: String,
     pub order_type: String,
     pub price: Option<Decimal>,
+    #[serde(default)]
     pub quantity: Decimal,
+    // Makes this a stop: held until the last trade price reaches it
+    #[serde(default)]
+    pub trigger_price: Option<Decimal>,
+    // Iceberg orders only show this much of their quantity at a time
+    #[serde(default)]
+    pub display_quantity: Option<Decimal>,
+    // Market orders may be sized in the quote asset instead ("spend 1000 USD")
+    #[serde(default)]
+    pub quote_quantity: Option<Decimal>,
+    // "gtc" (default), "ioc", "fok" or "post_only"
+    #[serde(default)]
+    pub time_in_force: Option<String>,
+    // "none", "cancel_newest", "cancel_oldest", "cancel_both" or "decrement_and_cancel";
+    // the market default applies when missing
+    #[serde(default)]
+    pub self_trade_prevention: Option<String>,
+    // Trade from this sub-account of the user instead of the main account
+    #[serde(default)]
+    pub sub_account: Option<String>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedisAmendRequest{
+    pub order_id: String,
+    pub market: String,
+    pub user_id: String,
+    pub price: Option<Decimal>,
+    pub quantity: Option<Decimal>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+// "list", "halt", "resume", "cancel_only", "delist", "auction", "uncross" or "set_reference_price",
+// or "check_invariants", which covers every market and ignores `market`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedisAdminRequest{
+    pub market: String,
+    pub action: String,
+    // New reference price for "set_reference_price"
+    #[serde(default)]
+    pub price: Option<Decimal>,
+    // Rules for a market being listed; the defaults apply when missing
+    #[serde(default)]
+    pub spec: Option<MarketSpec>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+// "request" a new withdrawal, or "approve" / "reject" a pending one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedisWithdrawalRequest{
+    pub withdrawal_id: String,
+    pub action: String,
+    // The rest is only needed for "request"
+    #[serde(default)]
+    pub user_id: Option<String>,
+    #[serde(default)]
+    pub asset: Option<String>,
+    #[serde(default)]
+    pub amount: Option<Decimal>,
+    #[serde(default)]
+    pub address: Option<String>,
+    // Shown to the user when an operator rejects the withdrawal
+    #[serde(default)]
+    pub reason: Option<String>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedisTransferRequest{
+    pub transfer_id: String,
+    pub user_id: String,
+    #[serde(default)]
+    pub from_sub_account: Option<String>,
+    pub to_user_id: String,
+    #[serde(default)]
+    pub to_sub_account: Option<String>,
+    pub asset: String,
+    pub amount: Decimal,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedisSubAccountRequest{
+    pub user_id: String,
+    pub name: String,
     pub timestamp: chrono::DateTime<chrono::Utc>,
 }
 
@@ -21,7 +112,27 @@ pub struct RedisOrderResponse {
     pub success: bool,
     pub order_id: Option<Uuid>,
     pub trades: Vec<RedisTradeInfo>,
+    pub status: Option<OrderStatus>,
+    pub filled_quantity: Option<Decimal>,
+    pub remaining_quantity: Option<Decimal>,
+    #[serde(default)]
+    pub self_trade_prevented: Vec<RedisSelfTradeInfo>,
     pub error: Option<String>,
+    // Which market rule a rejected order broke, e.g. "price_off_tick"
+    #[serde(default)]
+    pub reject_reason: Option<String>,
+}
+
+// A match against the user's own resting order that self-trade prevention stopped
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedisSelfTradeInfo {
+    pub mode: String,
+    pub maker_order_id: String,
+    pub price: Decimal,
+    pub maker_removed: Decimal,
+    pub taker_removed: Decimal,
+    pub maker_cancelled: bool,
+    pub taker_cancelled: bool,
 }
 
 
@@ -29,11 +140,76 @@ pub struct RedisOrderResponse {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RedisTradeInfo {
     pub trade_id: String,
+    pub market: String,
     pub price: Decimal,
     pub quantity: Decimal,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    // Side of the taker, "buy" or "sell"
+    pub side: String,
     pub buyer_order_id: String,
     pub seller_order_id: String,
+    pub buyer_user_id: String,
+    pub seller_user_id: String,
+    pub buyer_sub_account: Option<String>,
+    pub seller_sub_account: Option<String>,
+    pub maker_order_id: String,
+    pub taker_order_id: String,
+    // Buyer's fee in base, seller's fee in quote; negative for rebates
+    pub buyer_fee: Decimal,
+    pub seller_fee: Decimal,
+}
+
+// Latest book of a market, kept under "depth:{market}"
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedisDepthSnapshot{
+    pub market: String,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+// Current status of every market, kept in the "markets" hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedisMarketStatus{
+    pub market: String,
+    pub status: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+// Result of the latest invariant check, kept under "invariant_report"
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedisInvariantReport{
+    pub violations: Vec<Violation>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+// Indicative uncross of a running call auction, kept under "auction:{market}"
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedisAuction{
+    pub market: String,
+    pub price: Option<Decimal>,
+    pub volume: Decimal,
+    pub surplus: Decimal,
+    pub ends_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+// How a call auction ended, published as an "uncross" market update
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedisUncross{
+    pub market: String,
+    pub price: Option<Decimal>,
+    pub volume: Decimal,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+// Trading rules of every market, kept in the "market_specs" hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedisMarketSpec{
+    pub market: String,
+    pub base: String,
+    pub quote: String,
+    pub spec: MarketSpec,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,13 +221,8 @@ pub struct RedisMarketUpdate{
 }
 
 impl RedisOrderRequest{
-    pub fn to_engine_message(&self) -> Result<(TradingPair, Decimal,Order), String>{
-        let parts: Vec<&str> = self.market.split('_').collect();
-        if parts.len() != 2{
-            return Err("Invalid market format".to_string());
-        }
-
-        let pair = TradingPair::new(parts[0].to_string(), parts[1].to_string());
+    pub fn to_engine_message(&self) -> Result<EngineMessage, String>{
+        let pair = TradingPair::from_symbol(&self.market).ok_or("Invalid market format")?;
 
         let side = match self.side.as_str(){
             "buy" => BidOrAsk::Bid,
@@ -59,17 +230,126 @@ impl RedisOrderRequest{
             _ => return Err("Invalid side".to_string()),
         };
 
-        let price = match self.order_type.as_str(){
-            "limit" => self.price.ok_or("Price is required for limit orders")?,
-            "market" => Decimal::from(0),
+        let (price, mut order) = match (self.order_type.as_str(), self.quote_quantity){
+            ("limit", None) => (Some(self.price.ok_or("Price is required for limit orders")?), Order::new(side, self.quantity)),
+            ("limit", Some(_)) => return Err("Quote quantity is only supported for market orders".to_string()),
+            ("market", None) => (None, Order::new_market(side, self.quantity)),
+            ("market", Some(quote_quantity)) => (None, Order::new_quote_market(side, quote_quantity)),
             _ => return Err("Invalid order type".to_string()),
         };
 
-
-        let mut order = Order::new(side, self.quantity);
+        order.id = Uuid::parse_str(&self.id).map_err(|_| "Invalid order id".to_string())?;
         order.user_id = self.user_id.clone();
+        order.sub_account = self.sub_account.clone();
+        order.display_size = self.display_quantity;
+        order.time_in_force = match self.time_in_force.as_deref(){
+            None | Some("gtc") => TimeInForce::Gtc,
+            Some("ioc") => TimeInForce::Ioc,
+            Some("fok") => TimeInForce::Fok,
+            Some("post_only") => TimeInForce::PostOnly,
+            Some(_) => return Err("Invalid time in force".to_string()),
+        };
+        order.self_trade_prevention = match self.self_trade_prevention.as_deref(){
+            None => None,
+            Some("none") => Some(SelfTradePrevention::None),
+            Some("cancel_newest") => Some(SelfTradePrevention::CancelNewest),
+            Some("cancel_oldest") => Some(SelfTradePrevention::CancelOldest),
+            Some("cancel_both") => Some(SelfTradePrevention::CancelBoth),
+            Some("decrement_and_cancel") => Some(SelfTradePrevention::DecrementAndCancel),
+            Some(_) => return Err("Invalid self-trade prevention mode".to_string()),
+        };
 
-        Ok((pair, price, order))
+        match self.trigger_price{
+            Some(trigger_price) => Ok(EngineMessage::PlaceStopOrder{ pair, order, trigger_price, limit_price: price }),
+            None => Ok(EngineMessage::PlaceOrder{ pair, order, price }),
+        }
+    }
+}
+
+impl RedisAmendRequest{
+    pub fn to_engine_message(&self) -> Result<EngineMessage, String>{
+        let order_id = Uuid::parse_str(&self.order_id).map_err(|_| "Invalid order id".to_string())?;
+
+        let pair = TradingPair::from_symbol(&self.market).ok_or("Invalid market format")?;
+
+        Ok(EngineMessage::AmendOrder{
+            pair,
+            order_id,
+            user_id: self.user_id.clone(),
+            price: self.price,
+            size: self.quantity,
+        })
+    }
+}
+
+impl RedisAdminRequest{
+    pub fn to_engine_message(&self) -> Result<EngineMessage, String>{
+        if self.action == "check_invariants" {
+            return Ok(EngineMessage::CheckInvariants);
+        }
+
+        let pair = TradingPair::from_symbol(&self.market).ok_or("Invalid market format")?;
+
+        let status = match self.action.as_str(){
+            "list" => return Ok(EngineMessage::ListMarket{ pair, spec: self.spec.clone().unwrap_or_default() }),
+            "set_reference_price" => {
+                let price = self.price.ok_or("Price is required for set_reference_price")?;
+                return Ok(EngineMessage::SetReferencePrice{ pair, price });
+            }
+            "uncross" => return Ok(EngineMessage::Uncross{ pair }),
+            "auction" => MarketStatus::Auction,
+            "halt" => MarketStatus::Halted,
+            "resume" => MarketStatus::Active,
+            "cancel_only" => MarketStatus::CancelOnly,
+            "delist" => MarketStatus::Delisted,
+            _ => return Err("Invalid market action".to_string()),
+        };
+
+        Ok(EngineMessage::SetMarketStatus{ pair, status })
+    }
+}
+
+impl RedisWithdrawalRequest{
+    pub fn to_engine_message(&self) -> Result<EngineMessage, String>{
+        let withdrawal_id = Uuid::parse_str(&self.withdrawal_id).map_err(|_| "Invalid withdrawal id".to_string())?;
+
+        match self.action.as_str(){
+            "request" => Ok(EngineMessage::RequestWithdrawal{
+                withdrawal_id,
+                user_id: self.user_id.clone().ok_or("User is required for a withdrawal")?,
+                asset: self.asset.clone().ok_or("Asset is required for a withdrawal")?,
+                amount: self.amount.ok_or("Amount is required for a withdrawal")?,
+                address: self.address.clone().ok_or("Address is required for a withdrawal")?,
+            }),
+            "approve" => Ok(EngineMessage::ApproveWithdrawal{ withdrawal_id }),
+            "reject" => Ok(EngineMessage::FailWithdrawal{
+                withdrawal_id,
+                reason: self.reason.clone().unwrap_or_else(|| "Rejected by an operator".to_string()),
+            }),
+            _ => Err("Invalid withdrawal action".to_string()),
+        }
+    }
+}
+
+impl RedisTransferRequest{
+    pub fn to_engine_message(&self) -> Result<EngineMessage, String>{
+        let transfer_id = Uuid::parse_str(&self.transfer_id).map_err(|_| "Invalid transfer id".to_string())?;
+
+        Ok(EngineMessage::Transfer{
+            transfer_id,
+            user_id: self.user_id.clone(),
+            from_sub_account: self.from_sub_account.clone(),
+            to_user_id: self.to_user_id.clone(),
+            to_sub_account: self.to_sub_account.clone(),
+            asset: self.asset.clone(),
+            amount: self.amount,
+        })
+    }
+}
+
+impl RedisSubAccountRequest{
+    pub fn to_engine_message(&self) -> EngineMessage{
+        EngineMessage::CreateSubAccount{ user_id: self.user_id.clone(), name: self.name.clone() }
     }
 }
 
@@ -78,13 +358,35 @@ impl From<&Trade> for RedisTradeInfo{
     fn from(trade: &Trade) -> Self{
         RedisTradeInfo{
             trade_id: trade.id.to_string(),
+            market: trade.pair.symbol(),
             price: trade.price,
             quantity: trade.quantity,
             timestamp: trade.timestamp,
+            side: trade.taker_side.as_str().to_string(),
             buyer_order_id: trade.buyer_order_id.to_string(),
             seller_order_id: trade.seller_order_id.to_string(),
+            buyer_user_id: trade.buyer_user_id.clone(),
+            seller_user_id: trade.seller_user_id.clone(),
+            buyer_sub_account: trade.buyer_sub_account.clone(),
+            seller_sub_account: trade.seller_sub_account.clone(),
+            maker_order_id: trade.maker_order_id.to_string(),
+            taker_order_id: trade.taker_order_id.to_string(),
+            buyer_fee: trade.buyer_fee,
+            seller_fee: trade.seller_fee,
         }
     }
 }
 
-
+impl From<&PreventedMatch> for RedisSelfTradeInfo{
+    fn from(prevented: &PreventedMatch) -> Self{
+        RedisSelfTradeInfo{
+            mode: prevented.mode.as_str().to_string(),
+            maker_order_id: prevented.maker.id.to_string(),
+            price: prevented.price,
+            maker_removed: prevented.maker_removed,
+            taker_removed: prevented.taker_removed,
+            maker_cancelled: prevented.maker_cancelled,
+            taker_cancelled: prevented.taker_cancelled,
+        }
+    }
+}
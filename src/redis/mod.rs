@@ -4,7 +4,8 @@ use redis::{AsyncCommands, Client};
 use serde_json;
 use crossbeam::channel::{Receiver, Sender};
 use crate::matching_engine::messages::{EngineMessage, EngineResponse};
-use self::message::{RedisOrderRequest, RedisOrderResponse, RedisMarketUpdate, RedisTradeInfo};
+use crate::matching_engine::types::MarketStatus;
+use self::message::{RedisOrderRequest, RedisAmendRequest, RedisAdminRequest, RedisOrderResponse, RedisMarketUpdate, RedisTradeInfo, RedisSelfTradeInfo, RedisDepthSnapshot, RedisMarketStatus, RedisMarketSpec, RedisAuction, RedisUncross, RedisWithdrawalRequest, RedisTransferRequest, RedisSubAccountRequest, RedisInvariantReport};
 
 pub struct RedisService {
     client: Client,
@@ -37,13 +38,19 @@ impl RedisService {
                 Ok(mut response_con) => {
                     while let Ok(response) = response_receiver.recv() {
                         match response {
-                            EngineResponse::OrderPlaced { order_id, trades } => {
+                            EngineResponse::OrderPlaced { order_id, trades, status, filled_size, remaining_size, self_trade_prevented }
+                            | EngineResponse::OrderAmended { order_id, trades, status, filled_size, remaining_size, self_trade_prevented } => {
                                 let redis_response = RedisOrderResponse {
                                     request_id: order_id,
                                     success: true,
                                     order_id: Some(order_id),
                                     trades: trades.iter().map(RedisTradeInfo::from).collect(),
+                                    status: Some(status),
+                                    filled_quantity: Some(filled_size),
+                                    remaining_quantity: Some(remaining_size),
+                                    self_trade_prevented: self_trade_prevented.iter().map(RedisSelfTradeInfo::from).collect(),
                                     error: None,
+                                    reject_reason: None,
                                 };
 
                                 if let Ok(json_response) = serde_json::to_string(&redis_response) {
@@ -52,7 +59,7 @@ impl RedisService {
 
                                 for trade in trades {
                                     let market_update = RedisMarketUpdate {
-                                        market: "BTC_USD".to_string(),
+                                        market: trade.pair.symbol(),
                                         data: serde_json::to_value(&RedisTradeInfo::from(&trade)).unwrap(),
                                         update_type: "trade".to_string(),
                                         timestamp: chrono::Utc::now(),
@@ -70,7 +77,31 @@ impl RedisService {
                                     success: false,
                                     order_id: None,
                                     trades: vec![],
+                                    status: None,
+                                    filled_quantity: None,
+                                    remaining_quantity: None,
+                                    self_trade_prevented: Vec::new(),
                                     error: Some(message),
+                                    reject_reason: None,
+                                };
+
+                                if let Ok(json) = serde_json::to_string(&redis_response) {
+                                    let _: Result<(), _> = response_con.publish("order_response", json).await;
+                                }
+                            }
+
+                            EngineResponse::OrderRejected { order_id, reason } => {
+                                let redis_response = RedisOrderResponse {
+                                    request_id: order_id,
+                                    success: false,
+                                    order_id: Some(order_id),
+                                    trades: vec![],
+                                    status: None,
+                                    filled_quantity: None,
+                                    remaining_quantity: None,
+                                    self_trade_prevented: Vec::new(),
+                                    error: Some(reason.message()),
+                                    reject_reason: Some(reason.as_str().to_string()),
                                 };
 
                                 if let Ok(json) = serde_json::to_string(&redis_response) {
@@ -80,6 +111,160 @@ impl RedisService {
                             
                             EngineResponse::OrderCancelled { .. } => {
                             }
+
+                            EngineResponse::SnapshotTaken { seq, path } => {
+                                println!("Engine snapshot at sequence {} written to {}", seq, path);
+                            }
+
+                            EngineResponse::DepthUpdated { pair, bids, asks } => {
+                                let depth = RedisDepthSnapshot {
+                                    market: pair.symbol(),
+                                    bids,
+                                    asks,
+                                    timestamp: chrono::Utc::now(),
+                                };
+
+                                if let Ok(json) = serde_json::to_string(&depth) {
+                                    let _: Result<(), _> = response_con.set(format!("depth:{}", depth.market), &json).await;
+                                }
+
+                                let market_update = RedisMarketUpdate {
+                                    market: depth.market.clone(),
+                                    data: serde_json::to_value(&depth).unwrap(),
+                                    update_type: "depth".to_string(),
+                                    timestamp: depth.timestamp,
+                                };
+
+                                if let Ok(json) = serde_json::to_string(&market_update) {
+                                    let _: Result<(), _> = response_con.publish("market_updates", json).await;
+                                }
+                            }
+
+                            EngineResponse::MarketSpecChanged { pair, spec } => {
+                                let market_spec = RedisMarketSpec {
+                                    market: pair.symbol(),
+                                    base: pair.base,
+                                    quote: pair.quote,
+                                    spec,
+                                };
+
+                                if let Ok(json) = serde_json::to_string(&market_spec) {
+                                    let _: Result<(), _> = response_con.hset("market_specs", &market_spec.market, &json).await;
+                                }
+                            }
+
+                            EngineResponse::MarketStatusChanged { pair, status } => {
+                                let market_status = RedisMarketStatus {
+                                    market: pair.symbol(),
+                                    status: status.as_str().to_string(),
+                                    timestamp: chrono::Utc::now(),
+                                };
+
+                                if let Ok(json) = serde_json::to_string(&market_status) {
+                                    let _: Result<(), _> = response_con.hset("markets", &market_status.market, &json).await;
+                                }
+
+                                // The indicative price only means something while the auction runs
+                                if status != MarketStatus::Auction {
+                                    let _: Result<(), _> = response_con.del(format!("auction:{}", market_status.market)).await;
+                                }
+
+                                let market_update = RedisMarketUpdate {
+                                    market: market_status.market.clone(),
+                                    data: serde_json::to_value(&market_status).unwrap(),
+                                    update_type: "status".to_string(),
+                                    timestamp: market_status.timestamp,
+                                };
+
+                                if let Ok(json) = serde_json::to_string(&market_update) {
+                                    let _: Result<(), _> = response_con.publish("market_updates", json).await;
+                                }
+                            }
+
+                            EngineResponse::SubAccountCreated { user_id, name } => {
+                                let _: Result<(), _> = response_con.sadd(format!("sub_accounts:{}", user_id), name).await;
+                            }
+
+                            // Both users see a transfer in their "transfers:{user_id}" hash, by id
+                            EngineResponse::TransferProcessed { transfer } => {
+                                if let Ok(json) = serde_json::to_string(&transfer) {
+                                    let _: Result<(), _> = response_con.hset(format!("transfers:{}", transfer.user_id), transfer.id.to_string(), &json).await;
+                                    if transfer.to_user_id != transfer.user_id {
+                                        let _: Result<(), _> = response_con.hset(format!("transfers:{}", transfer.to_user_id), transfer.id.to_string(), &json).await;
+                                    }
+                                }
+                            }
+
+                            // Deposit history of every user lives in "deposits:{user_id}", by tx hash
+                            EngineResponse::DepositCredited { deposit } => {
+                                if let Ok(json) = serde_json::to_string(&deposit) {
+                                    let _: Result<(), _> = response_con.hset(format!("deposits:{}", deposit.user_id), &deposit.tx_hash, json).await;
+                                }
+                            }
+
+                            // Latest state of every withdrawal, in the "withdrawals" hash by id
+                            EngineResponse::WithdrawalUpdated { withdrawal } => {
+                                if let Ok(json) = serde_json::to_string(&withdrawal) {
+                                    let _: Result<(), _> = response_con.hset("withdrawals", withdrawal.id.to_string(), json).await;
+                                }
+                            }
+
+                            EngineResponse::InvariantsChecked { violations } => {
+                                let report = RedisInvariantReport {
+                                    violations,
+                                    timestamp: chrono::Utc::now(),
+                                };
+
+                                if let Ok(json) = serde_json::to_string(&report) {
+                                    let _: Result<(), _> = response_con.set("invariant_report", &json).await;
+                                }
+                            }
+
+                            EngineResponse::AuctionIndicative { pair, auction, ends_at } => {
+                                let redis_auction = RedisAuction {
+                                    market: pair.symbol(),
+                                    price: auction.map(|auction| auction.price),
+                                    volume: auction.map(|auction| auction.volume).unwrap_or_default(),
+                                    surplus: auction.map(|auction| auction.surplus).unwrap_or_default(),
+                                    ends_at,
+                                    timestamp: chrono::Utc::now(),
+                                };
+
+                                if let Ok(json) = serde_json::to_string(&redis_auction) {
+                                    let _: Result<(), _> = response_con.set(format!("auction:{}", redis_auction.market), &json).await;
+                                }
+
+                                let market_update = RedisMarketUpdate {
+                                    market: redis_auction.market.clone(),
+                                    data: serde_json::to_value(&redis_auction).unwrap(),
+                                    update_type: "auction".to_string(),
+                                    timestamp: redis_auction.timestamp,
+                                };
+
+                                if let Ok(json) = serde_json::to_string(&market_update) {
+                                    let _: Result<(), _> = response_con.publish("market_updates", json).await;
+                                }
+                            }
+
+                            EngineResponse::AuctionUncrossed { pair, price, volume } => {
+                                let uncross = RedisUncross {
+                                    market: pair.symbol(),
+                                    price,
+                                    volume,
+                                    timestamp: chrono::Utc::now(),
+                                };
+
+                                let market_update = RedisMarketUpdate {
+                                    market: uncross.market.clone(),
+                                    data: serde_json::to_value(&uncross).unwrap(),
+                                    update_type: "uncross".to_string(),
+                                    timestamp: uncross.timestamp,
+                                };
+
+                                if let Ok(json) = serde_json::to_string(&market_update) {
+                                    let _: Result<(), _> = response_con.publish("market_updates", json).await;
+                                }
+                            }
                         }
                     }
                 }
@@ -89,21 +274,35 @@ impl RedisService {
         });
 
         loop {
-            match con.blpop::<_, Vec<String>>("order_queue", 0.0).await {
+            match con.blpop::<_, Vec<String>>(&["order_queue", "amend_queue", "admin_queue", "withdrawal_queue", "transfer_queue", "sub_account_queue"], 0.0).await {
                 Ok(result) => {
                     if result.len() >= 2 {
+                        let queue = result[0].as_str();
                         let json_data = &result[1];
 
-                        if let Ok(order_request) = serde_json::from_str::<RedisOrderRequest>(json_data) {
-                            if let Ok((pair, price, order)) = order_request.to_engine_message() {
-                                let engine_message = EngineMessage::PlaceOrder { 
-                                    pair, 
-                                    price, 
-                                    order 
-                                };
+                        let engine_message = match queue {
+                            "amend_queue" => serde_json::from_str::<RedisAmendRequest>(json_data)
+                                .ok()
+                                .and_then(|amend_request| amend_request.to_engine_message().ok()),
+                            "admin_queue" => serde_json::from_str::<RedisAdminRequest>(json_data)
+                                .ok()
+                                .and_then(|admin_request| admin_request.to_engine_message().ok()),
+                            "withdrawal_queue" => serde_json::from_str::<RedisWithdrawalRequest>(json_data)
+                                .ok()
+                                .and_then(|withdrawal_request| withdrawal_request.to_engine_message().ok()),
+                            "transfer_queue" => serde_json::from_str::<RedisTransferRequest>(json_data)
+                                .ok()
+                                .and_then(|transfer_request| transfer_request.to_engine_message().ok()),
+                            "sub_account_queue" => serde_json::from_str::<RedisSubAccountRequest>(json_data)
+                                .ok()
+                                .map(|sub_account_request| sub_account_request.to_engine_message()),
+                            _ => serde_json::from_str::<RedisOrderRequest>(json_data)
+                                .ok()
+                                .and_then(|order_request| order_request.to_engine_message().ok()),
+                        };
 
-                                let _ = self.order_sender.send(engine_message);
-                            }
+                        if let Some(engine_message) = engine_message {
+                            let _ = self.order_sender.send(engine_message);
                         }
                     }
                 }
@@ -0,0 +1,355 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::thread::{self, JoinHandle};
+use std::time::Duration as StdDuration;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use crossbeam::channel::{Receiver, Sender, select, tick};
+use redis::{Client, Commands};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use crate::matching_engine::messages::EngineMessage;
+use crate::matching_engine::types::Trade;
+
+// Redis keys the volume tracker is saved under, so the window survives a restart:
+// a hash of every user's `UserVolume` and the reference prices
+const VOLUMES_KEY: &str = "fee_tier_volumes";
+const PRICES_KEY: &str = "fee_tier_prices";
+
+// VIP tiers by trailing traded volume, measured in `reference_asset`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TierSchedule{
+    pub reference_asset: String,
+    pub window_days: i64,
+    // tier -> volume a user needs to reach it; tier 0 needs nothing
+    pub thresholds: BTreeMap<u32, Decimal>,
+}
+
+// Where a user stands, as shown on the account endpoint
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TierStatus{
+    pub user_id: String,
+    pub tier: u32,
+    pub volume: Decimal,
+    pub reference_asset: String,
+    // None on the top tier
+    pub next_tier: Option<u32>,
+    pub volume_to_next_tier: Option<Decimal>,
+}
+
+impl TierSchedule{
+    pub fn tier_for(&self, volume: Decimal) -> u32{
+        self.thresholds.iter()
+            .filter(|(_, threshold)| volume >= **threshold)
+            .map(|(tier, _)| *tier)
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn status(&self, user_id: &str, volume: Decimal) -> TierStatus{
+        let tier = self.tier_for(volume);
+        let next = self.thresholds.iter().find(|(next_tier, threshold)| **next_tier > tier && **threshold > volume);
+
+        TierStatus{
+            user_id: user_id.to_string(),
+            tier,
+            volume,
+            reference_asset: self.reference_asset.clone(),
+            next_tier: next.map(|(next_tier, _)| *next_tier),
+            volume_to_next_tier: next.map(|(_, threshold)| *threshold - volume),
+        }
+    }
+}
+
+
+// Volume of one user per day still in the window, and the tier they were last given
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UserVolume{
+    pub days: BTreeMap<NaiveDate, Decimal>,
+    pub tier: u32,
+}
+
+impl UserVolume{
+    pub fn volume(&self) -> Decimal{
+        self.days.values().sum()
+    }
+}
+
+// Rolling traded volume of every user over the schedule's window, in whole days. Both sides
+// of a trade count its notional. Trades quoted in another asset are converted at the last
+// price of that asset against the reference, and skipped until one is known.
+#[derive(Debug)]
+pub struct VolumeTracker{
+    schedule: TierSchedule,
+    // Users with volume in the window or a tier above 0
+    users: HashMap<String, UserVolume>,
+    // asset -> last price in the reference asset
+    reference_prices: HashMap<String, Decimal>,
+    // Users whose volume or tier changed since `take_changed`
+    changed: BTreeSet<String>,
+}
+
+impl VolumeTracker{
+    pub fn new(schedule: TierSchedule) -> VolumeTracker{
+        VolumeTracker{
+            schedule,
+            users: HashMap::new(),
+            reference_prices: HashMap::new(),
+            changed: BTreeSet::new(),
+        }
+    }
+
+    pub fn schedule(&self) -> &TierSchedule{
+        &self.schedule
+    }
+
+    pub fn record_trade(&mut self, trade: &Trade){
+        if trade.pair.quote == self.schedule.reference_asset {
+            self.reference_prices.insert(trade.pair.base.clone(), trade.price);
+        }
+
+        let notional = trade.quantity * trade.price;
+        let volume = match self.reference_prices.get(&trade.pair.quote) {
+            _ if trade.pair.quote == self.schedule.reference_asset => notional,
+            Some(quote_price) => notional * quote_price,
+            None => return,
+        };
+
+        for user_id in [&trade.buyer_user_id, &trade.seller_user_id] {
+            let user = self.users.entry(user_id.clone()).or_default();
+            *user.days.entry(trade.timestamp.date_naive()).or_default() += volume;
+            self.changed.insert(user_id.clone());
+        }
+    }
+
+    pub fn volume(&self, user_id: &str) -> Decimal{
+        self.users.get(user_id).map(UserVolume::volume).unwrap_or_default()
+    }
+
+    pub fn user(&self, user_id: &str) -> Option<&UserVolume>{
+        self.users.get(user_id)
+    }
+
+    pub fn status(&self, user_id: &str) -> TierStatus{
+        self.schedule.status(user_id, self.volume(user_id))
+    }
+
+    // Drop days that left the window and work out every user's tier again. Users left
+    // without volume on tier 0 are forgotten. Returns the users whose tier changed, with
+    // the new tier.
+    pub fn recalculate(&mut self, now: DateTime<Utc>) -> Vec<(String, u32)>{
+        let window_start = now.checked_sub_signed(Duration::days(self.schedule.window_days)).unwrap_or(DateTime::<Utc>::MIN_UTC).date_naive();
+        let mut users: Vec<String> = self.users.keys().cloned().collect();
+        users.sort();
+
+        let mut changes = Vec::new();
+        for user_id in users {
+            let user = self.users.get_mut(&user_id).expect("user is tracked");
+            let kept = user.days.split_off(&window_start);
+            if !user.days.is_empty() {
+                self.changed.insert(user_id.clone());
+            }
+            user.days = kept;
+
+            let tier = self.schedule.tier_for(user.volume());
+            if tier != user.tier {
+                user.tier = tier;
+                changes.push((user_id.clone(), tier));
+                self.changed.insert(user_id.clone());
+            }
+
+            if user.days.is_empty() && user.tier == 0 {
+                self.users.remove(&user_id);
+            }
+        }
+        changes
+    }
+
+    // Users whose volume or tier changed since the last call, forgotten ones included
+    pub fn take_changed(&mut self) -> Vec<String>{
+        std::mem::take(&mut self.changed).into_iter().collect()
+    }
+
+    // Pick up a user saved before a restart
+    pub fn restore(&mut self, user_id: String, user: UserVolume){
+        self.changed.insert(user_id.clone());
+        self.users.insert(user_id, user);
+    }
+
+    pub fn reference_prices(&self) -> &HashMap<String, Decimal>{
+        &self.reference_prices
+    }
+
+    pub fn set_reference_prices(&mut self, reference_prices: HashMap<String, Decimal>){
+        self.reference_prices = reference_prices;
+    }
+}
+
+
+// Side service that follows executed trades and moves users between fee tiers. Tier changes
+// go to the engine as SetFeeTier messages, so they are journaled like everything else.
+// With Redis, the volumes of the users that changed are saved on every recalculation and
+// picked up again on start, so a restart only loses the trades since the last one.
+pub struct TierService{
+    tracker: VolumeTracker,
+    trade_receiver: Receiver<Vec<Trade>>,
+    engine_sender: Sender<EngineMessage>,
+    redis_client: Option<Client>,
+    interval: StdDuration,
+}
+
+impl TierService{
+    pub fn new(schedule: TierSchedule, trade_receiver: Receiver<Vec<Trade>>, engine_sender: Sender<EngineMessage>, interval: StdDuration) -> TierService{
+        TierService{
+            tracker: VolumeTracker::new(schedule),
+            trade_receiver,
+            engine_sender,
+            redis_client: None,
+            interval,
+        }
+    }
+
+    // Publish the status of every user with volume or a tier to the "fee_tiers" hash and the
+    // schedule to "fee_tier_schedule", for the account endpoint
+    pub fn with_redis(mut self, redis_url: &str) -> Result<TierService, redis::RedisError>{
+        self.redis_client = Some(Client::open(redis_url)?);
+        Ok(self)
+    }
+
+    pub fn start(mut self) -> JoinHandle<()>{
+        thread::Builder::new()
+            .name("fee-tiers".to_string())
+            .spawn(move || {
+                self.load_state();
+                self.publish_schedule();
+                let ticker = tick(self.interval);
+
+                loop {
+                    select! {
+                        recv(self.trade_receiver) -> trades => match trades {
+                            Ok(trades) => trades.iter().for_each(|trade| self.tracker.record_trade(trade)),
+                            Err(_) => return,
+                        },
+                        recv(ticker) -> _ => self.recalculate(Utc::now()),
+                    }
+                }
+            })
+            .expect("Failed to spawn fee tier service")
+    }
+
+    fn recalculate(&mut self, now: DateTime<Utc>){
+        for (user_id, tier) in self.tracker.recalculate(now) {
+            let _ = self.engine_sender.send(EngineMessage::SetFeeTier{ user_id, tier });
+        }
+
+        let Some(mut con) = self.redis_client.as_ref().and_then(|client| client.get_connection().ok()) else {
+            return;
+        };
+        // Users the tracker forgot are back on tier 0, which the endpoint shows for anyone missing
+        for user_id in self.tracker.take_changed() {
+            let Some(user) = self.tracker.user(&user_id) else {
+                let _: Result<(), _> = con.hdel(VOLUMES_KEY, &user_id);
+                let _: Result<(), _> = con.hdel("fee_tiers", &user_id);
+                continue;
+            };
+            if let (Ok(user), Ok(status)) = (serde_json::to_string(user), serde_json::to_string(&self.tracker.status(&user_id))) {
+                let _: Result<(), _> = con.hset(VOLUMES_KEY, &user_id, user);
+                let _: Result<(), _> = con.hset("fee_tiers", &user_id, status);
+            }
+        }
+        if let Ok(json) = serde_json::to_string(self.tracker.reference_prices()) {
+            let _: Result<(), _> = con.set(PRICES_KEY, json);
+        }
+    }
+
+    // Carry on with the volumes saved before the restart, under the schedule configured now
+    fn load_state(&mut self){
+        let Some(mut con) = self.redis_client.as_ref().and_then(|client| client.get_connection().ok()) else {
+            return;
+        };
+        let saved: HashMap<String, String> = con.hgetall(VOLUMES_KEY).unwrap_or_default();
+        for (user_id, json) in saved {
+            if let Ok(user) = serde_json::from_str::<UserVolume>(&json) {
+                self.tracker.restore(user_id, user);
+            }
+        }
+        let prices: Option<String> = con.get(PRICES_KEY).ok().flatten();
+        if let Some(prices) = prices.and_then(|json| serde_json::from_str(&json).ok()) {
+            self.tracker.set_reference_prices(prices);
+        }
+    }
+
+    fn publish_schedule(&self){
+        let Some(mut con) = self.redis_client.as_ref().and_then(|client| client.get_connection().ok()) else {
+            return;
+        };
+        if let Ok(json) = serde_json::to_string(self.tracker.schedule()) {
+            let _: Result<(), _> = con.set("fee_tier_schedule", json);
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+    use crate::matching_engine::types::{BidOrAsk, Order, TradingPair};
+
+    fn trade(base: &str, quote: &str, buyer: &str, seller: &str, price: i64, quantity: i64, timestamp: DateTime<Utc>) -> Trade {
+        let mut buy = Order::new(BidOrAsk::Bid, Decimal::from(quantity));
+        buy.user_id = buyer.to_string();
+        let mut sell = Order::new(BidOrAsk::Ask, Decimal::from(quantity));
+        sell.user_id = seller.to_string();
+        let pair = TradingPair::new(base.to_string(), quote.to_string());
+        Trade::new(pair, &buy, &sell, Decimal::from(price), Decimal::from(quantity), Uuid::new_v4(), timestamp)
+    }
+
+    #[test]
+    fn test_tiers_follow_the_trailing_volume() {
+        let schedule = TierSchedule{
+            reference_asset: "USD".to_string(),
+            window_days: 30,
+            thresholds: BTreeMap::from([(1, Decimal::from(1_000)), (2, Decimal::from(10_000))]),
+        };
+        let mut tracker = VolumeTracker::new(schedule);
+        let start = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap();
+
+        // 1500 USD for both sides, then 5000 more for alice two weeks later
+        tracker.record_trade(&trade("BTC", "USD", "alice", "bob", 100, 15, start));
+        // Quoted in BTC: 2 * 25 BTC at 100 USD each
+        tracker.record_trade(&trade("ETH", "BTC", "alice", "carol", 25, 2, start + Duration::days(14)));
+        // Not convertible, no USD price for DOGE
+        tracker.record_trade(&trade("ETH", "DOGE", "alice", "dave", 1, 1_000_000, start));
+
+        let changes = tracker.recalculate(start + Duration::days(15));
+        assert_eq!(changes, [("alice".to_string(), 1), ("bob".to_string(), 1), ("carol".to_string(), 1)]);
+
+        let alice = tracker.status("alice");
+        assert_eq!((alice.tier, alice.volume), (1, Decimal::from(6_500)));
+        assert_eq!((alice.next_tier, alice.volume_to_next_tier), (Some(2), Some(Decimal::from(3_500))));
+
+        // The first trade leaves the window: bob drops back, alice keeps enough
+        let changes = tracker.recalculate(start + Duration::days(31));
+        assert_eq!(changes, [("bob".to_string(), 0)]);
+        assert_eq!(tracker.volume("alice"), Decimal::from(5_000));
+        assert!(tracker.recalculate(start + Duration::days(31)).is_empty());
+
+        assert_eq!(tracker.take_changed(), ["alice", "bob", "carol"]);
+        assert!(tracker.user("bob").is_none());
+
+        // Saved and loaded again, the window and the tiers given carry on
+        let mut restored = VolumeTracker::new(tracker.schedule().clone());
+        for user_id in ["alice", "carol"] {
+            let json = serde_json::to_string(tracker.user(user_id).unwrap()).unwrap();
+            restored.restore(user_id.to_string(), serde_json::from_str(&json).unwrap());
+        }
+        let mut tracker = restored;
+        assert_eq!(tracker.volume("alice"), Decimal::from(5_000));
+        assert!(tracker.recalculate(start + Duration::days(31)).is_empty());
+        assert_eq!(tracker.recalculate(start + Duration::days(45)), [("alice".to_string(), 0), ("carol".to_string(), 0)]);
+        assert!(tracker.user("alice").is_none() && tracker.user("carol").is_none());
+
+        let top = tracker.schedule().status("whale", Decimal::from(50_000));
+        assert_eq!((top.tier, top.next_tier, top.volume_to_next_tier), (2, None, None));
+    }
+}
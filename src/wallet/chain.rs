@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use rust_decimal::Decimal;
+use uuid::Uuid;
+use crate::wallet::Withdrawal;
+
+
+// A transfer into one of our deposit addresses
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainDeposit{
+    pub tx_hash: String,
+    pub address: String,
+    pub asset: String,
+    pub amount: Decimal,
+    pub confirmations: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxState{
+    Confirmations(u32),
+    // Never made it into the chain, or was reorganised out of it
+    Dropped(String),
+}
+
+// What the wallet service needs from a blockchain node or custody provider
+pub trait ChainAdapter: Send{
+    // A fresh address the user can deposit `asset` to
+    fn new_address(&mut self, user_id: &str, asset: &str) -> Result<String, String>;
+
+    // Every transfer into one of `addresses`, with its confirmations so far
+    fn deposits(&mut self, addresses: &[String]) -> Result<Vec<ChainDeposit>, String>;
+
+    // Send a withdrawal and return its transaction hash. Sending the same withdrawal id
+    // twice must not pay it out twice: a restart can retry a broadcast whose result was lost.
+    fn broadcast(&mut self, withdrawal: &Withdrawal) -> Result<String, String>;
+
+    fn transaction(&mut self, tx_hash: &str) -> Result<TxState, String>;
+}
+
+
+#[derive(Debug)]
+struct MockTx{
+    address: String,
+    asset: String,
+    amount: Decimal,
+    // Sent to us rather than by us
+    inbound: bool,
+    confirmations: u32,
+    dropped: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct MockState{
+    next_id: u64,
+    txs: HashMap<String, MockTx>,
+    // withdrawal id -> tx hash
+    broadcasts: HashMap<Uuid, String>,
+    fail_broadcasts: Option<String>,
+}
+
+// In-memory chain for running the whole deposit and withdrawal flow offline. Clones share
+// the chain, so a test can keep one to send deposits and mine blocks while the wallet
+// service holds another.
+#[derive(Debug, Clone, Default)]
+pub struct MockChain{
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockChain{
+    pub fn new() -> MockChain{
+        MockChain::default()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState>{
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn next_id(state: &mut MockState, prefix: &str) -> String{
+        state.next_id += 1;
+        format!("{}{}", prefix, state.next_id)
+    }
+
+    // Someone outside sends `amount` to `address`; returns the transaction hash
+    pub fn send_to(&self, address: &str, asset: &str, amount: Decimal) -> String{
+        let mut state = self.state();
+        let tx_hash = MockChain::next_id(&mut state, "mocktx");
+        state.txs.insert(tx_hash.clone(), MockTx{
+            address: address.to_string(),
+            asset: asset.to_string(),
+            amount,
+            inbound: true,
+            confirmations: 0,
+            dropped: None,
+        });
+        tx_hash
+    }
+
+    // Every transaction still on the chain gets `blocks` more confirmations
+    pub fn mine(&self, blocks: u32){
+        for tx in self.state().txs.values_mut().filter(|tx| tx.dropped.is_none()) {
+            tx.confirmations += blocks;
+        }
+    }
+
+    pub fn drop_transaction(&self, tx_hash: &str, reason: &str){
+        if let Some(tx) = self.state().txs.get_mut(tx_hash) {
+            tx.dropped = Some(reason.to_string());
+            tx.confirmations = 0;
+        }
+    }
+
+    // Make every broadcast fail with `reason` until cleared with None
+    pub fn fail_broadcasts(&self, reason: Option<&str>){
+        self.state().fail_broadcasts = reason.map(str::to_string);
+    }
+
+    // (address, asset, amount) of every withdrawal sent out
+    pub fn sent(&self) -> Vec<(String, String, Decimal)>{
+        let state = self.state();
+        let mut sent: Vec<(&String, &MockTx)> = state.txs.iter().filter(|(_, tx)| !tx.inbound).collect();
+        sent.sort_by_key(|(tx_hash, _)| tx_hash.trim_start_matches("mocktx").parse::<u64>().unwrap_or_default());
+        sent.into_iter().map(|(_, tx)| (tx.address.clone(), tx.asset.clone(), tx.amount)).collect()
+    }
+}
+
+impl ChainAdapter for MockChain{
+    fn new_address(&mut self, user_id: &str, asset: &str) -> Result<String, String>{
+        let mut state = self.state();
+        Ok(MockChain::next_id(&mut state, &format!("mock:{}:{}:", asset, user_id)))
+    }
+
+    fn deposits(&mut self, addresses: &[String]) -> Result<Vec<ChainDeposit>, String>{
+        let state = self.state();
+        Ok(state.txs.iter()
+            .filter(|(_, tx)| tx.inbound && tx.dropped.is_none() && addresses.contains(&tx.address))
+            .map(|(tx_hash, tx)| ChainDeposit{
+                tx_hash: tx_hash.clone(),
+                address: tx.address.clone(),
+                asset: tx.asset.clone(),
+                amount: tx.amount,
+                confirmations: tx.confirmations,
+            })
+            .collect())
+    }
+
+    fn broadcast(&mut self, withdrawal: &Withdrawal) -> Result<String, String>{
+        let mut state = self.state();
+        if let Some(tx_hash) = state.broadcasts.get(&withdrawal.id) {
+            return Ok(tx_hash.clone());
+        }
+        if let Some(reason) = &state.fail_broadcasts {
+            return Err(reason.clone());
+        }
+
+        let tx_hash = MockChain::next_id(&mut state, "mocktx");
+        state.txs.insert(tx_hash.clone(), MockTx{
+            address: withdrawal.address.clone(),
+            asset: withdrawal.asset.clone(),
+            amount: withdrawal.amount,
+            inbound: false,
+            confirmations: 0,
+            dropped: None,
+        });
+        state.broadcasts.insert(withdrawal.id, tx_hash.clone());
+        Ok(tx_hash)
+    }
+
+    fn transaction(&mut self, tx_hash: &str) -> Result<TxState, String>{
+        let state = self.state();
+        let tx = state.txs.get(tx_hash).ok_or(format!("Unknown transaction {}", tx_hash))?;
+        Ok(match &tx.dropped {
+            Some(reason) => TxState::Dropped(reason.clone()),
+            None => TxState::Confirmations(tx.confirmations),
+        })
+    }
+}
@@ -0,0 +1,480 @@
+pub mod chain;
+
+use std::collections::{BTreeMap, HashMap};
+use std::thread::{self, JoinHandle};
+use std::time::Duration as StdDuration;
+use chrono::{DateTime, Utc};
+use crossbeam::channel::{Sender, tick};
+use redis::{Client, Commands};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::balance::BalanceManager;
+use crate::matching_engine::messages::EngineMessage;
+use self::chain::{ChainAdapter, TxState};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DepositStatus{
+    // Seen on the chain, waiting for confirmations
+    Pending,
+    Credited,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Deposit{
+    pub id: Uuid,
+    pub user_id: String,
+    pub asset: String,
+    pub amount: Decimal,
+    pub address: String,
+    pub tx_hash: String,
+    pub confirmations: u32,
+    pub status: DepositStatus,
+    pub credited_at: Option<DateTime<Utc>>,
+}
+
+// pending -> approved -> broadcast -> confirmed, or failed from any step before confirmed.
+// Funds are held from the request until the withdrawal confirms or fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WithdrawalStatus{
+    Pending,
+    Approved,
+    Broadcast,
+    Confirmed,
+    Failed,
+}
+
+impl WithdrawalStatus{
+    pub fn as_str(&self) -> &'static str{
+        match self{
+            WithdrawalStatus::Pending => "pending",
+            WithdrawalStatus::Approved => "approved",
+            WithdrawalStatus::Broadcast => "broadcast",
+            WithdrawalStatus::Confirmed => "confirmed",
+            WithdrawalStatus::Failed => "failed",
+        }
+    }
+
+    pub fn is_final(&self) -> bool{
+        matches!(self, WithdrawalStatus::Confirmed | WithdrawalStatus::Failed)
+    }
+
+    pub fn can_become(&self, next: WithdrawalStatus) -> bool{
+        matches!(
+            (self, next),
+            (WithdrawalStatus::Pending, WithdrawalStatus::Approved)
+            | (WithdrawalStatus::Approved, WithdrawalStatus::Broadcast)
+            | (WithdrawalStatus::Broadcast, WithdrawalStatus::Confirmed)
+        ) || (!self.is_final() && next == WithdrawalStatus::Failed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Withdrawal{
+    pub id: Uuid,
+    pub user_id: String,
+    pub asset: String,
+    pub amount: Decimal,
+    pub address: String,
+    pub status: WithdrawalStatus,
+    pub tx_hash: Option<String>,
+    pub confirmations: u32,
+    // Why it failed
+    pub failure: Option<String>,
+    pub requested_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Withdrawal{
+    pub fn new(id: Uuid, user_id: String, asset: String, amount: Decimal, address: String, now: DateTime<Utc>) -> Withdrawal{
+        Withdrawal{
+            id,
+            user_id,
+            asset,
+            amount,
+            address,
+            status: WithdrawalStatus::Pending,
+            tx_hash: None,
+            confirmations: 0,
+            failure: None,
+            requested_at: now,
+            updated_at: now,
+        }
+    }
+
+    pub fn advance(&mut self, next: WithdrawalStatus, now: DateTime<Utc>) -> Result<(), String>{
+        if !self.status.can_become(next) {
+            return Err(format!("Withdrawal {} is {} and can't become {}", self.id, self.status.as_str(), next.as_str()));
+        }
+
+        self.status = next;
+        self.updated_at = now;
+        Ok(())
+    }
+}
+
+
+// How the wallet service handles one asset
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AssetConfig{
+    // Confirmations before a deposit is credited or a withdrawal counts as done
+    pub confirmations: u32,
+    // Withdrawals up to this amount are approved without an operator
+    pub auto_approve_limit: Option<Decimal>,
+}
+
+
+// Side service between the chain and the engine. It watches deposit addresses and the
+// withdrawals held in the balances, and moves both along by sending engine messages, so
+// every balance change is journaled. Withdrawals over the auto-approve limit wait for an
+// operator to approve them. What fails is kept in `errors` until it works again.
+pub struct WalletService{
+    chain: Box<dyn ChainAdapter>,
+    assets: HashMap<String, AssetConfig>,
+    balance_manager: BalanceManager,
+    engine_sender: Sender<EngineMessage>,
+    redis_client: Option<Client>,
+    interval: StdDuration,
+    // address -> (user_id, asset)
+    addresses: HashMap<String, (String, String)>,
+    // Deposits waiting for confirmations, by tx hash
+    deposits: HashMap<String, Deposit>,
+    // Status each withdrawal had when it was last acted on, so nothing is sent twice
+    handled: HashMap<Uuid, WithdrawalStatus>,
+    // What is failing right now: "address:{user_id}:{asset}", "deposits" or "withdrawal:{id}"
+    errors: BTreeMap<String, String>,
+}
+
+impl WalletService{
+    pub fn new(
+        chain: Box<dyn ChainAdapter>,
+        assets: HashMap<String, AssetConfig>,
+        balance_manager: BalanceManager,
+        engine_sender: Sender<EngineMessage>,
+        interval: StdDuration,
+    ) -> WalletService{
+        WalletService{
+            chain,
+            assets,
+            balance_manager,
+            engine_sender,
+            redis_client: None,
+            interval,
+            addresses: HashMap::new(),
+            deposits: HashMap::new(),
+            handled: HashMap::new(),
+            errors: BTreeMap::new(),
+        }
+    }
+
+    // Serve deposit address requests from the "deposit_address_requests" list, keep addresses
+    // in the "deposit_addresses" hash, show pending deposits under "deposits:{user_id}" and
+    // failures in the "wallet_errors" hash
+    pub fn with_redis(mut self, redis_url: &str) -> Result<WalletService, redis::RedisError>{
+        let client = Client::open(redis_url)?;
+
+        // Keep watching the addresses handed out before a restart
+        let mut con = client.get_connection()?;
+        let known: HashMap<String, String> = con.hgetall("deposit_addresses")?;
+        for (key, address) in known {
+            if let Some((user_id, asset)) = key.rsplit_once(':') {
+                self.addresses.insert(address, (user_id.to_string(), asset.to_string()));
+            }
+        }
+
+        self.redis_client = Some(client);
+        Ok(self)
+    }
+
+    pub fn start(mut self) -> JoinHandle<()>{
+        thread::Builder::new()
+            .name("wallet".to_string())
+            .spawn(move || {
+                let ticker = tick(self.interval);
+                while ticker.recv().is_ok() {
+                    self.poll();
+                }
+            })
+            .expect("Failed to spawn wallet service")
+    }
+
+    pub fn errors(&self) -> &BTreeMap<String, String>{
+        &self.errors
+    }
+
+    // The user's address for `asset`, created on first use
+    pub fn deposit_address(&mut self, user_id: &str, asset: &str) -> Result<String, String>{
+        let address = self.find_or_create_address(user_id, asset);
+        self.record(format!("address:{}:{}", user_id, asset), address.as_ref().err().cloned());
+        address
+    }
+
+    fn find_or_create_address(&mut self, user_id: &str, asset: &str) -> Result<String, String>{
+        let existing = self.addresses.iter()
+            .find(|(_, (owner, owned_asset))| owner == user_id && owned_asset == asset)
+            .map(|(address, _)| address.clone());
+        if let Some(address) = existing {
+            return Ok(address);
+        }
+        if !self.assets.contains_key(asset) {
+            return Err(format!("Deposits of {} are not supported", asset));
+        }
+
+        let address = self.chain.new_address(user_id, asset)?;
+        self.addresses.insert(address.clone(), (user_id.to_string(), asset.to_string()));
+
+        if let Some(mut con) = self.redis_client.as_ref().and_then(|client| client.get_connection().ok()) {
+            let _: Result<(), _> = con.hset("deposit_addresses", format!("{}:{}", user_id, asset), &address);
+        }
+        Ok(address)
+    }
+
+    pub fn poll(&mut self){
+        self.serve_address_requests();
+        self.poll_deposits();
+        self.poll_withdrawals();
+    }
+
+    fn serve_address_requests(&mut self){
+        let Some(mut con) = self.redis_client.as_ref().and_then(|client| client.get_connection().ok()) else {
+            return;
+        };
+
+        while let Ok(Some(request)) = con.lpop::<_, Option<String>>("deposit_address_requests", None) {
+            let Some((user_id, asset)) = request.rsplit_once(':') else {
+                continue;
+            };
+            let _ = self.deposit_address(user_id, asset);
+        }
+    }
+
+    fn poll_deposits(&mut self){
+        let addresses: Vec<String> = self.addresses.keys().cloned().collect();
+        let seen = self.chain.deposits(&addresses).map_err(|e| format!("Failed to fetch deposits: {}", e));
+        self.record("deposits".to_string(), seen.as_ref().err().cloned());
+        let Ok(seen) = seen else {
+            return;
+        };
+
+        for chain_deposit in seen {
+            if self.balance_manager.deposit_credited(&chain_deposit.tx_hash) {
+                continue;
+            }
+            let Some((user_id, _)) = self.addresses.get(&chain_deposit.address) else {
+                continue;
+            };
+            let Some(config) = self.assets.get(&chain_deposit.asset) else {
+                continue;
+            };
+
+            let deposit = self.deposits.entry(chain_deposit.tx_hash.clone()).or_insert_with(|| Deposit{
+                id: Uuid::new_v4(),
+                user_id: user_id.clone(),
+                asset: chain_deposit.asset.clone(),
+                amount: chain_deposit.amount,
+                address: chain_deposit.address.clone(),
+                tx_hash: chain_deposit.tx_hash.clone(),
+                confirmations: 0,
+                status: DepositStatus::Pending,
+                credited_at: None,
+            });
+            deposit.confirmations = chain_deposit.confirmations;
+            let deposit = deposit.clone();
+
+            if deposit.confirmations >= config.confirmations {
+                self.deposits.remove(&deposit.tx_hash);
+                let _ = self.engine_sender.send(EngineMessage::CreditDeposit{ deposit });
+            } else {
+                self.show_pending(&deposit);
+            }
+        }
+    }
+
+    // The engine's answer replaces this entry once the deposit is credited
+    fn show_pending(&self, deposit: &Deposit){
+        let Some(mut con) = self.redis_client.as_ref().and_then(|client| client.get_connection().ok()) else {
+            return;
+        };
+        if let Ok(json) = serde_json::to_string(deposit) {
+            let _: Result<(), _> = con.hset(format!("deposits:{}", deposit.user_id), &deposit.tx_hash, json);
+        }
+    }
+
+    fn poll_withdrawals(&mut self){
+        let open = self.balance_manager.open_withdrawals();
+        self.handled.retain(|id, _| open.iter().any(|withdrawal| withdrawal.id == *id));
+        let gone: Vec<String> = self.errors.keys()
+            .filter(|key| key.strip_prefix("withdrawal:").is_some_and(|id| !open.iter().any(|withdrawal| withdrawal.id.to_string() == id)))
+            .cloned()
+            .collect();
+        for key in gone {
+            self.record(key, None);
+        }
+
+        for withdrawal in open {
+            // Waiting for the engine to apply what was sent last time
+            if self.handled.get(&withdrawal.id) == Some(&withdrawal.status) {
+                continue;
+            }
+
+            let Some(config) = self.assets.get(&withdrawal.asset).cloned() else {
+                self.fail(&withdrawal, format!("Withdrawals of {} are not supported", withdrawal.asset));
+                continue;
+            };
+
+            match withdrawal.status {
+                WithdrawalStatus::Pending => {
+                    if config.auto_approve_limit.is_some_and(|limit| withdrawal.amount <= limit) {
+                        self.send(&withdrawal, EngineMessage::ApproveWithdrawal{ withdrawal_id: withdrawal.id });
+                    }
+                }
+                WithdrawalStatus::Approved => match self.chain.broadcast(&withdrawal) {
+                    Ok(tx_hash) => self.send(&withdrawal, EngineMessage::WithdrawalBroadcast{ withdrawal_id: withdrawal.id, tx_hash }),
+                    Err(e) => self.fail(&withdrawal, format!("Broadcast failed: {}", e)),
+                },
+                WithdrawalStatus::Broadcast => {
+                    let Some(tx_hash) = withdrawal.tx_hash.as_deref() else {
+                        continue;
+                    };
+                    // The transaction may still confirm, so a failed check only gets reported
+                    let state = self.chain.transaction(tx_hash).map_err(|e| format!("Failed to check transaction {}: {}", tx_hash, e));
+                    self.record(format!("withdrawal:{}", withdrawal.id), state.as_ref().err().cloned());
+                    match state {
+                        Ok(TxState::Confirmations(confirmations)) if confirmations >= config.confirmations => {
+                            self.send(&withdrawal, EngineMessage::ConfirmWithdrawal{ withdrawal_id: withdrawal.id, confirmations });
+                        }
+                        Ok(TxState::Confirmations(_)) | Err(_) => {}
+                        Ok(TxState::Dropped(reason)) => self.fail(&withdrawal, format!("Transaction {} dropped: {}", tx_hash, reason)),
+                    }
+                }
+                WithdrawalStatus::Confirmed | WithdrawalStatus::Failed => {}
+            }
+        }
+    }
+
+    fn send(&mut self, withdrawal: &Withdrawal, message: EngineMessage){
+        self.handled.insert(withdrawal.id, withdrawal.status);
+        let _ = self.engine_sender.send(message);
+    }
+
+    fn fail(&mut self, withdrawal: &Withdrawal, reason: String){
+        self.send(withdrawal, EngineMessage::FailWithdrawal{ withdrawal_id: withdrawal.id, reason });
+    }
+
+    // Keep what failed under `key` until it works again, and show it to operators and the API
+    fn record(&mut self, key: String, error: Option<String>){
+        let changed = match &error {
+            Some(error) => self.errors.insert(key.clone(), error.clone()).as_ref() != Some(error),
+            None => self.errors.remove(&key).is_some(),
+        };
+        if !changed {
+            return;
+        }
+
+        let Some(mut con) = self.redis_client.as_ref().and_then(|client| client.get_connection().ok()) else {
+            return;
+        };
+        let _: Result<(), _> = match error {
+            Some(error) => con.hset("wallet_errors", &key, error),
+            None => con.hdel("wallet_errors", &key),
+        };
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crossbeam::channel::Receiver;
+    use crate::matching_engine::engine::MatchingEngine;
+    use crate::matching_engine::messages::EngineResponse;
+    use self::chain::MockChain;
+
+    fn next_withdrawal(resp_rx: &Receiver<EngineResponse>) -> Withdrawal {
+        loop {
+            match resp_rx.recv_timeout(Duration::from_secs(5)).expect("engine did not answer") {
+                EngineResponse::WithdrawalUpdated{ withdrawal } => return withdrawal,
+                EngineResponse::Error{ message } => panic!("{}", message),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_deposits_and_withdrawals_run_through_the_mock_chain() {
+        let (mut engine, msg_tx, resp_rx, _db_rx, _ws_rx) = MatchingEngine::new();
+        engine.add_user("alice".to_string(), HashMap::from([("BTC".to_string(), Decimal::from(10))])).unwrap();
+        let balances = engine.balance_manager.clone();
+        let _engine = thread::spawn(move || engine.run());
+
+        let chain = MockChain::new();
+        let assets = HashMap::from([("BTC".to_string(), AssetConfig{ confirmations: 3, auto_approve_limit: Some(Decimal::from(5)) })]);
+        let mut wallet = WalletService::new(Box::new(chain.clone()), assets, balances.clone(), msg_tx.clone(), Duration::from_secs(1));
+
+        // Credited once it has enough confirmations, and only once
+        let address = wallet.deposit_address("alice", "BTC").unwrap();
+        assert_eq!(wallet.deposit_address("alice", "BTC").unwrap(), address);
+        assert!(wallet.deposit_address("alice", "DOGE").is_err());
+        assert!(wallet.errors()["address:alice:DOGE"].contains("not supported"));
+        let tx_hash = chain.send_to(&address, "BTC", Decimal::from(2));
+        chain.mine(2);
+        wallet.poll();
+        assert_eq!(wallet.deposits[&tx_hash].confirmations, 2);
+        chain.mine(1);
+        wallet.poll();
+        match resp_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            EngineResponse::DepositCredited{ deposit } => assert_eq!((deposit.status, deposit.amount), (DepositStatus::Credited, Decimal::from(2))),
+            other => panic!("unexpected {:?}", other),
+        }
+        wallet.poll();
+        assert!(balances.deposit_credited(&tx_hash));
+        assert_eq!(balances.get_balance("alice", "BTC").unwrap().available, Decimal::from(12));
+
+        // A small withdrawal goes all the way on its own
+        let small = Uuid::new_v4();
+        msg_tx.send(EngineMessage::RequestWithdrawal{ withdrawal_id: small, user_id: "alice".to_string(), asset: "BTC".to_string(), amount: Decimal::from(4), address: "out".to_string() }).unwrap();
+        assert_eq!(next_withdrawal(&resp_rx).status, WithdrawalStatus::Pending);
+        assert_eq!(balances.get_balance("alice", "BTC").unwrap().locked, Decimal::from(4));
+        wallet.poll();
+        assert_eq!(next_withdrawal(&resp_rx).status, WithdrawalStatus::Approved);
+        wallet.poll();
+        assert_eq!(next_withdrawal(&resp_rx).status, WithdrawalStatus::Broadcast);
+        chain.mine(3);
+        wallet.poll();
+        let confirmed = next_withdrawal(&resp_rx);
+        assert_eq!((confirmed.status, confirmed.confirmations), (WithdrawalStatus::Confirmed, 3));
+        assert_eq!(chain.sent(), [("out".to_string(), "BTC".to_string(), Decimal::from(4))]);
+
+        let btc = balances.get_balance("alice", "BTC").unwrap();
+        assert_eq!((btc.available, btc.locked), (Decimal::from(8), Decimal::ZERO));
+
+        // A large one waits for an operator, then the chain drops it and the funds come back
+        let large = Uuid::new_v4();
+        msg_tx.send(EngineMessage::RequestWithdrawal{ withdrawal_id: large, user_id: "alice".to_string(), asset: "BTC".to_string(), amount: Decimal::from(7), address: "out".to_string() }).unwrap();
+        assert_eq!(next_withdrawal(&resp_rx).status, WithdrawalStatus::Pending);
+        wallet.poll();
+        assert!(resp_rx.recv_timeout(Duration::from_millis(100)).is_err());
+        msg_tx.send(EngineMessage::ApproveWithdrawal{ withdrawal_id: large }).unwrap();
+        assert_eq!(next_withdrawal(&resp_rx).status, WithdrawalStatus::Approved);
+        wallet.poll();
+        let broadcast = next_withdrawal(&resp_rx);
+        chain.drop_transaction(broadcast.tx_hash.as_deref().unwrap(), "reorg");
+        wallet.poll();
+        let failed = next_withdrawal(&resp_rx);
+        assert_eq!(failed.status, WithdrawalStatus::Failed);
+        assert!(failed.failure.unwrap().contains("reorg"));
+
+        let btc = balances.get_balance("alice", "BTC").unwrap();
+        assert_eq!((btc.available, btc.locked), (Decimal::from(8), Decimal::ZERO));
+
+        // More than is available never gets a hold
+        msg_tx.send(EngineMessage::RequestWithdrawal{ withdrawal_id: Uuid::new_v4(), user_id: "alice".to_string(), asset: "BTC".to_string(), amount: Decimal::from(9), address: "out".to_string() }).unwrap();
+        assert_eq!(next_withdrawal(&resp_rx).status, WithdrawalStatus::Failed);
+        assert!(balances.open_withdrawals().is_empty());
+    }
+}
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use rust_decimal::Decimal;
 use chrono::{DateTime, Utc};
-use crate::matching_engine::types::{Trade, TradingPair};
+use crate::matching_engine::types::{Order, Trade};
 
 
 #[derive(Debug,Clone, Serialize, Deserialize)]
@@ -16,6 +16,15 @@ pub enum MarketDataEvent{
         quantity: Decimal,
         timestamp: DateTime<Utc>,
         trade_id: String,
+        side: String,
+        buyer_user_id: String,
+        seller_user_id: String,
+        maker_order_id: String,
+        taker_order_id: String,
+        // In base, taken from what the buyer received
+        buyer_fee: Decimal,
+        // In quote, taken from what the seller received
+        seller_fee: Decimal,
     },
 
 
@@ -47,6 +56,30 @@ pub enum MarketDataEvent{
         filled_quantity: Decimal,
         remaining_quantity: Decimal,
         timestamp: DateTime<Utc>,
+        // Why the engine changed the order on its own, e.g. "self_trade_prevention"
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+
+    #[serde(rename = "market_status")]
+    MarketStatus{
+        pair: String,
+        status: String,
+        // Set when the engine changed the status itself, e.g. "circuit_breaker"
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+        timestamp: DateTime<Utc>,
+    },
+
+    // Indicative uncross of a call auction; `price` is missing while nothing crosses
+    #[serde(rename = "auction")]
+    Auction{
+        pair: String,
+        price: Option<Decimal>,
+        volume: Decimal,
+        surplus: Decimal,
+        ends_at: Option<DateTime<Utc>>,
+        timestamp: DateTime<Utc>,
     },
 }
 
@@ -58,7 +91,7 @@ pub struct PriceLevel{
     pub quantity: Decimal,
 }
 
-#[derive(Debug,Clone, Serialize, Deserialize)]
+#[derive(Debug,Clone, PartialEq, Serialize, Deserialize)]
 pub enum OrderStatus{
     #[serde(rename = "placed")]
     Placed,
@@ -68,18 +101,39 @@ pub enum OrderStatus{
     PartialFilled,
     #[serde(rename = "cancelled")]
     Cancelled,
+    #[serde(rename = "triggered")]
+    Triggered,
 }
 
 
 
 impl MarketDataEvent {
-    pub fn from_trade(trade: &Trade, pair: &TradingPair) -> Self{
+    pub fn from_trade(trade: &Trade) -> Self{
         MarketDataEvent::Trade{
-            pair: format!("{}/{}", pair.base, pair.quote),
+            pair: format!("{}/{}", trade.pair.base, trade.pair.quote),
             price: trade.price,
             quantity: trade.quantity,
             timestamp: trade.timestamp,
             trade_id: trade.id.to_string(),
+            side: trade.taker_side.as_str().to_string(),
+            buyer_user_id: trade.buyer_user_id.clone(),
+            seller_user_id: trade.seller_user_id.clone(),
+            maker_order_id: trade.maker_order_id.to_string(),
+            taker_order_id: trade.taker_order_id.to_string(),
+            buyer_fee: trade.buyer_fee,
+            seller_fee: trade.seller_fee,
+        }
+    }
+
+    pub fn order_update(order: &Order, status: OrderStatus, reason: Option<String>, timestamp: DateTime<Utc>) -> Self{
+        MarketDataEvent::OrderUpdate{
+            order_id: order.id.to_string(),
+            user_id: order.user_id.clone(),
+            status,
+            filled_quantity: order.filled_size,
+            remaining_quantity: order.size,
+            timestamp,
+            reason,
         }
     }
 